@@ -0,0 +1,534 @@
+//! Derive macros of destore. Use them through the reexports of the `destore` crate.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::ext::IdentExt;
+use syn::{
+    parse_macro_input, Attribute, Data, DeriveInput, Error, Expr, ExprLit, Field, Fields, Lit,
+    LitStr, Meta, MetaNameValue, Type,
+};
+
+/// Implements `postcard_schema::Schema` for a record type and exports its schema to the ELF,
+/// replacing `#[derive(Schema)]` and `destore::export_schema!`.
+///
+/// The type must also derive `serde::Serialize`, this derive does not implement it: a derive can
+/// not expand other derives, and generating the impl here would duplicate `serde_derive`. The
+/// schema describes the encoding of the derived `Serialize`, so `#[serde(...)]` attributes, which
+/// change it, are rejected. The exception is `#[serde(with = "serde_bytes")]` on fields, which
+/// are described as byte arrays.
+///
+/// The schema is exported under a symbol derived from the module path of the type, so every type
+/// of the firmware can derive it. Generic types are not supported, as the exported schema must be
+/// concrete.
+///
+/// Like with `export_schema!`, the firmware must be linked with `-Tdestore.x`, e.g. with
+/// `println!("cargo:rustc-link-arg=-Tdestore.x")` in its `build.rs`. The script keeps the sections
+/// of the exported schemas, which nothing references. It can not be added by the derive, and the
+/// linker arguments of the build scripts of dependencies do not apply to the firmware.
+///
+/// Fields can be annotated with `#[destore(unit = "...", scale = ..., format = "...")]`, where
+/// `scale` converts the stored value into `unit` and `format` (`"hex"` or `"bin"`) sets how
+/// integers are displayed. Together with the doc comments of fields and variants these are
+/// exported as `destore::TypeMetadata`, which the host tools apply to their output.
+///
+/// ```ignore
+/// #[derive(serde::Serialize, destore::DestoreRecord)]
+/// enum Record {
+///     /// Written once after reset
+///     Boot,
+///     Temperature {
+///         sensor: u8,
+///         #[destore(unit = "°C", scale = 0.01)]
+///         value: i16,
+///         #[destore(format = "hex")]
+///         status: u8,
+///     },
+/// }
+///
+/// let mut storer: Storer<_, Record> = Storer::new(flash, 0x620000..0x800000).await?;
+/// ```
+#[proc_macro_derive(DestoreRecord, attributes(destore))]
+pub fn derive_destore_record(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    record(&input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn record(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    if !input.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &input.generics,
+            "DestoreRecord can not be derived for generic types, the exported schema must be concrete",
+        ));
+    }
+
+    let ident = &input.ident;
+    let name = ident.unraw().to_string();
+    reject_serde_attributes(&input.attrs)?;
+    let mut metadata = Vec::new();
+    let schema = match &input.data {
+        Data::Struct(data) => {
+            fields_metadata(None, &data.fields, &mut metadata)?;
+            schema_struct(&name, &data.fields)?
+        }
+        Data::Enum(data) => {
+            for variant in &data.variants {
+                reject_serde_attributes(&variant.attrs)?;
+                let name = variant.ident.unraw().to_string();
+                let annotation = annotation(&variant.attrs)?;
+                if annotation.unit.is_some()
+                    || annotation.scale.is_some()
+                    || annotation.format.is_some()
+                {
+                    return Err(Error::new_spanned(
+                        variant,
+                        "`unit`, `scale` and `format` can only be given for fields",
+                    ));
+                }
+                if let Some(tokens) = annotation.to_tokens(Some(&name), None) {
+                    metadata.push(tokens);
+                }
+                fields_metadata(Some(&name), &variant.fields, &mut metadata)?;
+            }
+            schema_enum(&name, data.variants.iter().map(|v| (&v.ident, &v.fields)))?
+        }
+        Data::Union(_) => {
+            return Err(Error::new_spanned(
+                input,
+                "DestoreRecord can not be derived for unions",
+            ))
+        }
+    };
+
+    let metadata = (!metadata.is_empty()).then(|| {
+        quote! {
+            const _: () = {
+                const METADATA: ::destore::TypeMetadata = ::destore::TypeMetadata {
+                    name: #name,
+                    schema_hash: ::destore::__private::hash_ty_path::<#ident>(""),
+                    fields: &[#(#metadata),*],
+                };
+
+                #[link_section = ".destore.meta"]
+                #[used]
+                #[export_name = concat!("_DESTORE_META_", module_path!(), "::", #name)]
+                static EXPORTED: ::destore::ExportedMetadata<
+                    { ::destore::serialized_metadata_len(&METADATA) },
+                > = ::destore::ExportedMetadata::new(&METADATA);
+            };
+        }
+    });
+
+    Ok(quote! {
+        impl ::destore::Schema for #ident {
+            const SCHEMA: &'static ::destore::DataModelType = #schema;
+        }
+
+        // Points at the missing `#[derive(serde::Serialize)]` instead of the use of the type
+        const _: () = {
+            fn assert_serialize<T: ::destore::__private::SerializeRecord>() {}
+            let _ = assert_serialize::<#ident>;
+        };
+
+        ::destore::export_schema!(
+            @export_name concat!("_DESTORE_SCHEMA_", module_path!(), "::", #name),
+            #ident
+        );
+
+        #metadata
+    })
+}
+
+/// `#[destore(...)]` attributes and doc comments of a field or variant
+#[derive(Default)]
+struct Annotation {
+    unit: Option<String>,
+    scale: Option<f64>,
+    format: Option<String>,
+    description: Option<String>,
+}
+
+impl Annotation {
+    /// The `destore::FieldMetadata`, `None` if there is nothing to export
+    fn to_tokens(&self, variant: Option<&str>, field: Option<&str>) -> Option<TokenStream2> {
+        if self.unit.is_none()
+            && self.scale.is_none()
+            && self.format.is_none()
+            && self.description.is_none()
+        {
+            return None;
+        }
+        let [variant, field, unit, format, description] = [
+            variant,
+            field,
+            self.unit.as_deref(),
+            self.format.as_deref(),
+            self.description.as_deref(),
+        ]
+        .map(option);
+        let scale = option(self.scale);
+        Some(quote! {
+            ::destore::FieldMetadata {
+                variant: #variant,
+                field: #field,
+                unit: #unit,
+                scale: #scale,
+                format: #format,
+                description: #description,
+            }
+        })
+    }
+}
+
+fn option<T: quote::ToTokens>(value: Option<T>) -> TokenStream2 {
+    match value {
+        Some(value) => quote!(::core::option::Option::Some(#value)),
+        None => quote!(::core::option::Option::None),
+    }
+}
+
+const UNSUPPORTED_SERDE_ATTRIBUTE: &str =
+    "serde attributes other than `with = \"serde_bytes\"` on fields are not supported by \
+     DestoreRecord, the schema would not match the encoding";
+
+/// Whether a field is serialized with `#[serde(with = "serde_bytes")]`, the only serde attribute
+/// whose encoding the derived schema can describe
+fn serde_bytes(attrs: &[Attribute]) -> syn::Result<bool> {
+    let mut bytes = false;
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("serde")) {
+        attr.parse_nested_meta(|meta| {
+            bytes = meta.path.is_ident("with")
+                && meta.value()?.parse::<LitStr>()?.value() == "serde_bytes";
+            if bytes {
+                Ok(())
+            } else {
+                Err(Error::new_spanned(attr, UNSUPPORTED_SERDE_ATTRIBUTE))
+            }
+        })?;
+    }
+    Ok(bytes)
+}
+
+/// `#[serde(...)]` attributes change the encoding, which the derived schema would not describe
+fn reject_serde_attributes(attrs: &[Attribute]) -> syn::Result<()> {
+    match attrs.iter().find(|attr| attr.path().is_ident("serde")) {
+        Some(attr) => Err(Error::new_spanned(attr, UNSUPPORTED_SERDE_ATTRIBUTE)),
+        None => Ok(()),
+    }
+}
+
+/// Display formats understood by the host tools
+const FORMATS: [&str; 2] = ["hex", "bin"];
+
+fn annotation(attrs: &[Attribute]) -> syn::Result<Annotation> {
+    let mut annotation = Annotation::default();
+    let mut doc = Vec::new();
+    for attr in attrs {
+        if attr.path().is_ident("doc") {
+            if let Meta::NameValue(MetaNameValue {
+                value:
+                    Expr::Lit(ExprLit {
+                        lit: Lit::Str(line),
+                        ..
+                    }),
+                ..
+            }) = &attr.meta
+            {
+                let line = line.value();
+                if !line.trim().is_empty() {
+                    doc.push(line.trim().to_string());
+                }
+            }
+        } else if attr.path().is_ident("destore") {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("unit") {
+                    annotation.unit = Some(meta.value()?.parse::<LitStr>()?.value());
+                } else if meta.path.is_ident("scale") {
+                    annotation.scale = Some(match meta.value()?.parse::<Lit>()? {
+                        Lit::Float(scale) => scale.base10_parse()?,
+                        Lit::Int(scale) => scale.base10_parse()?,
+                        lit => return Err(Error::new_spanned(lit, "expected a number")),
+                    });
+                } else if meta.path.is_ident("format") {
+                    let format = meta.value()?.parse::<LitStr>()?;
+                    if !FORMATS.contains(&format.value().as_str()) {
+                        return Err(Error::new_spanned(
+                            format,
+                            "expected `\"hex\"` or `\"bin\"`",
+                        ));
+                    }
+                    annotation.format = Some(format.value());
+                } else {
+                    return Err(meta
+                        .error("unknown destore attribute, expected `unit`, `scale` or `format`"));
+                }
+                Ok(())
+            })?;
+        }
+    }
+    if !doc.is_empty() {
+        annotation.description = Some(doc.join(" "));
+    }
+    Ok(annotation)
+}
+
+/// Collects the annotations of the fields of a struct or variant
+fn fields_metadata(
+    variant: Option<&str>,
+    fields: &Fields,
+    metadata: &mut Vec<TokenStream2>,
+) -> syn::Result<()> {
+    for (index, field) in fields.iter().enumerate() {
+        let name = match &field.ident {
+            Some(ident) => ident.unraw().to_string(),
+            None => index.to_string(),
+        };
+        if let Some(tokens) = annotation(&field.attrs)?.to_tokens(variant, Some(&name)) {
+            metadata.push(tokens);
+        }
+    }
+    Ok(())
+}
+
+fn schema_struct(name: &str, fields: &Fields) -> syn::Result<TokenStream2> {
+    let data = schema_data(fields)?;
+    Ok(quote! {
+        &::destore::DataModelType::Struct { name: #name, data: #data }
+    })
+}
+
+fn schema_enum<'a>(
+    name: &str,
+    variants: impl Iterator<Item = (&'a syn::Ident, &'a Fields)>,
+) -> syn::Result<TokenStream2> {
+    let variants = variants
+        .map(|(ident, fields)| {
+            let name = ident.unraw().to_string();
+            let data = schema_data(fields)?;
+            Ok(quote! {
+                &::destore::__private::postcard_schema::schema::Variant { name: #name, data: #data }
+            })
+        })
+        .collect::<syn::Result<Vec<_>>>()?;
+    Ok(quote! {
+        &::destore::DataModelType::Enum { name: #name, variants: &[#(#variants),*] }
+    })
+}
+
+fn schema_data(fields: &Fields) -> syn::Result<TokenStream2> {
+    let data = quote!(::destore::__private::postcard_schema::schema::Data);
+    Ok(match fields {
+        Fields::Unit => quote!(#data::Unit),
+        Fields::Unnamed(fields) if fields.unnamed.len() == 1 => {
+            let ty = field_schema(&fields.unnamed[0])?;
+            quote!(#data::Newtype(#ty))
+        }
+        Fields::Unnamed(fields) => {
+            let types = fields
+                .unnamed
+                .iter()
+                .map(field_schema)
+                .collect::<syn::Result<Vec<_>>>()?;
+            quote!(#data::Tuple(&[#(#types),*]))
+        }
+        Fields::Named(fields) => {
+            let fields = fields
+                .named
+                .iter()
+                .map(|f| {
+                    let name = f.ident.as_ref().unwrap().unraw().to_string();
+                    let ty = field_schema(f)?;
+                    Ok(quote! {
+                        &::destore::__private::postcard_schema::schema::NamedField {
+                            name: #name,
+                            ty: #ty,
+                        }
+                    })
+                })
+                .collect::<syn::Result<Vec<_>>>()?;
+            quote!(#data::Struct(&[#(#fields),*]))
+        }
+    })
+}
+
+/// The schema of the type of a field. serde_bytes serializes bytes (also in an `Option`) with
+/// `serialize_bytes` instead of as a sequence.
+fn field_schema(field: &Field) -> syn::Result<TokenStream2> {
+    let ty = &field.ty;
+    if !serde_bytes(&field.attrs)? {
+        return Ok(quote!(<#ty as ::destore::Schema>::SCHEMA));
+    }
+    let bytes = quote!(&::destore::DataModelType::ByteArray);
+    Ok(if is_option(ty) {
+        quote!(&::destore::DataModelType::Option(#bytes))
+    } else {
+        bytes
+    })
+}
+
+fn is_option(ty: &Type) -> bool {
+    match ty {
+        Type::Path(path) => path
+            .path
+            .segments
+            .last()
+            .is_some_and(|segment| segment.ident == "Option"),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syn::parse_quote;
+
+    fn expand(input: DeriveInput) -> String {
+        record(&input).unwrap().to_string()
+    }
+
+    fn error(input: DeriveInput) -> String {
+        record(&input).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_expand_struct() {
+        let expanded = expand(parse_quote! {
+            struct r#Sub {
+                r#type: u8,
+                /// Age in years
+                #[destore(unit = "a")]
+                age: u16,
+            }
+        });
+        assert!(expanded.contains("impl :: destore :: Schema for r#Sub"));
+        assert!(expanded.contains("name : \"Sub\""));
+        assert!(expanded.contains("name : \"type\""));
+        assert!(expanded.contains("\"_DESTORE_SCHEMA_\""));
+        assert!(expanded.contains("SerializeRecord"));
+        assert!(expanded.contains("\"_DESTORE_META_\""));
+        assert!(
+            expanded.contains("schema_hash : :: destore :: __private :: hash_ty_path :: < r#Sub >")
+        );
+        assert!(expanded.contains("Some (\"Age in years\")"));
+        assert!(expanded.contains("Some (\"a\")"));
+    }
+
+    #[test]
+    fn test_expand_enum() {
+        let expanded = expand(parse_quote! {
+            enum Record {
+                Boot,
+                Pair(u8, u16),
+                Temperature {
+                    #[destore(scale = 0.01)]
+                    value: i16,
+                    #[destore(format = "hex")]
+                    status: u8,
+                },
+            }
+        });
+        assert!(expanded.contains("DataModelType :: Enum { name : \"Record\""));
+        assert!(expanded.contains("Data :: Unit"));
+        assert!(expanded.contains("Data :: Tuple"));
+        assert!(
+            expanded.contains("variant : :: core :: option :: Option :: Some (\"Temperature\")")
+        );
+        assert!(expanded.contains("Some (0.01f64)"));
+        assert!(expanded.contains("format : :: core :: option :: Option :: Some (\"hex\")"));
+    }
+
+    #[test]
+    fn test_expand_serde_bytes() {
+        let expanded = expand(parse_quote! {
+            struct Frame {
+                #[serde(with = "serde_bytes")]
+                payload: Vec<u8>,
+                #[serde(with = "serde_bytes")]
+                trailer: Option<Vec<u8>>,
+                checksum: u16,
+            }
+        });
+        assert!(expanded.contains("ty : & :: destore :: DataModelType :: ByteArray"));
+        assert!(expanded.contains(
+            "ty : & :: destore :: DataModelType :: Option (& :: destore :: DataModelType :: ByteArray)"
+        ));
+        assert!(expanded.contains("ty : < u16 as :: destore :: Schema > :: SCHEMA"));
+    }
+
+    #[test]
+    fn test_expand_without_metadata() {
+        let expanded = expand(parse_quote! {
+            struct Plain(u32);
+        });
+        assert!(expanded.contains("Data :: Newtype"));
+        assert!(!expanded.contains("_DESTORE_META_"));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(error(parse_quote! {
+            struct Wrapper<T>(T);
+        })
+        .contains("generic types"));
+        assert!(error(parse_quote! {
+            union Bits { a: u32, b: f32 }
+        })
+        .contains("unions"));
+        assert!(error(parse_quote! {
+            #[serde(rename = "Other")]
+            struct Renamed(u32);
+        })
+        .contains("serde attributes"));
+        assert!(error(parse_quote! {
+            struct Skipped {
+                #[serde(skip)]
+                value: u32,
+            }
+        })
+        .contains("serde attributes"));
+        assert!(error(parse_quote! {
+            struct Encoded {
+                #[serde(with = "hex")]
+                value: Vec<u8>,
+            }
+        })
+        .contains("serde attributes"));
+        assert!(error(parse_quote! {
+            enum Record {
+                #[serde(rename = "Start")]
+                Boot,
+            }
+        })
+        .contains("serde attributes"));
+        assert!(error(parse_quote! {
+            enum Record {
+                #[destore(unit = "s")]
+                Uptime(u32),
+            }
+        })
+        .contains("only be given for fields"));
+        assert!(error(parse_quote! {
+            struct Record {
+                #[destore(format = "octal")]
+                value: u32,
+            }
+        })
+        .contains("expected `\"hex\"` or `\"bin\"`"));
+        assert!(error(parse_quote! {
+            struct Record {
+                #[destore(offset = 3)]
+                value: u32,
+            }
+        })
+        .contains("unknown destore attribute"));
+        assert!(error(parse_quote! {
+            struct Record {
+                #[destore(scale = "0.1")]
+                value: u32,
+            }
+        })
+        .contains("expected a number"));
+    }
+}
@@ -0,0 +1,535 @@
+use crate::{DecodedRecord, FieldMetadata, Metadata};
+use anyhow::{bail, Context, Result};
+use arrow::datatypes::{DataType, Field, Fields, Schema, SchemaRef};
+use arrow::json::ReaderBuilder;
+use log::info;
+use parquet::arrow::ArrowWriter;
+use postcard_dyn::Value;
+use postcard_schema::schema::owned::{OwnedData, OwnedDataModelType, OwnedNamedField};
+use serde_json::Map;
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Name of the column holding the variant name of an enum
+const VARIANT_FIELD: &str = "variant";
+
+/// Number of records converted to arrow at once
+const BATCH_SIZE: usize = 1024;
+
+/// Derives the arrow schema of a table holding records of the given postcard schema.
+///
+/// The table has an `index` column (position of the entry in the partition) and a `record` column
+/// holding the record itself. Units, display formats and descriptions from the metadata are stored
+/// as field metadata, scaled fields become `Float64` columns.
+pub fn arrow_schema(ty: &OwnedDataModelType, metadata: &Metadata) -> Schema {
+    Schema::new(vec![
+        Field::new("index", DataType::UInt64, false),
+        arrow_field("record", ty, metadata),
+    ])
+}
+
+fn arrow_field(name: &str, ty: &OwnedDataModelType, metadata: &Metadata) -> Field {
+    Field::new(name, arrow_data_type(ty, metadata), is_nullable(ty))
+}
+
+/// A field of a struct, tuple struct or variant, annotated with its metadata.
+/// `owner` is the type and variant the field belongs to.
+fn annotated_field(
+    name: &str,
+    ty: &OwnedDataModelType,
+    metadata: &Metadata,
+    (owner, variant): (&str, Option<&str>),
+) -> Field {
+    let field = arrow_field(name, ty, metadata);
+    let Some(annotation) = metadata.field(owner, variant, name) else {
+        return field;
+    };
+    let field = if annotation.scale.is_some() && is_number(ty) {
+        field.with_data_type(DataType::Float64)
+    } else {
+        field
+    };
+    field.with_metadata(field_metadata(annotation))
+}
+
+fn field_metadata(annotation: &FieldMetadata) -> HashMap<String, String> {
+    let mut metadata = HashMap::new();
+    if let Some(unit) = &annotation.unit {
+        metadata.insert("unit".to_string(), unit.clone());
+    }
+    if let Some(scale) = annotation.scale {
+        metadata.insert("scale".to_string(), scale.to_string());
+    }
+    if let Some(format) = &annotation.format {
+        metadata.insert("format".to_string(), format.clone());
+    }
+    if let Some(description) = &annotation.description {
+        metadata.insert("description".to_string(), description.clone());
+    }
+    metadata
+}
+
+fn is_number(ty: &OwnedDataModelType) -> bool {
+    match ty {
+        OwnedDataModelType::Option(inner) => is_number(inner),
+        OwnedDataModelType::I8
+        | OwnedDataModelType::U8
+        | OwnedDataModelType::I16
+        | OwnedDataModelType::I32
+        | OwnedDataModelType::I64
+        | OwnedDataModelType::U16
+        | OwnedDataModelType::U32
+        | OwnedDataModelType::U64
+        | OwnedDataModelType::Usize
+        | OwnedDataModelType::Isize
+        | OwnedDataModelType::F32
+        | OwnedDataModelType::F64 => true,
+        _ => false,
+    }
+}
+
+fn is_nullable(ty: &OwnedDataModelType) -> bool {
+    match ty {
+        OwnedDataModelType::Option(_) | OwnedDataModelType::Unit => true,
+        OwnedDataModelType::Struct { data, .. } => match data {
+            OwnedData::Unit => true,
+            OwnedData::Newtype(inner) => is_nullable(inner),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Converts a postcard schema into an arrow data type.
+///
+/// Structs and tuples become struct columns, sequences lists and maps map columns.
+/// Parquet has no union type, so enums are stored as tagged structs: a `variant` column with the name
+/// of the variant, plus a nullable column for every variant that carries data.
+/// The descriptions of the variants are stored in the metadata of the `variant` column.
+pub fn arrow_data_type(ty: &OwnedDataModelType, metadata: &Metadata) -> DataType {
+    match ty {
+        OwnedDataModelType::Bool => DataType::Boolean,
+        OwnedDataModelType::I8 => DataType::Int8,
+        OwnedDataModelType::U8 => DataType::UInt8,
+        OwnedDataModelType::I16 => DataType::Int16,
+        OwnedDataModelType::I32 => DataType::Int32,
+        OwnedDataModelType::I64 => DataType::Int64,
+        // A Decimal128 holds only 38 digits, 128 bit integers keep their decimal representation
+        OwnedDataModelType::I128 => DataType::Utf8,
+        OwnedDataModelType::U16 => DataType::UInt16,
+        OwnedDataModelType::U32 => DataType::UInt32,
+        OwnedDataModelType::U64 => DataType::UInt64,
+        // See I128
+        OwnedDataModelType::U128 => DataType::Utf8,
+        OwnedDataModelType::Usize => DataType::UInt64,
+        OwnedDataModelType::Isize => DataType::Int64,
+        OwnedDataModelType::F32 => DataType::Float32,
+        OwnedDataModelType::F64 => DataType::Float64,
+        OwnedDataModelType::Char => DataType::Utf8,
+        OwnedDataModelType::String => DataType::Utf8,
+        // Hex encoded, the arrow json decoder cannot produce binary columns
+        OwnedDataModelType::ByteArray => DataType::Utf8,
+        OwnedDataModelType::Option(inner) => arrow_data_type(inner, metadata),
+        OwnedDataModelType::Unit => DataType::Null,
+        OwnedDataModelType::Seq(inner) => {
+            DataType::List(Arc::new(arrow_field("item", inner, metadata)))
+        }
+        OwnedDataModelType::Tuple(types) => DataType::Struct(tuple_fields(types, metadata, None)),
+        OwnedDataModelType::Map { key, val } => DataType::Map(
+            Arc::new(Field::new(
+                "entries",
+                DataType::Struct(Fields::from(vec![
+                    Field::new("keys", arrow_data_type(key, metadata), false),
+                    arrow_field("values", val, metadata),
+                ])),
+                false,
+            )),
+            false,
+        ),
+        OwnedDataModelType::Struct { name, data } => data_type_of(data, metadata, (name, None)),
+        OwnedDataModelType::Enum { name, variants } => {
+            let descriptions: HashMap<_, _> = variants
+                .iter()
+                .filter_map(|v| {
+                    let description = metadata.variant(name, &v.name)?.description.clone()?;
+                    Some((v.name.to_string(), description))
+                })
+                .collect();
+            let mut fields =
+                vec![Field::new(VARIANT_FIELD, DataType::Utf8, false).with_metadata(descriptions)];
+            for variant in variants.iter() {
+                if !matches!(variant.data, OwnedData::Unit) {
+                    fields.push(Field::new(
+                        &*variant.name,
+                        data_type_of(&variant.data, metadata, (name, Some(&variant.name))),
+                        true,
+                    ));
+                }
+            }
+            DataType::Struct(Fields::from(fields))
+        }
+        // Nested schemas are stored as their json representation
+        OwnedDataModelType::Schema => DataType::Utf8,
+    }
+}
+
+fn data_type_of(data: &OwnedData, metadata: &Metadata, owner: (&str, Option<&str>)) -> DataType {
+    match data {
+        OwnedData::Unit => DataType::Null,
+        OwnedData::Newtype(inner) => {
+            // Newtypes are not wrapped in a struct column, the annotation only affects the type
+            match metadata.field(owner.0, owner.1, "0") {
+                Some(annotation) if annotation.scale.is_some() && is_number(inner) => {
+                    DataType::Float64
+                }
+                _ => arrow_data_type(inner, metadata),
+            }
+        }
+        OwnedData::Tuple(types) => DataType::Struct(tuple_fields(types, metadata, Some(owner))),
+        OwnedData::Struct(fields) => DataType::Struct(
+            fields
+                .iter()
+                .map(|f| annotated_field(&f.name, &f.ty, metadata, owner))
+                .collect::<Vec<_>>()
+                .into(),
+        ),
+    }
+}
+
+fn tuple_fields(
+    types: &[OwnedDataModelType],
+    metadata: &Metadata,
+    owner: Option<(&str, Option<&str>)>,
+) -> Fields {
+    types
+        .iter()
+        .enumerate()
+        .map(|(i, ty)| match owner {
+            Some(owner) => annotated_field(&i.to_string(), ty, metadata, owner),
+            None => arrow_field(&i.to_string(), ty, metadata),
+        })
+        .collect::<Vec<_>>()
+        .into()
+}
+
+/// Rewrites a value decoded by postcard-dyn into the json shape the arrow json decoder expects
+/// for [`arrow_data_type`].
+fn normalize(ty: &OwnedDataModelType, value: Value) -> Result<Value> {
+    Ok(match ty {
+        OwnedDataModelType::I128 | OwnedDataModelType::U128 => match value {
+            Value::Number(n) => Value::String(n.to_string()),
+            value => value,
+        },
+        OwnedDataModelType::Option(inner) => match value {
+            Value::Null => Value::Null,
+            value => normalize(inner, value)?,
+        },
+        OwnedDataModelType::Unit => Value::Null,
+        OwnedDataModelType::Seq(inner) => Value::Array(
+            expect_array(value)?
+                .into_iter()
+                .map(|v| normalize(inner, v))
+                .collect::<Result<_>>()?,
+        ),
+        OwnedDataModelType::Tuple(types) => normalize_tuple(types, value)?,
+        OwnedDataModelType::Map { val, .. } => match value {
+            Value::Object(map) => Value::Object(
+                map.into_iter()
+                    .map(|(k, v)| Ok((k, normalize(val, v)?)))
+                    .collect::<Result<_>>()?,
+            ),
+            value => bail!("Expected map, got {}", value),
+        },
+        OwnedDataModelType::Struct { data, .. } => normalize_data(data, value)?,
+        OwnedDataModelType::Enum { variants, .. } => {
+            let (name, inner) = match value {
+                Value::String(name) => (name, Value::Null),
+                Value::Object(map) if map.len() == 1 => map.into_iter().next().unwrap(),
+                value => bail!("Expected enum variant, got {}", value),
+            };
+            let variant = variants
+                .iter()
+                .find(|v| *v.name == *name)
+                .with_context(|| format!("Unknown enum variant {}", name))?;
+            let mut object = Map::new();
+            object.insert(VARIANT_FIELD.to_string(), Value::String(name.clone()));
+            if !matches!(variant.data, OwnedData::Unit) {
+                object.insert(name, normalize_data(&variant.data, inner)?);
+            }
+            Value::Object(object)
+        }
+        OwnedDataModelType::Schema => Value::String(value.to_string()),
+        _ => value,
+    })
+}
+
+fn normalize_data(data: &OwnedData, value: Value) -> Result<Value> {
+    Ok(match data {
+        OwnedData::Unit => Value::Null,
+        OwnedData::Newtype(inner) => normalize(inner, value)?,
+        OwnedData::Tuple(types) => normalize_tuple(types, value)?,
+        OwnedData::Struct(fields) => normalize_struct(fields, value)?,
+    })
+}
+
+fn normalize_tuple(types: &[OwnedDataModelType], value: Value) -> Result<Value> {
+    let values = expect_array(value)?;
+    if values.len() != types.len() {
+        bail!(
+            "Expected tuple of {} elements, got {}",
+            types.len(),
+            values.len()
+        );
+    }
+    Ok(Value::Object(
+        types
+            .iter()
+            .zip(values)
+            .enumerate()
+            .map(|(i, (ty, v))| Ok((i.to_string(), normalize(ty, v)?)))
+            .collect::<Result<_>>()?,
+    ))
+}
+
+fn normalize_struct(fields: &[OwnedNamedField], value: Value) -> Result<Value> {
+    let Value::Object(mut map) = value else {
+        bail!("Expected struct, got {}", value);
+    };
+    Ok(Value::Object(
+        fields
+            .iter()
+            .map(|f| {
+                let v = map.remove(&*f.name).unwrap_or(Value::Null);
+                Ok((f.name.to_string(), normalize(&f.ty, v)?))
+            })
+            .collect::<Result<_>>()?,
+    ))
+}
+
+fn expect_array(value: Value) -> Result<Vec<Value>> {
+    match value {
+        Value::Array(values) => Ok(values),
+        value => bail!("Expected sequence, got {}", value),
+    }
+}
+
+/// Writes records sharing the same schema to a parquet file
+pub fn write_parquet(
+    path: &Path,
+    schema: &OwnedDataModelType,
+    metadata: &Metadata,
+    records: &[&DecodedRecord],
+) -> Result<()> {
+    let arrow_schema: SchemaRef = Arc::new(arrow_schema(schema, metadata));
+    let file = File::create(path).with_context(|| format!("Failed to create {:?}", path))?;
+    let mut writer = ArrowWriter::try_new(file, arrow_schema.clone(), None)?;
+
+    for chunk in records.chunks(BATCH_SIZE) {
+        let rows = chunk
+            .iter()
+            .map(|r| {
+                let mut row = Map::new();
+                row.insert("index".to_string(), Value::from(r.index));
+                row.insert("record".to_string(), normalize(schema, r.value.clone())?);
+                Ok(Value::Object(row))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut decoder = ReaderBuilder::new(arrow_schema.clone())
+            .with_batch_size(BATCH_SIZE)
+            .build_decoder()?;
+        decoder.serialize(&rows)?;
+        if let Some(batch) = decoder.flush()? {
+            writer.write(&batch)?;
+        }
+    }
+    writer.close()?;
+
+    info!("Wrote {} records to {:?}", records.len(), path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::{Array, AsArray};
+    use arrow::datatypes::{Int32Type, UInt16Type, UInt64Type};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use postcard_schema::schema::owned::OwnedVariant;
+    use serde_json::json;
+
+    #[test]
+    fn test_write_parquet() {
+        let schema = OwnedDataModelType::Enum {
+            name: "Record".into(),
+            variants: Box::new([
+                OwnedVariant {
+                    name: "Boot".into(),
+                    data: OwnedData::Unit,
+                },
+                OwnedVariant {
+                    name: "Sub".into(),
+                    data: OwnedData::Struct(Box::new([
+                        OwnedNamedField {
+                            name: "name".into(),
+                            ty: OwnedDataModelType::String,
+                        },
+                        OwnedNamedField {
+                            name: "pos".into(),
+                            ty: OwnedDataModelType::Tuple(Box::new([
+                                OwnedDataModelType::I32,
+                                OwnedDataModelType::Option(Box::new(OwnedDataModelType::U8)),
+                            ])),
+                        },
+                        OwnedNamedField {
+                            name: "total".into(),
+                            ty: OwnedDataModelType::I128,
+                        },
+                        OwnedNamedField {
+                            name: "count".into(),
+                            ty: OwnedDataModelType::U128,
+                        },
+                        OwnedNamedField {
+                            name: "tags".into(),
+                            ty: OwnedDataModelType::Seq(Box::new(OwnedDataModelType::U16)),
+                        },
+                    ])),
+                },
+            ]),
+        };
+        let records = [
+            DecodedRecord {
+                index: 1,
+                offset: None,
+                schema_hash: [0; 8],
+                value: json!("Boot"),
+            },
+            DecodedRecord {
+                index: 2,
+                offset: None,
+                schema_hash: [0; 8],
+                value: json!({"Sub": {"name": "Alice", "pos": [-3, null], "total": -9_000_000_000_000_000_000i64, "count": 7, "tags": [1, 2]}}),
+            },
+            // Json numbers end at u64::MAX, larger 128 bit integers arrive as decimal strings
+            DecodedRecord {
+                index: 3,
+                offset: None,
+                schema_hash: [0; 8],
+                value: json!({"Sub": {"name": "Bob", "pos": [0, 1], "total": u64::MAX, "count": u128::MAX.to_string(), "tags": []}}),
+            },
+        ];
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        write_parquet(
+            file.path(),
+            &schema,
+            &Metadata::default(),
+            &records.iter().collect::<Vec<_>>(),
+        )
+        .unwrap();
+
+        let batches = ParquetRecordBatchReaderBuilder::try_new(File::open(file.path()).unwrap())
+            .unwrap()
+            .build()
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(batches.len(), 1);
+        let batch = &batches[0];
+        assert_eq!(batch.num_rows(), 3);
+        assert_eq!(
+            batch.schema().as_ref(),
+            &arrow_schema(&schema, &Metadata::default())
+        );
+
+        let index = batch.column(0).as_primitive::<UInt64Type>();
+        assert_eq!(index.values(), &[1, 2, 3]);
+        let record = batch.column(1).as_struct();
+        let variant = record
+            .column_by_name(VARIANT_FIELD)
+            .unwrap()
+            .as_string::<i32>();
+        assert_eq!(variant.value(0), "Boot");
+        assert_eq!(variant.value(1), "Sub");
+
+        let sub = record.column_by_name("Sub").unwrap().as_struct();
+        assert!(sub.is_null(0));
+        let name = sub.column_by_name("name").unwrap().as_string::<i32>();
+        assert_eq!(name.value(1), "Alice");
+        let pos = sub.column_by_name("pos").unwrap().as_struct();
+        assert_eq!(pos.column(0).as_primitive::<Int32Type>().value(1), -3);
+        assert!(pos.column(1).is_null(1));
+        let total = sub.column_by_name("total").unwrap();
+        assert_eq!(total.data_type(), &DataType::Utf8);
+        assert_eq!(total.as_string::<i32>().value(1), "-9000000000000000000");
+        assert_eq!(total.as_string::<i32>().value(2), "18446744073709551615");
+        let count = sub.column_by_name("count").unwrap();
+        assert_eq!(count.data_type(), &DataType::Utf8);
+        assert_eq!(count.as_string::<i32>().value(1), "7");
+        assert_eq!(
+            count.as_string::<i32>().value(2),
+            "340282366920938463463374607431768211455"
+        );
+        let tags = sub
+            .column_by_name("tags")
+            .unwrap()
+            .as_list::<i32>()
+            .value(1);
+        assert_eq!(tags.as_primitive::<UInt16Type>().values(), &[1, 2]);
+    }
+
+    #[test]
+    fn test_field_metadata() {
+        let schema = OwnedDataModelType::Struct {
+            name: "Reading".into(),
+            data: OwnedData::Struct(Box::new([
+                OwnedNamedField {
+                    name: "status".into(),
+                    ty: OwnedDataModelType::U8,
+                },
+                OwnedNamedField {
+                    name: "value".into(),
+                    ty: OwnedDataModelType::I16,
+                },
+            ])),
+        };
+        let annotation = |field: &str| FieldMetadata {
+            variant: None,
+            field: Some(field.to_string()),
+            unit: None,
+            scale: None,
+            format: None,
+            description: None,
+        };
+        let metadata = Metadata {
+            types: vec![crate::TypeMetadata {
+                name: "Reading".to_string(),
+                schema_hash: [0; 8],
+                fields: vec![
+                    FieldMetadata {
+                        format: Some("hex".to_string()),
+                        ..annotation("status")
+                    },
+                    FieldMetadata {
+                        unit: Some("°C".to_string()),
+                        scale: Some(0.01),
+                        ..annotation("value")
+                    },
+                ],
+            }],
+        };
+
+        let arrow_schema = arrow_schema(&schema, &metadata);
+        let DataType::Struct(fields) = arrow_schema.field(1).data_type() else {
+            panic!("record is not a struct column");
+        };
+        // The display format is only a hint, the column keeps its integer type
+        assert_eq!(fields[0].data_type(), &DataType::UInt8);
+        assert_eq!(fields[0].metadata()["format"], "hex");
+        assert_eq!(fields[1].data_type(), &DataType::Float64);
+        assert_eq!(fields[1].metadata()["unit"], "°C");
+        assert_eq!(fields[1].metadata()["scale"], "0.01");
+    }
+}
@@ -0,0 +1,36 @@
+use destore::log_backend::LogRecord;
+use destore::panic::PanicRecord;
+use destore::{DataModelType, Schema};
+use postcard_schema::key::hash::fnv1a64_owned::hash_ty_path_owned;
+use postcard_schema::schema::owned::OwnedDataModelType;
+
+/// Schemas of the records written by destore itself, decoded without being exported by the firmware
+const BUILTIN_SCHEMAS: &[&DataModelType] = &[LogRecord::SCHEMA, PanicRecord::SCHEMA];
+
+/// Looks up a schema of a record type defined by destore
+pub fn builtin_schema(schema_hash: &[u8; 8]) -> Option<OwnedDataModelType> {
+    BUILTIN_SCHEMAS
+        .iter()
+        .map(|&schema| OwnedDataModelType::from(schema))
+        .find(|schema| hash_ty_path_owned("", schema) == *schema_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use postcard_schema::key::hash::fnv1a64::hash_ty_path;
+
+    #[test]
+    fn test_builtin_schemas() {
+        let hash = hash_ty_path::<LogRecord>("");
+        assert_eq!(
+            builtin_schema(&hash),
+            Some(OwnedDataModelType::from(LogRecord::SCHEMA))
+        );
+        let hash = hash_ty_path::<PanicRecord>("");
+        assert_eq!(
+            builtin_schema(&hash),
+            Some(OwnedDataModelType::from(PanicRecord::SCHEMA))
+        );
+    }
+}
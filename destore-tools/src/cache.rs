@@ -1,34 +1,80 @@
+use crate::{
+    defmt_table_elf, format_hash, format_hex, parse_hash, parse_hex, CacheIndex, Config,
+    IndexEntry, LogDescriptor, Metadata, Provenance, INDEX_FILE,
+};
+use anyhow::{bail, Context};
+use defmt_decoder::Table;
 use log::info;
 use postcard_schema::key::hash::fnv1a64_owned::hash_ty_path_owned;
 use postcard_schema::schema::owned::OwnedDataModelType;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashSet};
+use std::ffi::OsString;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+/// Environment variable with the cache directories, separated like `PATH`
+pub const CACHE_ENV: &str = "DESTORE_CACHE";
+
+/// Directory of the cache used by older versions, still looked up if it exists
+const LEGACY_DIR: &str = ".destore";
+
+/// Where the schemas to decode a partition come from
+pub trait SchemaSource {
+    fn lookup(&self, schema_hash: &[u8; 8]) -> anyhow::Result<Option<OwnedDataModelType>>;
+
+    /// Metadata of the types of a schema, empty if none was exported
+    fn lookup_metadata(&self, schema_hash: &[u8; 8]) -> anyhow::Result<Metadata>;
+
+    /// The defmt table of the firmware that exported a schema, if it uses defmt
+    fn defmt_table(&self, schema_hash: &[u8; 8]) -> anyhow::Result<Option<Rc<Table>>>;
+}
+
+/// Schemas, metadata and logs extracted from flashed ELFs.
+///
+/// Everything is stored in the first directory, lookups fall through all of them in order.
 pub struct Cache {
-    dir: PathBuf,
+    dirs: Vec<PathBuf>,
 }
 
 impl Cache {
-    pub fn new() -> Self {
-        /* let mut dir = PathBuf::from(
-            std::env::var_os("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR not set"),
-        );*/
-        let mut dir = PathBuf::from(std::env::current_dir().expect("failed to get current dir"));
-        dir.push(".destore");
-        info!("Destore Cache Directory: {:?}", dir);
-        if !dir.exists() {
-            std::fs::create_dir(&dir).expect("failed to create cache dir");
-        }
-        Self { dir }
+    pub fn new(dirs: Vec<PathBuf>) -> Self {
+        info!("Destore Cache Directories: {:?}", dirs);
+        Self { dirs }
     }
 
-    pub fn store(&mut self, schema: &OwnedDataModelType) -> anyhow::Result<()> {
-        let schema_hash = hash_ty_path_owned("", schema);
-        let schema_hash: String = schema_hash.iter().map(|b| format!("{:02x}", b)).collect();
+    /// Locates the cache directories. The first of these that is set takes precedence:
+    /// `cli_dirs` (`--cache-dir`), `DESTORE_CACHE` and `cache.dirs` of the nearest `destore.toml`.
+    /// They are followed by `.destore` in the current directory if it exists and by the per-user
+    /// directory (`$XDG_DATA_HOME/destore`). If nothing is configured, schemas are stored in
+    /// `.destore` if it exists, otherwise in the per-user directory.
+    pub fn locate(cli_dirs: &[PathBuf]) -> anyhow::Result<Self> {
+        let cwd = std::env::current_dir().context("Failed to get current dir")?;
+        let user_dir = dirs::data_dir().map(|dir| dir.join("destore"));
+        let dirs = cache_dirs(cli_dirs, std::env::var_os(CACHE_ENV), &cwd, user_dir)?;
+        Ok(Self::new(dirs))
+    }
+
+    fn store_dir(&self) -> anyhow::Result<&Path> {
+        let dir = self.dirs.first().context("No cache directory configured")?;
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create cache dir {:?}", dir))?;
+        Ok(dir)
+    }
 
-        let mut path = self.dir.clone();
-        path.push(format!("{}.pcs", schema_hash));
+    /// The first existing file with this name in the cache directories
+    fn find(&self, file_name: &str) -> Option<PathBuf> {
+        self.dirs
+            .iter()
+            .map(|dir| dir.join(file_name))
+            .find(|path| path.exists())
+    }
+
+    pub fn store(&mut self, schema: &OwnedDataModelType) -> anyhow::Result<()> {
+        let schema_hash = format_hash(&hash_ty_path_owned("", schema));
 
+        let path = self.store_dir()?.join(format!("{}.pcs", schema_hash));
         if path.exists() {
             info!("Schema {} already stored", schema_hash);
             return Ok(());
@@ -40,18 +86,572 @@ impl Cache {
         Ok(())
     }
 
-    pub fn lookup(&mut self, schema_hash: &[u8; 8]) -> anyhow::Result<Option<OwnedDataModelType>> {
-        let schema_hash: String = schema_hash.iter().map(|b| format!("{:02x}", b)).collect();
-        let mut path = self.dir.clone();
-        path.push(format!("{}.pcs", schema_hash));
-        if !path.exists() {
+    pub fn lookup(&self, schema_hash: &[u8; 8]) -> anyhow::Result<Option<OwnedDataModelType>> {
+        let schema_hash = format_hash(schema_hash);
+        let Some(path) = self.find(&format!("{}.pcs", schema_hash)) else {
             info!(
-                "Schema {:?} not found in cache dir {:?}",
-                schema_hash, self.dir
+                "Schema {:?} not found in cache dirs {:?}",
+                schema_hash, self.dirs
             );
             return Ok(None);
-        }
+        };
         let schema = postcard::from_bytes(&fs::read(&path)?)?;
         Ok(Some(schema))
     }
+
+    /// Stores the metadata of the types of a schema, if there is any
+    pub fn store_metadata(
+        &mut self,
+        schema: &OwnedDataModelType,
+        metadata: &Metadata,
+    ) -> anyhow::Result<()> {
+        if metadata.is_empty() {
+            return Ok(());
+        }
+        let file_name = metadata_file_name(&hash_ty_path_owned("", schema));
+        let path = self.store_dir()?.join(file_name);
+        fs::write(&path, serde_json::to_vec_pretty(metadata)?)?;
+        info!("Stored metadata to {:?}", path);
+        Ok(())
+    }
+
+    /// Metadata of the types of a schema, empty if none was exported
+    pub fn lookup_metadata(&self, schema_hash: &[u8; 8]) -> anyhow::Result<Metadata> {
+        let Some(path) = self.find(&metadata_file_name(schema_hash)) else {
+            return Ok(Metadata::default());
+        };
+        serde_json::from_slice(&fs::read(&path)?)
+            .with_context(|| format!("Failed to read metadata {:?}", path))
+    }
+
+    /// Stores the defmt table of an ELF, reduced to the parts the decoder reads
+    pub fn store_defmt_table(&mut self, id: &str, elf: &[u8]) -> anyhow::Result<()> {
+        let file_name = defmt_file_name(id)?;
+        let dir = self.store_dir()?.join(DEFMT_DIR);
+        fs::create_dir_all(&dir)?;
+        let path = self.store_dir()?.join(file_name);
+        if path.exists() {
+            info!("Defmt table {} already stored", id);
+            return Ok(());
+        }
+        fs::write(&path, defmt_table_elf(elf)?)?;
+        info!("Stored defmt table {} to {:?}", id, path);
+        Ok(())
+    }
+
+    /// All schemas in the cache, sorted by hash
+    pub fn schemas(&self) -> anyhow::Result<Vec<([u8; 8], OwnedDataModelType)>> {
+        let mut hashes = HashSet::new();
+        for dir in &self.dirs {
+            hashes.extend(schema_files(dir)?.into_iter().map(|(hash, _)| hash));
+        }
+        let mut schemas = Vec::new();
+        for hash in hashes {
+            if let Some(schema) = self.lookup(&hash)? {
+                schemas.push((hash, schema));
+            }
+        }
+        schemas.sort_by_key(|(hash, _)| *hash);
+        Ok(schemas)
+    }
+
+    /// Records in the index of the store directory which firmware a schema was extracted from
+    pub fn index_schema(
+        &mut self,
+        schema: &OwnedDataModelType,
+        provenance: &Provenance,
+    ) -> anyhow::Result<()> {
+        self.update_index(|index| index.insert(IndexEntry::new(schema, provenance.clone())))
+    }
+
+    fn update_index(&mut self, update: impl FnOnce(&mut CacheIndex)) -> anyhow::Result<()> {
+        let path = self.store_dir()?.join(INDEX_FILE);
+        let mut index = CacheIndex::load(&path)?;
+        update(&mut index);
+        index.save(&path)
+    }
+
+    /// The indexes of all cache directories merged
+    pub fn index(&self) -> anyhow::Result<CacheIndex> {
+        let mut merged = CacheIndex::default();
+        for dir in self.dirs.iter().rev() {
+            for entry in CacheIndex::load(&dir.join(INDEX_FILE))?.entries {
+                merged.insert(entry);
+            }
+        }
+        Ok(merged)
+    }
+
+    /// Stores a log declared with `export_log!`, replacing an older declaration of the same name.
+    /// Its schema is stored as well.
+    pub fn store_log(&mut self, log: &LogDescriptor) -> anyhow::Result<()> {
+        if !is_identifier(&log.name) {
+            bail!("Invalid log name {:?}, expected an identifier", log.name);
+        }
+        self.store(&log.schema)?;
+        let dir = self.store_dir()?.join(LOGS_DIR);
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("{}.json", log.name));
+        fs::write(&path, serde_json::to_vec_pretty(log)?)?;
+        info!("Stored log {} to {:?}", log.name, path);
+        Ok(())
+    }
+
+    /// All logs in the cache, sorted by name. A log in an earlier directory hides one of the same
+    /// name in a later directory.
+    pub fn logs(&self) -> anyhow::Result<Vec<LogDescriptor>> {
+        let mut logs: Vec<LogDescriptor> = Vec::new();
+        for dir in &self.dirs {
+            let dir = dir.join(LOGS_DIR);
+            if !dir.exists() {
+                continue;
+            }
+            for entry in fs::read_dir(&dir)? {
+                let path = entry?.path();
+                if path.extension().is_some_and(|ext| ext == "json") {
+                    let log: LogDescriptor = serde_json::from_slice(&fs::read(&path)?)
+                        .with_context(|| format!("Failed to read log {:?}", path))?;
+                    if !logs.iter().any(|l| l.name == log.name) {
+                        logs.push(log);
+                    }
+                }
+            }
+        }
+        logs.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(logs)
+    }
+
+    /// Looks up a log by name (case insensitive)
+    pub fn lookup_log(&self, name: &str) -> anyhow::Result<Option<LogDescriptor>> {
+        Ok(self.logs()?.into_iter().find(|log| log.has_name(name)))
+    }
+
+    /// Copies everything in the cache, e.g. to move it to another machine
+    pub fn export(&self) -> anyhow::Result<CacheArchive> {
+        let mut schemas = Vec::new();
+        for (hash, schema) in self.schemas()? {
+            schemas.push(ArchivedSchema {
+                metadata: self.lookup_metadata(&hash)?,
+                schema,
+            });
+        }
+        let index = self.index()?;
+        let mut defmt_tables = Vec::new();
+        let ids: BTreeSet<_> = index
+            .entries
+            .iter()
+            .filter_map(|e| e.provenance.defmt_table.as_deref())
+            .collect();
+        for id in ids {
+            if let Some(path) = self.find(&defmt_file_name(id)?) {
+                defmt_tables.push(ArchivedDefmtTable {
+                    id: id.to_string(),
+                    elf: format_hex(&fs::read(&path)?),
+                });
+            }
+        }
+        Ok(CacheArchive {
+            schemas,
+            logs: self.logs()?,
+            defmt_tables,
+            index,
+        })
+    }
+
+    /// Stores the content of an archive in the store directory. Logs of the same name are
+    /// replaced, the index entries are merged.
+    pub fn import(&mut self, archive: &CacheArchive) -> anyhow::Result<()> {
+        for archived in &archive.schemas {
+            self.store(&archived.schema)?;
+            self.store_metadata(&archived.schema, &archived.metadata)?;
+        }
+        for log in &archive.logs {
+            self.store_log(log)?;
+        }
+        for table in &archive.defmt_tables {
+            let elf = parse_hex(&table.elf)
+                .with_context(|| format!("Malformed defmt table {}", table.id))?;
+            self.store_defmt_table(&table.id, &elf)?;
+        }
+        self.update_index(|index| {
+            for entry in &archive.index.entries {
+                index.insert(entry.clone());
+            }
+        })
+    }
+
+    /// Removes the schemas from the store directory that were last extracted before `cutoff`,
+    /// together with their metadata, index entries and defmt tables. Schemas of logs are kept, as are the other
+    /// directories, which may be shared. Returns the hashes of the (with `dry_run` only found)
+    /// schemas.
+    pub fn prune(&mut self, cutoff: SystemTime, dry_run: bool) -> anyhow::Result<Vec<[u8; 8]>> {
+        let dir = self.store_dir()?.to_path_buf();
+        let index_path = dir.join(INDEX_FILE);
+        let index = CacheIndex::load(&index_path)?;
+        let log_schemas: HashSet<_> = self.logs()?.iter().map(|l| l.schema_hash()).collect();
+
+        let mut pruned = Vec::new();
+        for (hash, path) in schema_files(&dir)? {
+            let last_seen = match index.for_schema(&hash).last() {
+                Some(entry) => UNIX_EPOCH + Duration::from_secs(entry.provenance.timestamp),
+                None => fs::metadata(&path)?.modified()?,
+            };
+            if last_seen >= cutoff || log_schemas.contains(&hash) {
+                continue;
+            }
+            if !dry_run {
+                fs::remove_file(&path)?;
+                let metadata_path = dir.join(metadata_file_name(&hash));
+                if metadata_path.exists() {
+                    fs::remove_file(metadata_path)?;
+                }
+                info!("Pruned schema {:?}", path);
+            }
+            pruned.push(hash);
+        }
+        pruned.sort();
+
+        if !dry_run && !pruned.is_empty() {
+            let mut index = index;
+            let pruned: HashSet<_> = pruned.iter().map(format_hash).collect();
+            index.entries.retain(|e| !pruned.contains(&e.hash));
+            index.save(&index_path)?;
+            self.prune_defmt_tables(&dir, &index)?;
+        }
+        Ok(pruned)
+    }
+
+    /// Removes the defmt tables no firmware in the index refers to
+    fn prune_defmt_tables(&self, dir: &Path, index: &CacheIndex) -> anyhow::Result<()> {
+        let dir = dir.join(DEFMT_DIR);
+        if !dir.exists() {
+            return Ok(());
+        }
+        let used: HashSet<_> = index
+            .entries
+            .iter()
+            .filter_map(|e| e.provenance.defmt_table.as_deref())
+            .collect();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let id = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .unwrap_or_default();
+            if path.extension().is_some_and(|ext| ext == "elf") && !used.contains(id) {
+                fs::remove_file(&path)?;
+                info!("Pruned defmt table {:?}", path);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SchemaSource for Cache {
+    fn lookup(&self, schema_hash: &[u8; 8]) -> anyhow::Result<Option<OwnedDataModelType>> {
+        Cache::lookup(self, schema_hash)
+    }
+
+    fn lookup_metadata(&self, schema_hash: &[u8; 8]) -> anyhow::Result<Metadata> {
+        Cache::lookup_metadata(self, schema_hash)
+    }
+
+    /// The table of the firmware that was last extracted with this schema
+    fn defmt_table(&self, schema_hash: &[u8; 8]) -> anyhow::Result<Option<Rc<Table>>> {
+        let index = self.index()?;
+        let Some(id) = index
+            .for_schema(schema_hash)
+            .into_iter()
+            .rev()
+            .find_map(|e| e.provenance.defmt_table.as_deref())
+        else {
+            return Ok(None);
+        };
+        let Some(path) = self.find(&defmt_file_name(id)?) else {
+            return Ok(None);
+        };
+        let table = Table::parse(&fs::read(&path)?)
+            .with_context(|| format!("Failed to read the defmt table of {:?}", path))?;
+        Ok(table.map(Rc::new))
+    }
+}
+
+/// A portable copy of a cache, written by `destore cache export`.
+/// The hashes are not part of it, they are computed from the schemas on import.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct CacheArchive {
+    pub schemas: Vec<ArchivedSchema>,
+    pub logs: Vec<LogDescriptor>,
+    /// The defmt tables the firmwares in the index refer to
+    #[serde(default)]
+    pub defmt_tables: Vec<ArchivedDefmtTable>,
+    pub index: CacheIndex,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ArchivedSchema {
+    pub schema: OwnedDataModelType,
+    pub metadata: Metadata,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ArchivedDefmtTable {
+    pub id: String,
+    /// The reduced ELF as stored in the cache, hex encoded
+    pub elf: String,
+}
+
+/// The `<hash>.pcs` files in a cache directory
+fn schema_files(dir: &Path) -> anyhow::Result<Vec<([u8; 8], PathBuf)>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "pcs") {
+            if let Some(hash) = path.file_stem().and_then(|s| parse_hash(s.to_str()?)) {
+                files.push((hash, path));
+            }
+        }
+    }
+    Ok(files)
+}
+
+const LOGS_DIR: &str = "logs";
+const DEFMT_DIR: &str = "defmt";
+
+/// Defmt table ids are hashes, see `defmt_table_id`, which also keeps them from escaping the defmt directory
+fn defmt_file_name(id: &str) -> anyhow::Result<String> {
+    if parse_hash(id).is_none() {
+        bail!("Invalid defmt table id {:?}, expected a hash", id);
+    }
+    Ok(format!("{}/{}.elf", DEFMT_DIR, id))
+}
+
+fn metadata_file_name(schema_hash: &[u8; 8]) -> String {
+    format!("{}.meta.json", format_hash(schema_hash))
+}
+
+/// Log names are Rust identifiers, which also keeps them from escaping the logs directory
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// The cache directories in lookup order, see `Cache::locate`
+fn cache_dirs(
+    cli_dirs: &[PathBuf],
+    env: Option<OsString>,
+    cwd: &Path,
+    user_dir: Option<PathBuf>,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let mut dirs = if !cli_dirs.is_empty() {
+        cli_dirs.iter().map(|dir| cwd.join(dir)).collect()
+    } else if let Some(env) = env.filter(|env| !env.is_empty()) {
+        std::env::split_paths(&env)
+            .map(|dir| cwd.join(dir))
+            .collect()
+    } else {
+        Config::find(cwd)?.unwrap_or_default().cache.dirs
+    };
+    let legacy_dir = cwd.join(LEGACY_DIR);
+    if legacy_dir.is_dir() {
+        dirs.push(legacy_dir);
+    }
+    dirs.extend(user_dir);
+    // The same directory may be reached by different paths, e.g. `.destore` configured explicitly
+    let mut seen = HashSet::new();
+    dirs.retain(|dir| seen.insert(fs::canonicalize(dir).unwrap_or_else(|_| dir.clone())));
+    if dirs.is_empty() {
+        anyhow::bail!(
+            "No cache directory, pass --cache-dir or set {} or HOME",
+            CACHE_ENV
+        );
+    }
+    Ok(dirs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{defmt_test_elf, CONFIG_FILE};
+
+    #[test]
+    fn test_cache_dirs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let project = tmp.path().join("project");
+        let cwd = project.join("firmware");
+        fs::create_dir_all(&cwd).unwrap();
+        let user = tmp.path().join("user");
+
+        // Nothing configured
+        assert_eq!(
+            cache_dirs(&[], None, &cwd, Some(user.clone())).unwrap(),
+            vec![user.clone()]
+        );
+
+        // destore.toml in a parent directory, relative to the config file
+        fs::write(
+            project.join(CONFIG_FILE),
+            "[cache]\ndirs = [\"cache\", \"/shared\"]\n",
+        )
+        .unwrap();
+        assert_eq!(
+            cache_dirs(&[], None, &cwd, Some(user.clone())).unwrap(),
+            [
+                project.join("cache"),
+                PathBuf::from("/shared"),
+                user.clone()
+            ]
+        );
+
+        // The environment overrides the config, the flag overrides both
+        let env = std::env::join_paths(["a", "/b"]).unwrap();
+        assert_eq!(
+            cache_dirs(&[], Some(env.clone()), &cwd, Some(user.clone())).unwrap(),
+            [cwd.join("a"), PathBuf::from("/b"), user.clone()]
+        );
+        fs::create_dir(cwd.join(LEGACY_DIR)).unwrap();
+        assert_eq!(
+            cache_dirs(&["c".into()], Some(env), &cwd, Some(user.clone())).unwrap(),
+            [cwd.join("c"), cwd.join(LEGACY_DIR), user.clone()]
+        );
+
+        // Directories are searched once, however they are reached
+        let legacy = PathBuf::from("..").join("firmware").join(LEGACY_DIR);
+        let user_again = tmp.path().join("project").join("..").join("user");
+        fs::create_dir(&user).unwrap();
+        assert_eq!(
+            cache_dirs(
+                &[legacy.clone(), user_again.clone()],
+                None,
+                &cwd,
+                Some(user)
+            )
+            .unwrap(),
+            [cwd.join(legacy), user_again]
+        );
+    }
+
+    #[test]
+    fn test_export_import_prune() {
+        let tmp = tempfile::tempdir().unwrap();
+        let schema = OwnedDataModelType::U16;
+        let hash = hash_ty_path_owned("", &schema);
+        let provenance = Provenance {
+            elf_path: "firmware.elf".into(),
+            defmt_table: Some("0123456789abcdef".to_string()),
+            timestamp: 1_000,
+            ..Provenance::default()
+        };
+        let mut source = Cache::new(vec![tmp.path().join("source")]);
+        source.store(&schema).unwrap();
+        source.index_schema(&schema, &provenance).unwrap();
+        let elf = defmt_test_elf(&["{}"]);
+        source.store_defmt_table("0123456789abcdef", &elf).unwrap();
+        source.store_defmt_table("fedcba9876543210", &elf).unwrap();
+
+        let archive = source.export().unwrap();
+        let json = serde_json::to_vec(&archive).unwrap();
+        let archive: CacheArchive = serde_json::from_slice(&json).unwrap();
+        let mut target = Cache::new(vec![tmp.path().join("target")]);
+        target.import(&archive).unwrap();
+        assert_eq!(target.schemas().unwrap(), [(hash, schema.clone())]);
+        assert_eq!(target.index().unwrap(), source.index().unwrap());
+        let [table] = &archive.defmt_tables[..] else {
+            panic!("Expected only the used defmt table");
+        };
+        assert_eq!(table.id, "0123456789abcdef");
+        let stored = tmp
+            .path()
+            .join("target")
+            .join(defmt_file_name(&table.id).unwrap());
+        assert_eq!(fs::read(stored).unwrap(), defmt_table_elf(&elf).unwrap());
+
+        // Extracted at 1000s after the epoch, long before the cutoff
+        let cutoff = UNIX_EPOCH + Duration::from_secs(2_000);
+        assert_eq!(target.prune(cutoff, true).unwrap(), [hash]);
+        assert!(target.lookup(&hash).unwrap().is_some());
+        assert!(target.prune(UNIX_EPOCH, false).unwrap().is_empty());
+        assert_eq!(target.prune(cutoff, false).unwrap(), [hash]);
+        assert!(target.lookup(&hash).unwrap().is_none());
+        assert!(target.index().unwrap().entries.is_empty());
+
+        // Only the defmt tables of firmwares in the index are kept
+        let defmt_dir = tmp.path().join("source").join(DEFMT_DIR);
+        source.prune(UNIX_EPOCH, false).unwrap();
+        assert_eq!(fs::read_dir(&defmt_dir).unwrap().count(), 2);
+        source.prune(cutoff, false).unwrap();
+        assert_eq!(fs::read_dir(&defmt_dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_store_log() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut cache = Cache::new(vec![tmp.path().join("cache")]);
+        let mut log = LogDescriptor {
+            name: "EVENTS_2".to_string(),
+            flash_range: 0x1000..0x3000,
+            description: String::new(),
+            schema: OwnedDataModelType::U16,
+        };
+        cache.store_log(&log).unwrap();
+        assert_eq!(cache.lookup_log("events_2").unwrap(), Some(log.clone()));
+
+        for name in [
+            "",
+            "../EVENTS",
+            "logs/EVENTS",
+            "/tmp/EVENTS",
+            "2EVENTS",
+            "EVENTS.json",
+        ] {
+            log.name = name.to_string();
+            assert!(cache.store_log(&log).is_err(), "{:?}", name);
+        }
+        assert!(!tmp.path().join("EVENTS.json").exists());
+    }
+
+    #[test]
+    fn test_import_rejects_defmt_table_ids() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut cache = Cache::new(vec![tmp.path().join("cache")]);
+        let elf = format_hex(&defmt_test_elf(&["{}"]));
+        for id in [
+            "",
+            "../../x",
+            "0123456789abcde",
+            "0123456789abcdef/",
+            "../0123456789abcd",
+        ] {
+            let archive = CacheArchive {
+                defmt_tables: vec![ArchivedDefmtTable {
+                    id: id.to_string(),
+                    elf: elf.clone(),
+                }],
+                ..CacheArchive::default()
+            };
+            assert!(cache.import(&archive).is_err(), "{:?}", id);
+        }
+        assert!(!tmp.path().join("x.elf").exists());
+        assert!(!tmp.path().join("cache").join(DEFMT_DIR).exists());
+    }
+
+    #[test]
+    fn test_lookup_falls_through() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (first, second) = (tmp.path().join("first"), tmp.path().join("second"));
+        let schema = OwnedDataModelType::U16;
+        Cache::new(vec![second.clone()]).store(&schema).unwrap();
+
+        let cache = Cache::new(vec![first.clone(), second]);
+        let hash = hash_ty_path_owned("", &schema);
+        assert_eq!(cache.lookup(&hash).unwrap(), Some(schema));
+        assert_eq!(cache.lookup(&[0xff; 8]).unwrap(), None);
+        assert!(!first.exists());
+    }
 }
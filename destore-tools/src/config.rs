@@ -0,0 +1,53 @@
+use anyhow::Context;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Name of the project configuration file, looked up in the current directory and its ancestors
+pub const CONFIG_FILE: &str = "destore.toml";
+
+/// Project configuration read from `destore.toml`, e.g.
+///
+/// ```toml
+/// [cache]
+/// dirs = ["target/destore", "/mnt/team/destore"]
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub cache: CacheConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Cache directories, relative to the config file. Schemas are stored in the first one.
+    pub dirs: Vec<PathBuf>,
+}
+
+impl Config {
+    /// Reads the nearest `destore.toml` in `dir` or its ancestors, if there is one
+    pub fn find(dir: &Path) -> anyhow::Result<Option<Self>> {
+        let Some(path) = dir
+            .ancestors()
+            .map(|dir| dir.join(CONFIG_FILE))
+            .find(|path| path.is_file())
+        else {
+            return Ok(None);
+        };
+        Self::load(&path).map(Some)
+    }
+
+    /// Reads a config file and resolves its relative paths
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config {:?}", path))?;
+        let mut config: Config =
+            toml::from_str(&content).with_context(|| format!("Invalid config {:?}", path))?;
+        let base = path.parent().unwrap_or(Path::new("."));
+        for dir in &mut config.cache.dirs {
+            *dir = base.join(&*dir);
+        }
+        Ok(config)
+    }
+}
@@ -0,0 +1,316 @@
+use anyhow::{anyhow, Context};
+use defmt_decoder::Table;
+use goblin::container::{Container, Ctx, Endian};
+use goblin::elf::section_header::{SHN_ABS, SHT_NOBITS, SHT_STRTAB, SHT_SYMTAB};
+use goblin::elf::sym::Sym;
+use goblin::elf::{Elf, Header, SectionHeader};
+use scroll::ctx::IntoCtx;
+
+/// A defmt log frame stored with `Storer::write_defmt_frame`, decoded with the table of its firmware
+#[derive(Clone, Debug, PartialEq)]
+pub struct DefmtRecord {
+    /// Position of the entry in the partition (schema entries included)
+    pub index: usize,
+    /// Offset of the item in the partition, if the page layout could be followed
+    pub offset: Option<usize>,
+    /// Hash of the schema of the session, identifying the firmware that wrote the frame
+    pub schema_hash: [u8; 8],
+    pub level: Option<String>,
+    pub timestamp: Option<String>,
+    pub message: String,
+}
+
+impl DefmtRecord {
+    pub fn decode(
+        table: &Table,
+        index: usize,
+        offset: Option<usize>,
+        schema_hash: [u8; 8],
+        frame: &[u8],
+    ) -> anyhow::Result<Self> {
+        let (frame, _) = table
+            .decode(frame)
+            .map_err(|e| anyhow!("Failed to decode defmt frame: {:?}", e))?;
+        Ok(Self {
+            index,
+            offset,
+            schema_hash,
+            level: frame.level().map(|level| level.as_str().to_string()),
+            timestamp: frame.display_timestamp().map(|ts| ts.to_string()),
+            message: frame.display_message().to_string(),
+        })
+    }
+}
+
+/// Identifies the defmt table of an ELF: a FNV-1a hash of the interned strings in the `.defmt`
+/// section and their indices. Firmwares logging the same strings share a table.
+pub fn defmt_table_id(elf: &Elf) -> Option<String> {
+    let section = defmt_section(elf)?;
+    let mut symbols: Vec<_> = elf
+        .syms
+        .iter()
+        .filter(|sym| sym.st_shndx == section)
+        .filter_map(|sym| Some((sym.st_value, elf.strtab.get_at(sym.st_name)?)))
+        .collect();
+    if symbols.is_empty() {
+        return None;
+    }
+    symbols.sort();
+
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut update = |bytes: &[u8]| {
+        for byte in bytes {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    };
+    for (index, name) in symbols {
+        update(&index.to_le_bytes());
+        update(name.as_bytes());
+        update(&[0]);
+    }
+    Some(format!("{:016x}", hash))
+}
+
+fn defmt_section(elf: &Elf) -> Option<usize> {
+    elf.section_headers
+        .iter()
+        .position(|sh| elf.shdr_strtab.get_at(sh.sh_name) == Some(".defmt"))
+}
+
+/// Reduces an ELF to what the defmt decoder reads from it: the `.defmt` section with the symbols
+/// of the interned strings, and the symbols of the defmt version and encoding.
+pub fn defmt_table_elf(bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let elf = Elf::parse(bytes).context("Failed to parse ELF file")?;
+    let section = defmt_section(&elf).context("ELF has no .defmt section")?;
+    let header = &elf.section_headers[section];
+    let data = match header.sh_type {
+        SHT_NOBITS => &[][..],
+        _ => header
+            .file_range()
+            .and_then(|range| bytes.get(range))
+            .context("The .defmt section lies outside of the file")?,
+    };
+
+    let mut symbols = Vec::new();
+    for sym in elf.syms.iter() {
+        let Some(name) = elf.strtab.get_at(sym.st_name) else {
+            continue;
+        };
+        let st_shndx = if sym.st_shndx == section {
+            1
+        } else if name.starts_with("_defmt") {
+            SHN_ABS as usize
+        } else {
+            continue;
+        };
+        symbols.push((name, Sym { st_shndx, ..sym }));
+    }
+    let ctx = Ctx::new(
+        if elf.is_64 {
+            Container::Big
+        } else {
+            Container::Little
+        },
+        if elf.little_endian {
+            Endian::Little
+        } else {
+            Endian::Big
+        },
+    );
+    Ok(write_elf(
+        elf.header,
+        ctx,
+        &[(".defmt", header.clone(), data)],
+        &symbols,
+    ))
+}
+
+/// Writes an ELF without program headers. `st_shndx` of the symbols is 1 for the first section.
+pub(crate) fn write_elf(
+    header: Header,
+    ctx: Ctx,
+    sections: &[(&str, SectionHeader, &[u8])],
+    symbols: &[(&str, Sym)],
+) -> Vec<u8> {
+    fn append(out: &mut Vec<u8>, data: &[u8]) -> u64 {
+        out.resize(out.len().next_multiple_of(8), 0);
+        let offset = out.len() as u64;
+        out.extend_from_slice(data);
+        offset
+    }
+    fn names<'a>(names: impl IntoIterator<Item = &'a str>) -> (Vec<u8>, Vec<usize>) {
+        let mut table = vec![0];
+        let offsets = names
+            .into_iter()
+            .map(|name| {
+                let offset = table.len();
+                table.extend_from_slice(name.as_bytes());
+                table.push(0);
+                offset
+            })
+            .collect();
+        (table, offsets)
+    }
+
+    let mut out = vec![0; Header::size(ctx)];
+    let mut headers = vec![SectionHeader::default()];
+    for (_, section, data) in sections {
+        let sh_offset = append(&mut out, data);
+        headers.push(SectionHeader {
+            sh_offset,
+            ..section.clone()
+        });
+    }
+
+    let (strtab, name_offsets) = names(symbols.iter().map(|(name, _)| *name));
+    let sym_size = Sym::size(ctx.container);
+    let mut symtab = vec![0; (symbols.len() + 1) * sym_size];
+    for (i, ((_, sym), st_name)) in symbols.iter().zip(name_offsets).enumerate() {
+        Sym { st_name, ..*sym }.into_ctx(&mut symtab[(i + 1) * sym_size..], ctx);
+    }
+    let symtab_index = headers.len();
+    headers.push(SectionHeader {
+        sh_type: SHT_SYMTAB,
+        sh_offset: append(&mut out, &symtab),
+        sh_size: symtab.len() as u64,
+        sh_link: symtab_index as u32 + 1,
+        sh_info: 1,
+        sh_addralign: 8,
+        sh_entsize: sym_size as u64,
+        ..SectionHeader::default()
+    });
+    headers.push(SectionHeader {
+        sh_type: SHT_STRTAB,
+        sh_offset: append(&mut out, &strtab),
+        sh_size: strtab.len() as u64,
+        sh_addralign: 1,
+        ..SectionHeader::default()
+    });
+    let section_names = sections.iter().map(|(name, _, _)| *name);
+    let (shstrtab, sh_names) = names(section_names.chain([".symtab", ".strtab", ".shstrtab"]));
+    headers.push(SectionHeader {
+        sh_type: SHT_STRTAB,
+        sh_offset: append(&mut out, &shstrtab),
+        sh_size: shstrtab.len() as u64,
+        sh_addralign: 1,
+        ..SectionHeader::default()
+    });
+    for (header, sh_name) in headers.iter_mut().skip(1).zip(sh_names) {
+        header.sh_name = sh_name;
+    }
+
+    let shdr_size = SectionHeader::size(ctx);
+    let e_shoff = append(&mut out, &[]);
+    out.resize(out.len() + headers.len() * shdr_size, 0);
+    for (i, section) in headers.iter().enumerate() {
+        section
+            .clone()
+            .into_ctx(&mut out[e_shoff as usize + i * shdr_size..], ctx);
+    }
+    Header {
+        e_phoff: 0,
+        e_phnum: 0,
+        e_shoff,
+        e_shentsize: shdr_size as u16,
+        e_shnum: headers.len() as u16,
+        e_shstrndx: headers.len() as u16 - 1,
+        ..header
+    }
+    .into_ctx(&mut out, ctx);
+    out
+}
+
+/// An ELF with a `.defmt` section holding the given strings, besides a symbol of the code
+#[cfg(test)]
+pub(crate) fn defmt_test_elf(strings: &[&str]) -> Vec<u8> {
+    use goblin::elf::section_header::SHT_PROGBITS;
+    use goblin::elf::sym::{STB_GLOBAL, STT_FUNC, STT_OBJECT};
+
+    let ctx = Ctx::new(Container::Little, Endian::Little);
+    let code = [0x13; 16];
+    let defmt = vec![0; strings.len()];
+    let sections = [
+        (
+            ".text",
+            SectionHeader {
+                sh_type: SHT_PROGBITS,
+                sh_addr: 0x4200_0000,
+                sh_size: code.len() as u64,
+                ..SectionHeader::default()
+            },
+            &code[..],
+        ),
+        (
+            ".defmt",
+            SectionHeader {
+                sh_type: SHT_PROGBITS,
+                sh_size: defmt.len() as u64,
+                ..SectionHeader::default()
+            },
+            &defmt[..],
+        ),
+    ];
+    let sym = |st_shndx, st_value, typ| Sym {
+        st_info: (STB_GLOBAL << 4) | typ,
+        st_shndx,
+        st_value,
+        st_size: 1,
+        ..Sym::default()
+    };
+    let mut symbols = vec![
+        ("main", sym(1, 0x4200_0000, STT_FUNC)),
+        ("_defmt_version_ = 4", sym(SHN_ABS as usize, 1, STT_OBJECT)),
+    ];
+    for (i, string) in strings.iter().enumerate() {
+        symbols.push((string, sym(2, i as u64, STT_OBJECT)));
+    }
+    write_elf(Header::new(ctx), ctx, &sections, &symbols)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_defmt_table_elf() {
+        let strings = [
+            r#"{"package":"app","tag":"defmt_info","data":"boot","disambiguator":"1","crate_name":"app"}"#,
+            r#"{"package":"app","tag":"defmt_warn","data":"low: {=u8}","disambiguator":"2","crate_name":"app"}"#,
+        ];
+        let bytes = defmt_test_elf(&strings);
+        let table_elf = defmt_table_elf(&bytes).unwrap();
+
+        let elf = Elf::parse(&table_elf).unwrap();
+        assert_eq!(elf.section_headers.len(), 5);
+        assert_eq!(defmt_section(&elf), Some(1));
+        let symbols: Vec<_> = elf
+            .syms
+            .iter()
+            .skip(1)
+            .map(|sym| {
+                (
+                    elf.strtab.get_at(sym.st_name).unwrap(),
+                    sym.st_shndx,
+                    sym.st_value,
+                )
+            })
+            .collect();
+        assert_eq!(
+            symbols,
+            [
+                ("_defmt_version_ = 4", SHN_ABS as usize, 1),
+                (strings[0], 1, 0),
+                (strings[1], 1, 1),
+            ]
+        );
+        assert_eq!(
+            defmt_table_id(&elf),
+            defmt_table_id(&Elf::parse(&bytes).unwrap())
+        );
+        // Reducing it again changes nothing
+        assert_eq!(defmt_table_elf(&table_elf).unwrap(), table_elf);
+
+        assert!(defmt_table_elf(b"\x7fELF").is_err());
+    }
+}
@@ -0,0 +1,135 @@
+use crate::{
+    builtin_schema, format_hash, ElfFile, LogDescriptor, Metadata, PartitionEntry, SchemaSource,
+};
+use anyhow::bail;
+use defmt_decoder::Table;
+use log::warn;
+use postcard_schema::key::hash::fnv1a64_owned::hash_ty_path_owned;
+use postcard_schema::schema::owned::OwnedDataModelType;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// The schemas exported by an ELF, used instead of the cache to decode a partition written by it
+pub struct ElfSchemas {
+    path: PathBuf,
+    schemas: BTreeMap<[u8; 8], (OwnedDataModelType, Metadata)>,
+    logs: Vec<LogDescriptor>,
+    defmt_table: Option<Rc<Table>>,
+}
+
+impl ElfSchemas {
+    /// Restores the schemas exported with `export_schema!` and `export_log!` and their metadata
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let file = ElfFile::read(path)?;
+        let elf = file.restorer()?;
+        let metadata = elf.load_metadata()?;
+        let logs = elf.load_logs()?;
+        let schemas = elf
+            .load_all_schemas()
+            .into_iter()
+            .filter_map(|(symbol, schema)| {
+                // Only entries of this schema fail to decode
+                schema
+                    .inspect_err(|e| warn!("Skipping schema {}: {:#}", symbol, e))
+                    .ok()
+            })
+            .chain(logs.iter().map(|log| log.schema.clone()))
+            .map(|schema| {
+                let schema_metadata = Metadata::for_schema(&metadata, &schema);
+                (hash_ty_path_owned("", &schema), (schema, schema_metadata))
+            })
+            .collect::<BTreeMap<_, _>>();
+        if schemas.is_empty() {
+            bail!("{:?} exports no schema", path);
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            schemas,
+            logs,
+            defmt_table: elf.defmt_table()?.map(Rc::new),
+        })
+    }
+
+    /// Looks up a log declared with `export_log!` by name (case insensitive)
+    pub fn lookup_log(&self, name: &str) -> Option<&LogDescriptor> {
+        self.logs.iter().find(|log| log.has_name(name))
+    }
+
+    /// Checks that the partition was written by this firmware, i.e. that every schema entry refers
+    /// to a schema of the ELF or a builtin one
+    pub fn verify(&self, entries: &[PartitionEntry]) -> anyhow::Result<()> {
+        for entry in entries {
+            if let PartitionEntry::Schema(hash) = entry {
+                if !self.schemas.contains_key(hash) && builtin_schema(hash).is_none() {
+                    let exported: Vec<_> = self.schemas.keys().map(format_hash).collect();
+                    bail!(
+                        "The partition contains records of schema {}, which {:?} does not export \
+                         (it exports {}). Was the partition written by a different firmware?",
+                        format_hash(hash),
+                        self.path,
+                        exported.join(", ")
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SchemaSource for ElfSchemas {
+    fn lookup(&self, schema_hash: &[u8; 8]) -> anyhow::Result<Option<OwnedDataModelType>> {
+        Ok(self
+            .schemas
+            .get(schema_hash)
+            .map(|(schema, _)| schema.clone()))
+    }
+
+    fn lookup_metadata(&self, schema_hash: &[u8; 8]) -> anyhow::Result<Metadata> {
+        Ok(self
+            .schemas
+            .get(schema_hash)
+            .map(|(_, metadata)| metadata.clone())
+            .unwrap_or_default())
+    }
+
+    /// The table of the ELF, used for all schemas as the partition was verified to be written by it
+    fn defmt_table(&self, _schema_hash: &[u8; 8]) -> anyhow::Result<Option<Rc<Table>>> {
+        Ok(self.defmt_table.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let schema = OwnedDataModelType::U16;
+        let hash = hash_ty_path_owned("", &schema);
+        let elf = ElfSchemas {
+            path: "firmware.elf".into(),
+            schemas: [(hash, (schema.clone(), Metadata::default()))].into(),
+            logs: Vec::new(),
+            defmt_table: None,
+        };
+        assert!(elf
+            .verify(&[
+                PartitionEntry::Schema(hash),
+                PartitionEntry::Data(vec![1, 0])
+            ])
+            .is_ok());
+        assert_eq!(elf.lookup(&hash).unwrap(), Some(schema));
+
+        let other = [0xff; 8];
+        let err = elf
+            .verify(&[PartitionEntry::Schema(hash), PartitionEntry::Schema(other)])
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("schema ffffffffffffffff"),
+            "{}",
+            err
+        );
+        assert_eq!(elf.lookup(&other).unwrap(), None);
+    }
+}
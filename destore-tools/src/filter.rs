@@ -0,0 +1,509 @@
+use anyhow::{bail, Context};
+use postcard_dyn::Value;
+use postcard_schema::schema::owned::{OwnedData, OwnedDataModelType};
+use std::cmp::Ordering;
+use std::ops::Range;
+use std::str::FromStr;
+
+/// A predicate on a decoded record, e.g. `Record::Panic` or `Sub.age > 18`.
+///
+/// The left-hand side is a dot separated path into the record. Path segments name enum variants,
+/// struct fields, tuple/sequence indices or map keys; newtypes are transparent. A variant may be
+/// qualified with the enum name (`Record::Sub`). Without an operator, the filter matches if the
+/// path can be resolved and its value is neither `None` nor `false`.
+#[derive(Clone, Debug)]
+pub struct RecordFilter {
+    path: Vec<Segment>,
+    comparison: Option<(Op, Literal)>,
+}
+
+#[derive(Clone, Debug)]
+struct Segment {
+    /// Enum name the segment must belong to (`Record` in `Record::Panic`)
+    qualifier: Option<String>,
+    name: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Literal {
+    Bool(bool),
+    Integer(Integer),
+    Float(f64),
+    String(String),
+}
+
+/// A value of any integer type up to 128 bits, ordered by value
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Integer {
+    Negative(i128),
+    Positive(u128),
+}
+
+impl From<i128> for Integer {
+    fn from(n: i128) -> Self {
+        match u128::try_from(n) {
+            Ok(n) => Integer::Positive(n),
+            Err(_) => Integer::Negative(n),
+        }
+    }
+}
+
+impl FromStr for Integer {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse() {
+            Ok(n) => Ok(Integer::Positive(n)),
+            Err(_) => s.parse::<i128>().map(Integer::from),
+        }
+    }
+}
+
+impl Integer {
+    fn as_f64(self) -> f64 {
+        match self {
+            Integer::Negative(n) => n as f64,
+            Integer::Positive(n) => n as f64,
+        }
+    }
+}
+
+/// Operators in the order they are searched for (two character operators first)
+const OPS: &[(&str, Op)] = &[
+    ("==", Op::Eq),
+    ("!=", Op::Ne),
+    ("<=", Op::Le),
+    (">=", Op::Ge),
+    ("<", Op::Lt),
+    (">", Op::Gt),
+];
+
+impl FromStr for RecordFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let op = OPS
+            .iter()
+            .filter_map(|(token, op)| s.find(token).map(|pos| (pos, *token, *op)))
+            .min_by_key(|(pos, token, _)| (*pos, usize::MAX - token.len()));
+
+        let (path, comparison) = match op {
+            Some((pos, token, op)) => {
+                let literal = parse_literal(s[pos + token.len()..].trim())?;
+                (&s[..pos], Some((op, literal)))
+            }
+            None => (s, None),
+        };
+
+        let path = path
+            .trim()
+            .split('.')
+            .map(|segment| {
+                let segment = segment.trim();
+                if segment.is_empty() {
+                    bail!("Empty path segment in filter '{}'", s);
+                }
+                Ok(match segment.rsplit_once("::") {
+                    Some((qualifier, name)) => Segment {
+                        qualifier: Some(qualifier.to_string()),
+                        name: name.to_string(),
+                    },
+                    None => Segment {
+                        qualifier: None,
+                        name: segment.to_string(),
+                    },
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Self { path, comparison })
+    }
+}
+
+fn parse_literal(s: &str) -> anyhow::Result<Literal> {
+    if s.is_empty() {
+        bail!("Missing value after operator");
+    }
+    for quote in ['"', '\''] {
+        if let Some(inner) = s.strip_prefix(quote) {
+            let inner = inner
+                .strip_suffix(quote)
+                .with_context(|| format!("Unterminated string {}", s))?;
+            return Ok(Literal::String(inner.to_string()));
+        }
+    }
+    Ok(match s {
+        "true" => Literal::Bool(true),
+        "false" => Literal::Bool(false),
+        s => match (s.parse(), s.parse()) {
+            // Integers are compared exactly, beyond the 53 bits an f64 holds
+            (Ok(n), _) => Literal::Integer(n),
+            (_, Ok(n)) => Literal::Float(n),
+            // Bare words are compared against strings and variant names
+            _ => Literal::String(s.to_string()),
+        },
+    })
+}
+
+impl RecordFilter {
+    /// Checks whether a record decoded with the given schema matches the filter
+    pub fn matches(&self, schema: &OwnedDataModelType, value: &Value) -> bool {
+        let Some(value) = resolve(schema, value, &self.path) else {
+            return false;
+        };
+        match &self.comparison {
+            None => !matches!(value, Value::Null | Value::Bool(false)),
+            Some((op, literal)) => compare(value, literal).is_some_and(|ordering| match op {
+                Op::Eq => ordering == Ordering::Equal,
+                Op::Ne => ordering != Ordering::Equal,
+                Op::Lt => ordering == Ordering::Less,
+                Op::Le => ordering != Ordering::Greater,
+                Op::Gt => ordering == Ordering::Greater,
+                Op::Ge => ordering != Ordering::Less,
+            }),
+        }
+    }
+}
+
+fn resolve<'a>(
+    ty: &'a OwnedDataModelType,
+    value: &'a Value,
+    path: &[Segment],
+) -> Option<&'a Value> {
+    let Some((segment, rest)) = path.split_first() else {
+        return Some(value);
+    };
+    match ty {
+        OwnedDataModelType::Option(inner) if !value.is_null() => resolve(inner, value, path),
+        OwnedDataModelType::Struct { name, data } => {
+            resolve_data(data, value, path).or_else(|| {
+                // The path may start with the name of the struct, like it does with a variant
+                if segment.qualifier.is_some() || segment.name != **name {
+                    None
+                } else if rest.is_empty() {
+                    Some(value)
+                } else {
+                    resolve_data(data, value, rest)
+                }
+            })
+        }
+        OwnedDataModelType::Enum { name, variants } => {
+            if segment.qualifier.as_ref().is_some_and(|q| **q != **name) {
+                return None;
+            }
+            let (variant_name, inner) = variant_of(value)?;
+            if variant_name != segment.name {
+                return None;
+            }
+            let variant = variants.iter().find(|v| *v.name == *variant_name)?;
+            match (&variant.data, rest.is_empty()) {
+                // Unit variants carry no value, resolve to the variant itself to make them truthy
+                (OwnedData::Unit, true) => Some(value),
+                (_, true) => Some(inner),
+                (data, false) => resolve_data(data, inner, rest),
+            }
+        }
+        OwnedDataModelType::Seq(inner) => {
+            let index: usize = segment.name.parse().ok()?;
+            resolve(inner, value.as_array()?.get(index)?, rest)
+        }
+        OwnedDataModelType::Tuple(types) => {
+            let index: usize = segment.name.parse().ok()?;
+            resolve(types.get(index)?, value.as_array()?.get(index)?, rest)
+        }
+        OwnedDataModelType::Map { val, .. } => {
+            resolve(val, value.as_object()?.get(&segment.name)?, rest)
+        }
+        _ => None,
+    }
+}
+
+fn resolve_data<'a>(data: &'a OwnedData, value: &'a Value, path: &[Segment]) -> Option<&'a Value> {
+    let (segment, rest) = path.split_first()?;
+    match data {
+        OwnedData::Unit => None,
+        OwnedData::Newtype(inner) => resolve(inner, value, path),
+        OwnedData::Tuple(types) => {
+            let index: usize = segment.name.parse().ok()?;
+            resolve(types.get(index)?, value.as_array()?.get(index)?, rest)
+        }
+        OwnedData::Struct(fields) => {
+            let field = fields.iter().find(|f| *f.name == *segment.name)?;
+            resolve(&field.ty, value.as_object()?.get(&segment.name)?, rest)
+        }
+    }
+}
+
+/// Splits the json representation of an enum value into variant name and content
+fn variant_of(value: &Value) -> Option<(&str, &Value)> {
+    match value {
+        Value::String(name) => Some((name, &Value::Null)),
+        Value::Object(map) if map.len() == 1 => map
+            .iter()
+            .next()
+            .map(|(name, inner)| (name.as_str(), inner)),
+        _ => None,
+    }
+}
+
+fn compare(value: &Value, literal: &Literal) -> Option<Ordering> {
+    match (value, literal) {
+        (Value::Number(n), Literal::Integer(l)) => match (n.as_u64(), n.as_i64()) {
+            (Some(n), _) => Some(Integer::Positive(n.into()).cmp(l)),
+            (_, Some(n)) => Some(Integer::from(i128::from(n)).cmp(l)),
+            _ => n.as_f64()?.partial_cmp(&l.as_f64()),
+        },
+        // 128 bit integers beyond the range of json numbers are kept as decimal strings
+        (Value::String(s), Literal::Integer(l)) => Some(s.parse::<Integer>().ok()?.cmp(l)),
+        (Value::Number(n), Literal::Float(l)) => n.as_f64()?.partial_cmp(l),
+        (Value::String(s), Literal::String(l)) => Some(s.as_str().cmp(l)),
+        (Value::Bool(b), Literal::Bool(l)) => Some(b.cmp(l)),
+        (Value::Null, Literal::String(l)) if l == "None" => Some(Ordering::Equal),
+        // Compare enum values by their variant name
+        (value, Literal::String(l)) => Some(variant_of(value)?.0.cmp(l)),
+        _ => None,
+    }
+}
+
+/// Field holding the time of a record, shown by the pretty printer and used by [`TimeRange`]
+pub const TIMESTAMP_FIELD: &str = "timestamp";
+
+type Owner<'a> = (&'a str, Option<&'a str>);
+
+/// The `timestamp` field of a record, or of the struct of its variant, and the type (and variant)
+/// it belongs to
+pub fn find_timestamp<'a>(
+    ty: &'a OwnedDataModelType,
+    value: &'a Value,
+) -> Option<(Owner<'a>, &'a Value)> {
+    match (ty, value) {
+        (OwnedDataModelType::Struct { name, data }, value) => {
+            find_data_timestamp((name, None), data, value)
+        }
+        (OwnedDataModelType::Enum { name, variants }, Value::Object(map)) if map.len() == 1 => {
+            let (variant, inner) = map.iter().next()?;
+            let data = &variants.iter().find(|v| *v.name == **variant)?.data;
+            find_data_timestamp((name, Some(variant)), data, inner)
+        }
+        _ => None,
+    }
+}
+
+fn find_data_timestamp<'a>(
+    owner: Owner<'a>,
+    data: &'a OwnedData,
+    value: &'a Value,
+) -> Option<(Owner<'a>, &'a Value)> {
+    match (data, value) {
+        (OwnedData::Struct(fields), Value::Object(map))
+            if fields.iter().any(|f| &*f.name == TIMESTAMP_FIELD) =>
+        {
+            Some((owner, map.get(TIMESTAMP_FIELD)?))
+        }
+        (OwnedData::Newtype(inner), value) => find_timestamp(inner, value),
+        _ => None,
+    }
+}
+
+/// A range of entry indices, e.g. `100..200`, `100..` or `..200`
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndexRange(pub Range<usize>);
+
+impl FromStr for IndexRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_range(s, 0, usize::MAX).map(Self)
+    }
+}
+
+/// A range of timestamps, e.g. `1000..2000`, in the unit of the `timestamp` field of the records
+/// (or the timestamps of defmt frames). Entries without a timestamp are outside of every range.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimeRange(pub Range<f64>);
+
+impl FromStr for TimeRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_range(s, f64::NEG_INFINITY, f64::INFINITY).map(Self)
+    }
+}
+
+impl TimeRange {
+    /// Checks whether the `timestamp` field of a record lies in the range
+    pub fn matches(&self, schema: &OwnedDataModelType, value: &Value) -> bool {
+        find_timestamp(schema, value)
+            .and_then(|(_, timestamp)| timestamp.as_f64())
+            .is_some_and(|timestamp| self.0.contains(&timestamp))
+    }
+
+    /// Checks whether the timestamp of a defmt frame lies in the range
+    pub fn matches_defmt(&self, timestamp: Option<&str>) -> bool {
+        timestamp
+            .and_then(|timestamp| timestamp.trim().parse().ok())
+            .is_some_and(|timestamp| self.0.contains(&timestamp))
+    }
+}
+
+/// Parses `start..end`, either of which may be omitted
+fn parse_range<T: FromStr>(s: &str, min: T, max: T) -> anyhow::Result<Range<T>>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    let (start, end) = s
+        .split_once("..")
+        .with_context(|| format!("Expected a range like 10..20, got '{}'", s))?;
+    let start = match start.trim() {
+        "" => min,
+        start => start.parse()?,
+    };
+    let end = match end.trim() {
+        "" => max,
+        end => end.parse()?,
+    };
+    Ok(start..end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use postcard_schema::schema::owned::{OwnedNamedField, OwnedVariant};
+    use serde_json::json;
+
+    fn schema() -> OwnedDataModelType {
+        let sub = OwnedDataModelType::Struct {
+            name: "Sub".into(),
+            data: OwnedData::Struct(Box::new([
+                OwnedNamedField {
+                    name: "first_name".into(),
+                    ty: OwnedDataModelType::String,
+                },
+                OwnedNamedField {
+                    name: "age".into(),
+                    ty: OwnedDataModelType::U8,
+                },
+            ])),
+        };
+        OwnedDataModelType::Enum {
+            name: "Record".into(),
+            variants: Box::new([
+                OwnedVariant {
+                    name: "Boot".into(),
+                    data: OwnedData::Unit,
+                },
+                OwnedVariant {
+                    name: "Sub".into(),
+                    data: OwnedData::Newtype(Box::new(sub)),
+                },
+                OwnedVariant {
+                    name: "Panic".into(),
+                    data: OwnedData::Newtype(Box::new(OwnedDataModelType::String)),
+                },
+            ]),
+        }
+    }
+
+    fn matches(filter: &str, value: Value) -> bool {
+        filter
+            .parse::<RecordFilter>()
+            .unwrap()
+            .matches(&schema(), &value)
+    }
+
+    #[test]
+    fn test_filter() {
+        let alice = json!({"Sub": {"first_name": "Alice", "age": 20}});
+        let panic = json!({"Panic": "help"});
+
+        assert!(matches("Record::Panic", panic.clone()));
+        assert!(matches("Panic", panic.clone()));
+        assert!(!matches("Record::Panic", alice.clone()));
+        assert!(!matches("Other::Panic", panic.clone()));
+        assert!(matches("Boot", json!("Boot")));
+        assert!(matches("Panic == \"help\"", panic.clone()));
+        assert!(matches("Panic != help", json!({"Panic": "other"})));
+
+        assert!(matches("Sub.age > 18", alice.clone()));
+        assert!(matches("Sub.age>=20", alice.clone()));
+        assert!(!matches("Sub.age < 18", alice.clone()));
+        assert!(matches("Sub.first_name == 'Alice'", alice.clone()));
+        assert!(!matches("Sub.age > 18", panic));
+        assert!(!matches("Sub.unknown == 1", alice.clone()));
+        assert!(matches("Sub.age > 19.5", alice.clone()));
+        assert!(!matches("Sub.age < -0.5", alice.clone()));
+        assert!(matches(
+            "Sub.age > -170141183460469231731687303715884105728",
+            alice
+        ));
+
+        let big = json!({"Sub": {"first_name": "Bob", "age": 9_007_199_254_740_993u64}});
+        assert!(matches("Sub.age == 9007199254740993", big.clone()));
+        assert!(!matches("Sub.age == 9007199254740992", big.clone()));
+        assert!(matches("Sub.age > 9007199254740992", big));
+        let huge = json!({"Sub": {"first_name": "Bob", "age": u128::MAX.to_string()}});
+        assert!(matches(
+            "Sub.age == 340282366920938463463374607431768211455",
+            huge.clone()
+        ));
+        assert!(matches(
+            "Sub.age > 340282366920938463463374607431768211454",
+            huge
+        ));
+    }
+
+    #[test]
+    fn test_index_range() {
+        assert_eq!("10..20".parse::<IndexRange>().unwrap().0, 10..20);
+        assert_eq!("10..".parse::<IndexRange>().unwrap().0, 10..usize::MAX);
+        assert_eq!("..20".parse::<IndexRange>().unwrap().0, 0..20);
+        assert!("10".parse::<IndexRange>().is_err());
+    }
+
+    #[test]
+    fn test_struct_root() {
+        let schema = OwnedDataModelType::Struct {
+            name: "Reading".into(),
+            data: OwnedData::Struct(Box::new([
+                OwnedNamedField {
+                    name: "timestamp".into(),
+                    ty: OwnedDataModelType::U64,
+                },
+                OwnedNamedField {
+                    name: "value".into(),
+                    ty: OwnedDataModelType::I16,
+                },
+            ])),
+        };
+        let reading = json!({"timestamp": 1500, "value": -4});
+        let matches = |filter: &str| {
+            filter
+                .parse::<RecordFilter>()
+                .unwrap()
+                .matches(&schema, &reading)
+        };
+        assert!(matches("Reading"));
+        assert!(matches("Reading.value < 0"));
+        assert!(matches("value == -4"));
+        assert!(!matches("Other.value == -4"));
+
+        let time = |range: &str| range.parse::<TimeRange>().unwrap();
+        assert!(time("1000..2000").matches(&schema, &reading));
+        assert!(time("1500..").matches(&schema, &reading));
+        assert!(!time("..1500").matches(&schema, &reading));
+        assert!(!time("0..10").matches(&schema, &json!({"value": 1})));
+        assert!(time("1.5..2").matches_defmt(Some("1.750000")));
+        assert!(!time("1.5..2").matches_defmt(None));
+        assert!("1.5".parse::<TimeRange>().is_err());
+    }
+}
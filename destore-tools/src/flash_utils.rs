@@ -62,3 +62,21 @@ impl<'a> NorFlash for FlashVec<'a> {
         Ok(())*/
     }
 }
+
+/// In-memory flash of 4 pages with the page and word size of [`FlashVec`], for tests in which
+/// sequential-storage itself writes the partition
+#[cfg(test)]
+pub(crate) type MockFlash = sequential_storage::mock_flash::MockFlashBase<4, 4, 1024>;
+
+#[cfg(test)]
+pub(crate) const MOCK_FLASH_RANGE: std::ops::Range<u32> = 0..4 * 4096;
+
+#[cfg(test)]
+pub(crate) fn mock_flash() -> MockFlash {
+    // Popping an item clears its crc, writing the word a second time
+    MockFlash::new(
+        sequential_storage::mock_flash::WriteCountCheck::Twice,
+        None,
+        true,
+    )
+}
@@ -0,0 +1,468 @@
+//! Integrity check of partition images.
+//!
+//! Mirrors the on-flash format of sequential-storage 4.x: every page starts and ends with a marker word,
+//! in between are items consisting of an 8 byte header (data crc, length, length crc) followed by the
+//! word aligned data. A data crc of 0 marks an item that was popped from the queue.
+
+use crate::pages::{oldest_page, page_states, PageState, PAGE_SIZE, WORD_SIZE};
+use std::fmt;
+use std::ops::Range;
+
+const ITEM_HEADER_SIZE: usize = 8;
+const DATA_CRC_FIELD: Range<usize> = 0..4;
+const LENGTH_FIELD: Range<usize> = 4..6;
+const LENGTH_CRC_FIELD: Range<usize> = 6..8;
+
+/// A problem found in the image. Offsets are relative to the start of the partition.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Problem {
+    /// End marker written without start marker
+    CorruptedPageState { page: usize },
+    /// The page is marked as erased but contains data
+    DataInOpenPage { page: usize },
+    /// The length crc of an item header does not match. The rest of the page cannot be parsed.
+    CorruptHeader { offset: usize },
+    /// The length of an item exceeds its page. The rest of the page cannot be parsed.
+    ItemOverflow { offset: usize, length: usize },
+    /// The data crc does not match and the data is incomplete, e.g. due to a reset during the write
+    TornWrite { offset: usize },
+    /// The data crc does not match
+    CrcMismatch { offset: usize },
+    /// Only one page should be written to at a time
+    MultiplePartialOpenPages { pages: Vec<usize> },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::CorruptedPageState { page } => {
+                write!(f, "page {}: end marker written without start marker", page)
+            }
+            Problem::DataInOpenPage { page } => {
+                write!(f, "page {}: marked as erased but contains data", page)
+            }
+            Problem::CorruptHeader { offset } => {
+                write!(f, "{:#08x}: item header crc mismatch", offset)
+            }
+            Problem::ItemOverflow { offset, length } => write!(
+                f,
+                "{:#08x}: item of {} bytes does not fit into its page",
+                offset, length
+            ),
+            Problem::TornWrite { offset } => {
+                write!(f, "{:#08x}: torn write, item data incomplete", offset)
+            }
+            Problem::CrcMismatch { offset } => write!(f, "{:#08x}: item data crc mismatch", offset),
+            Problem::MultiplePartialOpenPages { pages } => {
+                write!(f, "multiple pages are partially written: {:?}", pages)
+            }
+        }
+    }
+}
+
+/// Result of checking a single page
+#[derive(Debug)]
+pub struct PageReport {
+    pub index: usize,
+    pub state: PageState,
+    /// Items with a valid crc
+    pub valid_items: usize,
+    /// Items that were popped from the queue
+    pub erased_items: usize,
+    pub problems: Vec<Problem>,
+}
+
+/// Result of checking a partition image
+#[derive(Debug)]
+pub struct FsckReport {
+    pub pages: Vec<PageReport>,
+    /// Problems concerning several pages
+    pub problems: Vec<Problem>,
+}
+
+impl FsckReport {
+    pub fn problems(&self) -> impl Iterator<Item = &Problem> {
+        self.pages
+            .iter()
+            .flat_map(|p| p.problems.iter())
+            .chain(self.problems.iter())
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.problems().next().is_none()
+    }
+}
+
+impl fmt::Display for FsckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for page in &self.pages {
+            writeln!(
+                f,
+                "page {:>4} @ {:#08x}  {:<12}  {:>5} items  {:>5} erased  {}",
+                page.index,
+                page.index * PAGE_SIZE,
+                page.state.to_string(),
+                page.valid_items,
+                page.erased_items,
+                if page.problems.is_empty() {
+                    "ok"
+                } else {
+                    "ERROR"
+                }
+            )?;
+            for problem in &page.problems {
+                writeln!(f, "    {}", problem)?;
+            }
+        }
+        for problem in &self.problems {
+            writeln!(f, "{}", problem)?;
+        }
+        write!(f, "{} problems found", self.problems().count())
+    }
+}
+
+/// Checks the page states, item headers and crcs of a partition image
+pub fn fsck(partition: &[u8]) -> FsckReport {
+    let states = page_states(partition);
+    let pages: Vec<_> = states
+        .iter()
+        .enumerate()
+        .map(|(index, state)| check_page(partition, index, *state))
+        .collect();
+
+    let mut problems = Vec::new();
+    let partial_open: Vec<_> = states
+        .iter()
+        .enumerate()
+        .filter(|(_, s)| **s == PageState::PartialOpen)
+        .map(|(i, _)| i)
+        .collect();
+    if partial_open.len() > 1 {
+        problems.push(Problem::MultiplePartialOpenPages {
+            pages: partial_open,
+        });
+    }
+
+    FsckReport { pages, problems }
+}
+
+fn check_page(partition: &[u8], index: usize, state: PageState) -> PageReport {
+    let page_start = index * PAGE_SIZE;
+    let mut report = PageReport {
+        index,
+        state,
+        valid_items: 0,
+        erased_items: 0,
+        problems: Vec::new(),
+    };
+
+    match state {
+        PageState::Open => {
+            if partition[page_start..page_start + PAGE_SIZE]
+                .iter()
+                .any(|b| *b != 0xFF)
+            {
+                report
+                    .problems
+                    .push(Problem::DataInOpenPage { page: index });
+            }
+            return report;
+        }
+        PageState::Corrupted => report
+            .problems
+            .push(Problem::CorruptedPageState { page: index }),
+        PageState::PartialOpen | PageState::Closed => {}
+    }
+
+    for item in page_items(partition, index) {
+        match item {
+            Item::Valid { .. } => report.valid_items += 1,
+            Item::Erased => report.erased_items += 1,
+            Item::Broken(problem) => report.problems.push(problem),
+        }
+    }
+    report
+}
+
+/// An item of a page
+enum Item {
+    /// Offset of the item header
+    Valid {
+        offset: usize,
+    },
+    /// Popped from the queue
+    Erased,
+    Broken(Problem),
+}
+
+/// Parses the items of a page. A broken header ends the page, as the next item cannot be found.
+fn page_items(partition: &[u8], index: usize) -> Vec<Item> {
+    let page_start = index * PAGE_SIZE;
+    let header_size = round_up(ITEM_HEADER_SIZE);
+    let data_end = page_start + PAGE_SIZE - WORD_SIZE;
+    let mut items = Vec::new();
+    let mut offset = page_start + WORD_SIZE;
+    while offset + header_size <= data_end {
+        let header = &partition[offset..offset + header_size];
+        if header.iter().all(|b| *b == 0xFF) {
+            break;
+        }
+
+        let length = u16::from_le_bytes(header[LENGTH_FIELD].try_into().unwrap()) as usize;
+        let length_crc = u16::from_le_bytes(header[LENGTH_CRC_FIELD].try_into().unwrap());
+        if crc16(&header[LENGTH_FIELD]) != length_crc {
+            items.push(Item::Broken(Problem::CorruptHeader { offset }));
+            break;
+        }
+
+        let data_start = offset + header_size;
+        let next = data_start + round_up(length);
+        if next > data_end {
+            items.push(Item::Broken(Problem::ItemOverflow { offset, length }));
+            break;
+        }
+
+        let data_crc = u32::from_le_bytes(header[DATA_CRC_FIELD].try_into().unwrap());
+        items.push(if data_crc == 0 {
+            Item::Erased
+        } else if adapted_crc32(&partition[data_start..data_start + length]) == data_crc {
+            Item::Valid { offset }
+        } else if partition[data_start..next]
+            .chunks(WORD_SIZE)
+            .any(|word| word.iter().all(|b| *b == 0xFF))
+        {
+            Item::Broken(Problem::TornWrite { offset })
+        } else {
+            Item::Broken(Problem::CrcMismatch { offset })
+        });
+        offset = next;
+    }
+    items
+}
+
+/// Offsets of the headers of the valid items, in the order the queue yields them: starting with the
+/// oldest page and wrapping around the end of the partition
+pub fn item_offsets(partition: &[u8]) -> Vec<usize> {
+    let states = page_states(partition);
+    let Some(oldest) = oldest_page(&states) else {
+        return Vec::new();
+    };
+    (0..states.len())
+        .map(|i| (oldest + i) % states.len())
+        .filter(|&page| matches!(states[page], PageState::PartialOpen | PageState::Closed))
+        .flat_map(|page| page_items(partition, page))
+        .filter_map(|item| match item {
+            Item::Valid { offset } => Some(offset),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Returns a copy of the image in which the problems of the report are fixed,
+/// so that the remaining items can be decoded.
+///
+/// Only operations possible on NOR flash are used (clearing bits, erasing):
+/// broken items are marked as popped, unparseable page remainders are erased.
+pub fn repair(partition: &[u8], report: &FsckReport) -> Vec<u8> {
+    let mut repaired = partition.to_vec();
+    for problem in report.problems() {
+        match problem {
+            Problem::CorruptedPageState { page } => {
+                let start = page * PAGE_SIZE;
+                repaired[start..start + WORD_SIZE].fill(0);
+            }
+            Problem::DataInOpenPage { page } => {
+                let start = page * PAGE_SIZE;
+                repaired[start..start + PAGE_SIZE].fill(0xFF);
+            }
+            Problem::CorruptHeader { offset } | Problem::ItemOverflow { offset, .. } => {
+                let data_end = (offset / PAGE_SIZE + 1) * PAGE_SIZE - WORD_SIZE;
+                repaired[*offset..data_end].fill(0xFF);
+            }
+            Problem::TornWrite { offset } | Problem::CrcMismatch { offset } => {
+                repaired[offset + DATA_CRC_FIELD.start..offset + DATA_CRC_FIELD.end].fill(0);
+            }
+            Problem::MultiplePartialOpenPages { .. } => {}
+        }
+    }
+    repaired
+}
+
+fn round_up(len: usize) -> usize {
+    len.div_ceil(WORD_SIZE) * WORD_SIZE
+}
+
+/// CRC-16/MODBUS, used by sequential-storage for the length field
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for byte in data {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xA001
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+/// CRC-32/ISO-HDLC, used by sequential-storage for the item data
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc: u32 = 0xFFFF_FFFF;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// sequential-storage never stores a data crc of 0, as that marks popped items
+fn adapted_crc32(data: &[u8]) -> u32 {
+    match crc32(data) {
+        0 => 1,
+        crc => crc,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash_utils::{mock_flash, MockFlash, MOCK_FLASH_RANGE};
+    use embedded_storage_async::nor_flash::NorFlash;
+    use futures::executor::block_on;
+    use sequential_storage::cache::NoCache;
+    use sequential_storage::queue;
+
+    fn write_item(partition: &mut [u8], offset: usize, data: &[u8]) -> usize {
+        let length = (data.len() as u16).to_le_bytes();
+        partition[offset..offset + 4].copy_from_slice(&adapted_crc32(data).to_le_bytes());
+        partition[offset + 4..offset + 6].copy_from_slice(&length);
+        partition[offset + 6..offset + 8].copy_from_slice(&crc16(&length).to_le_bytes());
+        let data_start = offset + round_up(ITEM_HEADER_SIZE);
+        partition[data_start..data_start + data.len()].copy_from_slice(data);
+        data_start + round_up(data.len())
+    }
+
+    #[test]
+    fn test_crc() {
+        assert_eq!(crc16(b"123456789"), 0x4B37);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_item_offsets() {
+        // The second page is full and older than the first one, which the queue wrapped around to
+        let mut partition = vec![0xFF; PAGE_SIZE * 2];
+        partition[..WORD_SIZE].fill(0);
+        partition[PAGE_SIZE..PAGE_SIZE + WORD_SIZE].fill(0);
+        partition[2 * PAGE_SIZE - WORD_SIZE..].fill(0);
+        let newest = WORD_SIZE;
+        write_item(&mut partition, newest, b"newest");
+        let oldest = PAGE_SIZE + WORD_SIZE;
+        let next = write_item(&mut partition, oldest, b"oldest");
+        write_item(&mut partition, next, b"popped");
+        partition[next..next + 4].fill(0);
+
+        assert_eq!(item_offsets(&partition), [oldest, newest]);
+    }
+
+    #[test]
+    fn test_fsck_and_repair() {
+        let mut partition = vec![0xFF; PAGE_SIZE * 2];
+        partition[..WORD_SIZE].fill(0);
+        let next = write_item(&mut partition, WORD_SIZE, b"hello");
+        let torn = next;
+        write_item(&mut partition, torn, b"world, how are you?");
+        // Simulate a reset before the last data word was written
+        partition[torn + round_up(ITEM_HEADER_SIZE) + 16..torn + round_up(ITEM_HEADER_SIZE) + 20]
+            .fill(0xFF);
+
+        let report = fsck(&partition);
+        assert_eq!(item_offsets(&partition), [WORD_SIZE]);
+        assert_eq!(report.pages[0].state, PageState::PartialOpen);
+        assert_eq!(report.pages[0].valid_items, 1);
+        assert_eq!(
+            report.problems().collect::<Vec<_>>(),
+            [&Problem::TornWrite { offset: torn }]
+        );
+
+        let repaired = repair(&partition, &report);
+        let report = fsck(&repaired);
+        assert!(report.is_clean());
+        assert_eq!(report.pages[0].valid_items, 1);
+        assert_eq!(report.pages[0].erased_items, 1);
+    }
+
+    #[test]
+    fn test_sequential_storage_items() {
+        assert_eq!(<MockFlash as NorFlash>::ERASE_SIZE, PAGE_SIZE);
+        let mut flash = mock_flash();
+        let mut cache = NoCache::new();
+        // 4 items fit in a page, so the queue wraps around and overwrites its oldest pages
+        for i in 0..24 {
+            let data = vec![i as u8; 1000 - i];
+            block_on(queue::push(
+                &mut flash,
+                MOCK_FLASH_RANGE,
+                &mut cache,
+                &data,
+                true,
+            ))
+            .unwrap();
+        }
+        let mut buf = [0; 1024];
+        for _ in 0..2 {
+            block_on(queue::pop(
+                &mut flash,
+                MOCK_FLASH_RANGE,
+                &mut cache,
+                &mut buf,
+            ))
+            .unwrap()
+            .unwrap();
+        }
+        let partition = flash.as_bytes().to_vec();
+
+        let report = fsck(&partition);
+        assert!(report.is_clean(), "{}", report);
+        assert_eq!(
+            report.pages.iter().map(|p| p.erased_items).sum::<usize>(),
+            2
+        );
+        let offsets = item_offsets(&partition);
+        assert_eq!(
+            offsets.len(),
+            report.pages.iter().map(|p| p.valid_items).sum::<usize>()
+        );
+        assert!(
+            offsets.windows(2).any(|w| w[0] > w[1]),
+            "the oldest page is not the first"
+        );
+
+        // The items at the offsets are the ones the queue yields, in the same order
+        let at_offsets: Vec<_> = offsets
+            .iter()
+            .map(|&offset| {
+                let header = &partition[offset..offset + ITEM_HEADER_SIZE];
+                let length = u16::from_le_bytes(header[LENGTH_FIELD].try_into().unwrap());
+                let data_start = offset + round_up(ITEM_HEADER_SIZE);
+                partition[data_start..data_start + length as usize].to_vec()
+            })
+            .collect();
+        let mut iter = block_on(queue::iter(&mut flash, MOCK_FLASH_RANGE, &mut cache)).unwrap();
+        let mut yielded = Vec::new();
+        while let Some(entry) = block_on(iter.next(&mut buf)).unwrap() {
+            yielded.push(entry.to_vec());
+        }
+        assert_eq!(at_offsets, yielded);
+        assert_eq!(yielded.last().unwrap(), &vec![23; 1000 - 23]);
+    }
+}
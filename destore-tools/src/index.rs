@@ -0,0 +1,199 @@
+use crate::{format_hash, SchemaRestorer};
+use anyhow::Context;
+use postcard_schema::key::hash::fnv1a64_owned::hash_ty_path_owned;
+use postcard_schema::schema::owned::OwnedDataModelType;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Name of the index file in a cache directory
+pub const INDEX_FILE: &str = "index.json";
+
+/// The firmware a schema was extracted from
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Provenance {
+    pub elf_path: PathBuf,
+    /// GNU build id of the ELF, hex encoded
+    pub build_id: Option<String>,
+    pub crate_name: Option<String>,
+    pub crate_version: Option<String>,
+    pub git_commit: Option<String>,
+    /// Id of the defmt table of the ELF, which the cache stores under `defmt/<id>.elf`
+    pub defmt_table: Option<String>,
+    /// When the schema was extracted, in seconds since the Unix epoch
+    pub timestamp: u64,
+}
+
+impl Provenance {
+    /// Collects the provenance of an ELF extracted by `destore proxy`.
+    ///
+    /// Cargo passes the package of the binary to the runner in `CARGO_PKG_NAME`, `CARGO_PKG_VERSION`
+    /// and `CARGO_MANIFEST_DIR`. The git commit is that of the package, or of the ELF if the proxy
+    /// is not used as cargo runner.
+    pub fn collect(elf_path: &Path, elf: &SchemaRestorer) -> Self {
+        let elf_path = fs::canonicalize(elf_path).unwrap_or_else(|_| elf_path.to_path_buf());
+        let git_dir = std::env::var_os("CARGO_MANIFEST_DIR")
+            .map(PathBuf::from)
+            .or_else(|| elf_path.parent().map(Path::to_path_buf));
+        Self {
+            build_id: elf.build_id(),
+            crate_name: std::env::var("CARGO_PKG_NAME").ok(),
+            crate_version: std::env::var("CARGO_PKG_VERSION").ok(),
+            git_commit: git_dir.as_deref().and_then(git_commit),
+            defmt_table: elf.defmt_table_id(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            elf_path,
+        }
+    }
+
+    /// Whether both describe the same firmware. Without build id, the path and commit are compared.
+    fn same_firmware(&self, other: &Provenance) -> bool {
+        match (&self.build_id, &other.build_id) {
+            (Some(a), Some(b)) => a == b,
+            _ => self.elf_path == other.elf_path && self.git_commit == other.git_commit,
+        }
+    }
+}
+
+impl fmt::Display for Provenance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(name) = &self.crate_name {
+            write!(f, "{} ", name)?;
+            if let Some(version) = &self.crate_version {
+                write!(f, "{} ", version)?;
+            }
+        }
+        if let Some(commit) = &self.git_commit {
+            let (hash, dirty) = match commit.strip_suffix("-dirty") {
+                Some(hash) => (hash, "-dirty"),
+                None => (commit.as_str(), ""),
+            };
+            write!(f, "@ {}{} ", hash.get(..12).unwrap_or(hash), dirty)?;
+        }
+        write!(f, "({})", self.elf_path.display())
+    }
+}
+
+/// The commit checked out in the repository containing `dir`, with a `-dirty` suffix if there are
+/// uncommitted changes
+fn git_commit(dir: &Path) -> Option<String> {
+    let git = |args: &[&str]| {
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(args)
+            .output()
+            .ok()?;
+        output
+            .status
+            .success()
+            .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+    let commit = git(&["rev-parse", "HEAD"])?;
+    let dirty = git(&["status", "--porcelain", "--untracked-files=no"])
+        .is_some_and(|status| !status.is_empty());
+    Some(if dirty {
+        format!("{}-dirty", commit)
+    } else {
+        commit
+    })
+}
+
+/// A schema in the cache and the firmware it was extracted from
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct IndexEntry {
+    /// Schema hash, hex encoded like the file names in the cache
+    pub hash: String,
+    pub pseudocode: String,
+    #[serde(flatten)]
+    pub provenance: Provenance,
+}
+
+impl IndexEntry {
+    pub fn new(schema: &OwnedDataModelType, provenance: Provenance) -> Self {
+        Self {
+            hash: format_hash(&hash_ty_path_owned("", schema)),
+            pseudocode: schema.to_pseudocode(),
+            provenance,
+        }
+    }
+}
+
+/// Records which firmwares the schemas in a cache directory were extracted from
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct CacheIndex {
+    pub entries: Vec<IndexEntry>,
+}
+
+impl CacheIndex {
+    /// Reads the index file, empty if it does not exist
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        serde_json::from_slice(&fs::read(path)?)
+            .with_context(|| format!("Failed to read cache index {:?}", path))
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        fs::write(path, serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("Failed to write cache index {:?}", path))
+    }
+
+    /// Adds an entry, replacing the one of the same schema and firmware
+    pub fn insert(&mut self, entry: IndexEntry) {
+        self.entries
+            .retain(|e| e.hash != entry.hash || !e.provenance.same_firmware(&entry.provenance));
+        self.entries.push(entry);
+    }
+
+    /// The entries of a schema, oldest first
+    pub fn for_schema(&self, schema_hash: &[u8; 8]) -> Vec<&IndexEntry> {
+        let hash = format_hash(schema_hash);
+        let mut entries: Vec<_> = self.entries.iter().filter(|e| e.hash == hash).collect();
+        entries.sort_by_key(|e| e.provenance.timestamp);
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_index_insert() {
+        let schema = OwnedDataModelType::U16;
+        let provenance = |build_id: Option<&str>, path: &str, timestamp| Provenance {
+            elf_path: path.into(),
+            build_id: build_id.map(str::to_string),
+            timestamp,
+            ..Provenance::default()
+        };
+
+        let mut index = CacheIndex::default();
+        index.insert(IndexEntry::new(&schema, provenance(Some("aa"), "fw", 1)));
+        index.insert(IndexEntry::new(&schema, provenance(Some("bb"), "fw", 2)));
+        // Flashing the same firmware again only updates the timestamp
+        index.insert(IndexEntry::new(&schema, provenance(Some("aa"), "fw", 3)));
+        index.insert(IndexEntry::new(&schema, provenance(None, "other", 4)));
+        index.insert(IndexEntry::new(&schema, provenance(None, "other", 5)));
+
+        let hash = hash_ty_path_owned("", &schema);
+        let entries = index.for_schema(&hash);
+        let firmwares: Vec<_> = entries
+            .iter()
+            .map(|e| (e.provenance.build_id.as_deref(), e.provenance.timestamp))
+            .collect();
+        assert_eq!(firmwares, [(Some("bb"), 2), (Some("aa"), 3), (None, 5)]);
+        assert_eq!(entries[0].pseudocode, "u16");
+        assert!(index.for_schema(&[0xff; 8]).is_empty());
+
+        let json = serde_json::to_string(&index).unwrap();
+        assert_eq!(serde_json::from_str::<CacheIndex>(&json).unwrap(), index);
+    }
+}
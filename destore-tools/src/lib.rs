@@ -4,8 +4,49 @@ pub use schema_restorer::*;
 
 mod flash_utils;
 
+mod type_layout;
+
 mod record_iterator;
 pub use record_iterator::*;
 
 mod cache;
 pub use cache::*;
+
+mod builtin;
+pub use builtin::*;
+
+mod config;
+pub use config::*;
+
+mod index;
+pub use index::*;
+
+mod elf_schemas;
+pub use elf_schemas::*;
+
+mod defmt_frames;
+pub use defmt_frames::*;
+
+mod arrow_export;
+pub use arrow_export::*;
+
+mod pretty_printer;
+pub use pretty_printer::*;
+
+mod filter;
+pub use filter::*;
+
+mod pages;
+pub use pages::*;
+
+mod stats;
+pub use stats::*;
+
+mod fsck;
+pub use fsck::*;
+
+mod logs;
+pub use logs::*;
+
+mod metadata;
+pub use metadata::*;
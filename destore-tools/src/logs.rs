@@ -0,0 +1,111 @@
+use crate::{format_hash, PAGE_SIZE};
+use anyhow::{bail, Context};
+use postcard_schema::key::hash::fnv1a64_owned::hash_ty_path_owned;
+use postcard_schema::schema::owned::OwnedDataModelType;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::ops::Range;
+
+/// Prefix of the symbols `destore::export_log!` exports
+pub const LOG_SYMBOL_PREFIX: &str = "_DESTORE_LOG_";
+
+/// A named log declared with `destore::export_log!`.
+///
+/// Deserialized from the postcard encoding of `destore::ExportedLog`, so the field order matters.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LogDescriptor {
+    pub name: String,
+    pub flash_range: Range<u32>,
+    pub description: String,
+    pub schema: OwnedDataModelType,
+}
+
+impl LogDescriptor {
+    pub fn schema_hash(&self) -> [u8; 8] {
+        hash_ty_path_owned("", &self.schema)
+    }
+
+    /// Log names are Rust constants, but are matched case insensitively on the command line
+    pub fn has_name(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+    }
+
+    /// Size of the flash range, which is dumped page by page
+    pub fn size(&self) -> anyhow::Result<u32> {
+        let Range { start, end } = self.flash_range;
+        let size = end.checked_sub(start).with_context(|| {
+            format!(
+                "Log {} has an invalid flash range {:#x}..{:#x}",
+                self.name, start, end
+            )
+        })?;
+        if size as usize % PAGE_SIZE != 0 {
+            bail!(
+                "Log {} has a flash range {:#x}..{:#x} that is not a multiple of {} bytes",
+                self.name,
+                start,
+                end,
+                PAGE_SIZE
+            );
+        }
+        Ok(size)
+    }
+}
+
+impl fmt::Display for LogDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<16} {:#010x}..{:#010x}  schema {}",
+            self.name,
+            self.flash_range.start,
+            self.flash_range.end,
+            format_hash(&self.schema_hash())
+        )?;
+        if !self.description.is_empty() {
+            write!(f, "  {}", self.description)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exported_log() {
+        const EVENTS: destore::Log<Option<u32>> =
+            destore::Log::new("EVENTS", 0x700000..0x720000, "Boot and error events");
+        const LEN: usize = destore::serialized_log_len(&EVENTS);
+        let exported = destore::ExportedLog::<LEN>::new(&EVENTS);
+
+        let log: LogDescriptor = postcard::from_bytes(&exported.bytes).unwrap();
+        assert_eq!(
+            log,
+            LogDescriptor {
+                name: "EVENTS".to_string(),
+                flash_range: 0x700000..0x720000,
+                description: "Boot and error events".to_string(),
+                schema: OwnedDataModelType::Option(Box::new(OwnedDataModelType::U32)),
+            }
+        );
+        assert!(log.has_name("events"));
+        assert_eq!(log.size().unwrap(), 0x20000);
+    }
+
+    #[test]
+    fn test_size() {
+        let log = |flash_range| LogDescriptor {
+            name: "EVENTS".to_string(),
+            flash_range,
+            description: String::new(),
+            schema: OwnedDataModelType::U32,
+        };
+        assert_eq!(log(0x700000..0x700000).size().unwrap(), 0);
+        assert_eq!(log(0x700000..0x702000).size().unwrap(), 0x2000);
+        assert!(log(0x700000..0x700800).size().is_err());
+        assert!(log(0x700800..0x702000).size().is_err());
+        assert!(log(0x702000..0x700000).size().is_err());
+    }
+}
@@ -1,12 +1,20 @@
-use clap::{Args, Parser, Subcommand};
-use destore_tools::{unpack_partition, Cache, SchemaRestorer};
+use anyhow::Context;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use destore_tools::{
+    format_hash, fsck, partition_stats, read_entries, repair, unpack_partition, write_parquet,
+    Cache, CacheArchive, DecodedPartition, DecodedRecord, DefmtRecord, ElfFile, ElfSchemas,
+    IndexEntry, IndexRange, Metadata, PrettyPrinter, Provenance, RecordFilter, SchemaSource,
+    TimeRange, PAGE_SIZE,
+};
 use espflash::cli::config::Config;
 use espflash::cli::{connect, ConnectArgs};
 use espflash::targets::Chip;
-use log::{info, LevelFilter};
+use log::{error, info, warn, LevelFilter};
+use postcard_schema::schema::owned::OwnedDataModelType;
 use std::fs;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tempfile::NamedTempFile;
 
 #[tokio::main]
@@ -20,13 +28,19 @@ async fn main() -> anyhow::Result<()> {
         .init();
 
     let cli = Cli::parse();
-    cli.command.run().await
+    cli.command.run(&cli.cache_dirs).await
 }
 
 #[derive(Parser)]
 pub struct Cli {
     #[command(subcommand)]
     pub command: Commands,
+
+    /// Directory of the schema cache. Can be given multiple times: schemas are stored in the first
+    /// and looked up in all of them. Defaults to DESTORE_CACHE, the cache.dirs of destore.toml or
+    /// the per-user data directory
+    #[arg(long = "cache-dir", global = true)]
+    pub cache_dirs: Vec<PathBuf>,
 }
 
 #[derive(Subcommand)]
@@ -39,25 +53,48 @@ pub enum Commands {
 
     /// Intercepts (& executes) the passed command and stores the postcard schema found in the ELF file
     Proxy(ProxyCommand),
+
+    /// Summarizes the content of a destore partition file
+    Stats(StatsCommand),
+
+    /// Checks the integrity of a destore partition file
+    Fsck(FsckCommand),
+
+    /// Lists the logs declared with `destore::export_log!` in the flashed firmwares
+    Logs,
+
+    /// Manages the schema cache
+    #[command(subcommand)]
+    Cache(CacheCommand),
 }
 
 impl Commands {
-    pub async fn run(self) -> anyhow::Result<()> {
+    pub async fn run(self, cache_dirs: &[PathBuf]) -> anyhow::Result<()> {
+        // Only located by the commands using it
+        let cache = || Cache::locate(cache_dirs);
         match self {
-            Commands::Dump(cmd) => cmd.run(),
-            Commands::Decode(cmd) => cmd.run(),
-            Commands::Proxy(cmd) => cmd.run(),
+            Commands::Dump(cmd) => cmd.run(&cache()?),
+            Commands::Decode(cmd) => cmd.run(&cache()?),
+            Commands::Proxy(cmd) => cmd.run(cache()?),
+            Commands::Stats(cmd) => cmd.run(&cache()?),
+            Commands::Fsck(cmd) => cmd.run(),
+            Commands::Logs => list_logs(&cache()?),
+            Commands::Cache(cmd) => cmd.run(cache()?),
         }
     }
 }
 
 #[derive(Args)]
 pub struct DumpCommand {
-    #[clap(value_parser=clap_num::maybe_hex::<u32>)]
-    start: u32,
+    #[clap(value_parser=clap_num::maybe_hex::<u32>, required_unless_present = "log")]
+    start: Option<u32>,
 
-    #[clap(value_parser=clap_num::maybe_hex::<u32>)]
-    size: u32,
+    #[clap(value_parser=clap_num::maybe_hex::<u32>, required_unless_present = "log")]
+    size: Option<u32>,
+
+    /// Dump the flash range of this log instead of START and SIZE (see the logs command)
+    #[clap(long, conflicts_with_all = ["start", "size"])]
+    log: Option<String>,
 
     /// Store the partition to this file. It can later be analyzed with the decode command
     #[clap(long)]
@@ -79,6 +116,22 @@ pub struct DecodeCommand {
     common_args: CommonArgs,
 }
 
+#[derive(Args)]
+pub struct StatsCommand {
+    /// The partition file to analyze
+    part: PathBuf,
+}
+
+#[derive(Args)]
+pub struct FsckCommand {
+    /// The partition file to check
+    part: PathBuf,
+
+    /// Write a repaired copy of the partition to this file. It can then be analyzed with the decode command
+    #[clap(long)]
+    repair: Option<PathBuf>,
+}
+
 #[derive(Args)]
 pub struct ProxyCommand {
     #[arg(last = true)]
@@ -86,18 +139,15 @@ pub struct ProxyCommand {
 }
 
 impl ProxyCommand {
-    fn run(self) -> anyhow::Result<()> {
+    fn run(self, mut cache: Cache) -> anyhow::Result<()> {
+        let mut import = None;
         if let Some(last) = self.args.last() {
             if fs::exists(last)? {
-                let last = last.clone();
-                std::thread::spawn(move || -> anyhow::Result<()> {
-                    let elf = SchemaRestorer::from_path(last)?;
-                    let schema = elf.load_schema_from_symbol("_DESTORE_SCHEMA")?;
-                    //info!("Schema found: {:?}", &schema);
-                    let mut cache = Cache::new();
-                    cache.store(&schema)?;
-                    Ok(())
-                });
+                let last = PathBuf::from(last);
+                import = Some(std::thread::spawn(move || {
+                    import_elf(&mut cache, &last)
+                        .with_context(|| format!("Failed to import {:?}", last))
+                }));
             }
         }
 
@@ -105,6 +155,15 @@ impl ProxyCommand {
         let output = std::process::Command::new(&self.args[0])
             .args(&self.args[1..])
             .output()?;
+
+        // Wait for the import, a failed import is reported without failing the proxied command
+        if let Some(import) = import {
+            match import.join() {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("{:#}", e),
+                Err(_) => error!("Schema import panicked"),
+            }
+        }
         std::io::stdout().write_all(&output.stdout)?;
         std::io::stderr().write_all(&output.stderr)?;
 
@@ -112,11 +171,264 @@ impl ProxyCommand {
     }
 }
 
+/// Extracts the schemas, logs and metadata of an ELF into the cache
+fn import_elf(cache: &mut Cache, path: &Path) -> anyhow::Result<()> {
+    let file = ElfFile::read(path)?;
+    let elf = file.restorer()?;
+    let mut schemas = Vec::new();
+    let mut failed = Vec::new();
+    for (symbol, schema) in elf.load_all_schemas() {
+        match schema {
+            Ok(schema) => schemas.push((symbol, schema)),
+            Err(e) => {
+                warn!("Skipping schema {}: {:#}", symbol, e);
+                failed.push(symbol);
+            }
+        }
+    }
+    let logs = elf.load_logs()?;
+    let metadata = elf.load_metadata()?;
+    let provenance = Provenance::collect(path, &elf);
+    if schemas.is_empty() && logs.is_empty() && failed.is_empty() {
+        warn!("No schema or log exported, use destore::export_schema! or destore::export_log! and link with -Tdestore.x");
+    }
+    for (symbol, schema) in schemas {
+        info!("Schema found: {}", symbol);
+        cache.store(&schema)?;
+        cache.index_schema(&schema, &provenance)?;
+        cache.store_metadata(&schema, &Metadata::for_schema(&metadata, &schema))?;
+    }
+    for log in logs {
+        info!("Log found: {}", log.name);
+        cache.store_log(&log)?;
+        cache.index_schema(&log.schema, &provenance)?;
+        cache.store_metadata(&log.schema, &Metadata::for_schema(&metadata, &log.schema))?;
+    }
+    if let Some(id) = &provenance.defmt_table {
+        info!("Defmt table found: {}", id);
+        cache.store_defmt_table(id, file.bytes())?;
+    }
+    if !failed.is_empty() {
+        anyhow::bail!(
+            "Failed to load {} of the exported schemas: {}",
+            failed.len(),
+            failed.join(", ")
+        );
+    }
+    Ok(())
+}
+
+#[derive(Subcommand)]
+pub enum CacheCommand {
+    /// Lists the cached schemas and when they were first and last extracted
+    List,
+
+    /// Prints a schema as pseudocode and JSON, together with the firmwares it was extracted from
+    Show {
+        /// Hash of the schema, or a unique prefix of it
+        hash: String,
+    },
+
+    /// Extracts the schemas of an ELF without flashing it, or imports an archive written by export
+    Import {
+        /// ELF file or archive
+        path: PathBuf,
+    },
+
+    /// Writes all schemas, logs and the index to an archive, e.g. to import them on another machine
+    Export {
+        /// The archive to write (JSON)
+        archive: PathBuf,
+    },
+
+    /// Removes schemas that were last extracted longer ago than the given duration from the store
+    /// directory (the first cache directory). Schemas of logs are kept.
+    Prune {
+        /// e.g. `30days` or `6months`
+        #[clap(long, value_parser = humantime::parse_duration)]
+        older_than: Duration,
+
+        /// Only list the schemas that would be removed
+        #[clap(long)]
+        dry_run: bool,
+    },
+}
+
+impl CacheCommand {
+    fn run(self, mut cache: Cache) -> anyhow::Result<()> {
+        match self {
+            CacheCommand::List => {
+                let index = cache.index()?;
+                println!(
+                    "{:<16}  {:<24}  {:<20}  LAST SEEN",
+                    "HASH", "TYPE", "FIRST SEEN"
+                );
+                for (hash, schema) in cache.schemas()? {
+                    let entries = index.for_schema(&hash);
+                    let seen = |entry: Option<&&IndexEntry>| {
+                        entry.map_or("-".to_string(), |e| {
+                            format_timestamp(e.provenance.timestamp)
+                        })
+                    };
+                    println!(
+                        "{:<16}  {:<24}  {:<20}  {}",
+                        format_hash(&hash),
+                        type_name(&schema),
+                        seen(entries.first()),
+                        seen(entries.last())
+                    );
+                }
+            }
+            CacheCommand::Show { hash } => {
+                let (hash, schema) = find_schema(&cache, &hash)?;
+                println!("Schema {}\n", format_hash(&hash));
+                println!("{}\n", schema.to_pseudocode());
+                println!("{}", serde_json::to_string_pretty(&schema)?);
+                let metadata = cache.lookup_metadata(&hash)?;
+                if !metadata.is_empty() {
+                    println!("\nMetadata:\n{}", serde_json::to_string_pretty(&metadata)?);
+                }
+                let index = cache.index()?;
+                let entries = index.for_schema(&hash);
+                if !entries.is_empty() {
+                    println!("\nExtracted from:");
+                }
+                for entry in entries {
+                    let provenance = &entry.provenance;
+                    print!(
+                        "  {}  {}",
+                        format_timestamp(provenance.timestamp),
+                        provenance
+                    );
+                    match &provenance.build_id {
+                        Some(build_id) => println!("  build id {}", build_id),
+                        None => println!(),
+                    }
+                }
+            }
+            CacheCommand::Import { path } => {
+                let bytes = fs::read(&path)?;
+                if bytes.starts_with(b"\x7fELF") {
+                    import_elf(&mut cache, &path)?;
+                } else {
+                    let archive: CacheArchive = serde_json::from_slice(&bytes)
+                        .with_context(|| format!("{:?} is neither an ELF nor an archive", path))?;
+                    cache.import(&archive)?;
+                    info!(
+                        "Imported {} schemas and {} logs",
+                        archive.schemas.len(),
+                        archive.logs.len()
+                    );
+                }
+            }
+            CacheCommand::Export { archive } => {
+                let exported = cache.export()?;
+                fs::write(&archive, serde_json::to_vec_pretty(&exported)?)?;
+                info!(
+                    "Exported {} schemas and {} logs to {:?}",
+                    exported.schemas.len(),
+                    exported.logs.len(),
+                    archive
+                );
+            }
+            CacheCommand::Prune {
+                older_than,
+                dry_run,
+            } => {
+                let cutoff = SystemTime::now()
+                    .checked_sub(older_than)
+                    .unwrap_or(UNIX_EPOCH);
+                for hash in cache.prune(cutoff, dry_run)? {
+                    println!(
+                        "{} {}",
+                        if dry_run { "Would remove" } else { "Removed" },
+                        format_hash(&hash)
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The schema whose hash starts with `prefix`
+fn find_schema(cache: &Cache, prefix: &str) -> anyhow::Result<([u8; 8], OwnedDataModelType)> {
+    let prefix = prefix.to_ascii_lowercase();
+    let mut matches: Vec<_> = cache
+        .schemas()?
+        .into_iter()
+        .filter(|(hash, _)| format_hash(hash).starts_with(&prefix))
+        .collect();
+    match matches.len() {
+        0 => anyhow::bail!("No schema {} in the cache", prefix),
+        1 => Ok(matches.remove(0)),
+        n => anyhow::bail!("{} schemas start with {}, use a longer prefix", n, prefix),
+    }
+}
+
+fn type_name(schema: &OwnedDataModelType) -> String {
+    match schema {
+        OwnedDataModelType::Struct { name, .. } | OwnedDataModelType::Enum { name, .. } => {
+            name.to_string()
+        }
+        schema => schema.to_pseudocode(),
+    }
+}
+
+fn format_timestamp(timestamp: u64) -> String {
+    humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(timestamp)).to_string()
+}
+
 #[derive(Args)]
-pub struct CommonArgs {}
+pub struct CommonArgs {
+    /// Restore the schemas from the ELF of the firmware that wrote the partition instead of looking
+    /// them up in the cache
+    #[clap(long)]
+    elf: Option<PathBuf>,
+
+    /// Output format of the decoded records
+    #[clap(long, value_enum, default_value_t = OutputFormat::Pretty)]
+    format: OutputFormat,
+
+    /// File to write the records to. Required for the parquet format.
+    /// If the partition contains records of several schemas, one file per schema is written.
+    #[clap(long, short)]
+    output: Option<PathBuf>,
+
+    /// Only output records matching this expression, e.g. `Record::Panic` or `Sub.age > 18`.
+    /// Can be given multiple times, all filters must match.
+    #[clap(long = "filter")]
+    filters: Vec<RecordFilter>,
+
+    /// Only output records whose entry index lies in this range, e.g. `100..200`, `100..` or `..200`
+    #[clap(long)]
+    range: Option<IndexRange>,
+
+    /// Only output records whose `timestamp` field lies in this range, e.g. `1000..2000`, in the
+    /// unit of the timestamps. Records without a timestamp are skipped.
+    #[clap(long)]
+    time: Option<TimeRange>,
+
+    /// Only output the last N (matching) records
+    #[clap(long)]
+    last: Option<usize>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum OutputFormat {
+    /// Print every record to stdout like Rust source. Colored unless NO_COLOR is set or stdout is not a terminal
+    Pretty,
+    /// Log every record using its debug representation
+    Log,
+    /// Print every record to stdout as a line of json. Integers of fields with a display format
+    /// become strings like `"0x1f"`
+    Json,
+    /// Write the records to an Apache Parquet file (see --output)
+    Parquet,
+}
 
 impl DumpCommand {
-    fn run(mut self) -> anyhow::Result<()> {
+    fn run(mut self, cache: &Cache) -> anyhow::Result<()> {
         if self.connect_args.chip.is_none() {
             self.connect_args.chip = Some(Chip::Esp32c6);
         }
@@ -130,9 +442,31 @@ impl DumpCommand {
         )
         .map_err(|e| anyhow::anyhow!("Failed to connect to device: {:?}", e))?;
 
+        let (start, size) = match &self.log {
+            Some(name) => {
+                let log = match &self.common_args.elf {
+                    Some(elf) => ElfSchemas::load(elf)?
+                        .lookup_log(name)
+                        .cloned()
+                        .with_context(|| format!("Log {} not found in {:?}", name, elf))?,
+                    None => cache.lookup_log(name)?.with_context(|| {
+                        format!(
+                            "Log {} not found, flash the firmware with `destore proxy` first",
+                            name
+                        )
+                    })?,
+                };
+                (log.flash_range.start, log.size()?)
+            }
+            None => (self.start.unwrap(), self.size.unwrap()),
+        };
+        if size as usize % PAGE_SIZE != 0 {
+            anyhow::bail!("The size must be a multiple of {} bytes", PAGE_SIZE);
+        }
+
         let mut vec = Vec::new();
-        let mut rest = self.size;
-        let mut offset = self.start;
+        let mut rest = size;
+        let mut offset = start;
         while rest > 0 {
             let tmp_file = NamedTempFile::new()?;
             flasher.read_flash(offset, 4096, 4096, 64, tmp_file.path().to_path_buf())?;
@@ -152,12 +486,12 @@ impl DumpCommand {
             info!("Partition stored to {:?}", store_path);
         }
 
-        output_records(vec.as_mut_slice(), &self.common_args)
+        output_records(vec.as_mut_slice(), cache, &self.common_args)
     }
 }
 
 impl DecodeCommand {
-    fn run(self) -> anyhow::Result<()> {
+    fn run(self, cache: &Cache) -> anyhow::Result<()> {
         if !self.part.exists() {
             return Err(anyhow::anyhow!(
                 "Partition file {:?} does not exist",
@@ -166,12 +500,215 @@ impl DecodeCommand {
         }
 
         let mut partition = fs::read(&self.part)?;
-        output_records(&mut partition, &self.common_args)
+        output_records(&mut partition, cache, &self.common_args)
+    }
+}
+
+fn list_logs(cache: &Cache) -> anyhow::Result<()> {
+    let logs = cache.logs()?;
+    if logs.is_empty() {
+        println!("No logs found, flash a firmware using destore::export_log! with `destore proxy`");
     }
+    for log in logs {
+        println!("{}", log);
+    }
+    Ok(())
 }
 
-fn output_records(partition: &mut [u8], _common_args: &CommonArgs) -> anyhow::Result<()> {
-    unpack_partition(partition)?;
+impl StatsCommand {
+    fn run(self, cache: &Cache) -> anyhow::Result<()> {
+        if !self.part.exists() {
+            return Err(anyhow::anyhow!(
+                "Partition file {:?} does not exist",
+                self.part
+            ));
+        }
+
+        let mut partition = fs::read(&self.part)?;
+        println!("{}", partition_stats(&mut partition, cache)?);
+        Ok(())
+    }
+}
+
+impl FsckCommand {
+    fn run(self) -> anyhow::Result<()> {
+        if !self.part.exists() {
+            return Err(anyhow::anyhow!(
+                "Partition file {:?} does not exist",
+                self.part
+            ));
+        }
+
+        let partition = fs::read(&self.part)?;
+        let report = fsck(&partition);
+        println!("{}", report);
+
+        if let Some(repair_path) = self.repair.as_ref() {
+            fs::write(repair_path, repair(&partition, &report))?;
+            info!("Repaired partition stored to {:?}", repair_path);
+        } else if !report.is_clean() {
+            std::process::exit(1);
+        }
+        Ok(())
+    }
+}
+
+fn output_records(
+    partition: &mut [u8],
+    cache: &Cache,
+    common_args: &CommonArgs,
+) -> anyhow::Result<()> {
+    let elf = common_args
+        .elf
+        .as_deref()
+        .map(ElfSchemas::load)
+        .transpose()?;
+    let schemas: &dyn SchemaSource = match &elf {
+        Some(elf) => {
+            elf.verify(&read_entries(partition)?)?;
+            elf
+        }
+        None => cache,
+    };
+    let mut decoded = unpack_partition(partition, schemas)?;
+
+    decoded.records.retain(|record| {
+        let schema = &decoded.schemas[&record.schema_hash];
+        common_args
+            .range
+            .as_ref()
+            .is_none_or(|range| range.0.contains(&record.index))
+            && common_args
+                .time
+                .as_ref()
+                .is_none_or(|time| time.matches(schema, &record.value))
+            && common_args
+                .filters
+                .iter()
+                .all(|filter| filter.matches(schema, &record.value))
+    });
+    // The filters refer to the schema of the records, defmt frames never match them
+    decoded.defmt.retain(|frame| {
+        common_args
+            .range
+            .as_ref()
+            .is_none_or(|range| range.0.contains(&frame.index))
+            && common_args
+                .time
+                .as_ref()
+                .is_none_or(|time| time.matches_defmt(frame.timestamp.as_deref()))
+            && common_args.filters.is_empty()
+    });
+    if let Some(last) = common_args.last {
+        decoded.retain_last(last);
+    }
+
+    match common_args.format {
+        OutputFormat::Pretty => {
+            let printer = PrettyPrinter::for_stdout();
+            for entry in interleave(&decoded) {
+                match entry {
+                    OutputEntry::Record(record) => {
+                        let schema = &decoded.schemas[&record.schema_hash];
+                        let metadata = &decoded.metadata[&record.schema_hash];
+                        println!("{}", printer.format_record(schema, metadata, record));
+                    }
+                    OutputEntry::Defmt(frame) => println!("{}", printer.format_defmt(frame)),
+                }
+            }
+        }
+        OutputFormat::Log => {
+            for entry in interleave(&decoded) {
+                match entry {
+                    OutputEntry::Record(record) => info!("Data entry: {:?}", record.value),
+                    OutputEntry::Defmt(frame) => info!("Defmt entry: {}", frame.message),
+                }
+            }
+        }
+        OutputFormat::Json => {
+            for entry in interleave(&decoded) {
+                let line = match entry {
+                    OutputEntry::Record(record) => {
+                        let schema = &decoded.schemas[&record.schema_hash];
+                        let metadata = &decoded.metadata[&record.schema_hash];
+                        serde_json::json!({
+                            "index": record.index,
+                            "offset": record.offset,
+                            "schema": format_hash(&record.schema_hash),
+                            "record": metadata.apply_format(schema, record.value.clone()),
+                        })
+                    }
+                    OutputEntry::Defmt(frame) => serde_json::json!({
+                        "index": frame.index,
+                        "offset": frame.offset,
+                        "level": frame.level,
+                        "timestamp": frame.timestamp,
+                        "message": frame.message,
+                    }),
+                };
+                println!("{}", line);
+            }
+        }
+        OutputFormat::Parquet => {
+            let output = common_args
+                .output
+                .as_ref()
+                .context("--output is required for the parquet format")?;
+            if !decoded.defmt.is_empty() {
+                warn!(
+                    "{} defmt frames are not written to the parquet file",
+                    decoded.defmt.len()
+                );
+            }
+            for (hash, schema) in &decoded.schemas {
+                let records: Vec<_> = decoded
+                    .records
+                    .iter()
+                    .filter(|r| r.schema_hash == *hash)
+                    .collect();
+                if records.is_empty() {
+                    continue;
+                }
+                let path = if decoded.schemas.len() == 1 {
+                    output.clone()
+                } else {
+                    schema_specific_path(output, hash)
+                };
+                write_parquet(&path, schema, &decoded.metadata[hash], &records)?;
+            }
+        }
+    }
 
     Ok(())
 }
+
+enum OutputEntry<'a> {
+    Record(&'a DecodedRecord),
+    Defmt(&'a DefmtRecord),
+}
+
+/// The records and defmt frames in the order they were written
+fn interleave(decoded: &DecodedPartition) -> Vec<OutputEntry<'_>> {
+    let mut entries: Vec<_> = decoded
+        .records
+        .iter()
+        .map(OutputEntry::Record)
+        .chain(decoded.defmt.iter().map(OutputEntry::Defmt))
+        .collect();
+    entries.sort_by_key(|entry| match entry {
+        OutputEntry::Record(record) => record.index,
+        OutputEntry::Defmt(frame) => frame.index,
+    });
+    entries
+}
+
+/// Turns `out.parquet` into `out.<schema hash>.parquet`
+fn schema_specific_path(output: &Path, hash: &[u8; 8]) -> PathBuf {
+    let mut file_name = output.file_stem().unwrap_or_default().to_os_string();
+    file_name.push(format!(".{}", format_hash(hash)));
+    if let Some(extension) = output.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    output.with_file_name(file_name)
+}
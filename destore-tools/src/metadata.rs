@@ -0,0 +1,443 @@
+use log::warn;
+use postcard_dyn::Value;
+use postcard_schema::key::hash::fnv1a64_owned::hash_ty_path_owned;
+use postcard_schema::schema::owned::{OwnedData, OwnedDataModelType};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// Prefix of the symbols `#[derive(destore::DestoreRecord)]` exports the type metadata under
+pub const METADATA_SYMBOL_PREFIX: &str = "_DESTORE_META_";
+
+/// Annotations of the fields and variants of a type, exported by `#[derive(destore::DestoreRecord)]`.
+///
+/// Deserialized from the postcard encoding of `destore::ExportedMetadata`, so the field order matters.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TypeMetadata {
+    pub name: String,
+    /// Hash of the schema of the type. Missing in metadata cached by older versions, which was
+    /// already filtered by schema.
+    #[serde(default)]
+    pub schema_hash: [u8; 8],
+    pub fields: Vec<FieldMetadata>,
+}
+
+/// Annotation of a field, or of a variant if `field` is `None`. Unnamed fields are named by their index.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FieldMetadata {
+    pub variant: Option<String>,
+    pub field: Option<String>,
+    pub unit: Option<String>,
+    pub scale: Option<f64>,
+    pub format: Option<String>,
+    pub description: Option<String>,
+}
+
+impl FieldMetadata {
+    /// How integers of the field are displayed, `None` if the format is not given or unknown
+    pub fn display_format(&self) -> Option<DisplayFormat> {
+        match self.format.as_deref()? {
+            "hex" => Some(DisplayFormat::Hex),
+            "bin" => Some(DisplayFormat::Bin),
+            _ => None,
+        }
+    }
+}
+
+/// Display format of integers, given with `#[destore(format = "...")]`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DisplayFormat {
+    Hex,
+    Bin,
+}
+
+impl DisplayFormat {
+    /// Formats an integer like `0x1f` or `0b11111`, `None` for other values
+    pub fn format(self, value: &Value) -> Option<String> {
+        let Value::Number(number) = value else {
+            return None;
+        };
+        let (sign, abs) = match (number.as_u64(), number.as_i64()) {
+            (Some(value), _) => ("", value),
+            (None, Some(value)) => ("-", value.unsigned_abs()),
+            _ => return None,
+        };
+        Some(match self {
+            DisplayFormat::Hex => format!("{}{:#x}", sign, abs),
+            DisplayFormat::Bin => format!("{}{:#b}", sign, abs),
+        })
+    }
+
+    /// Replaces the integers of a value, or of the arrays it consists of, by their formatted string
+    fn apply(self, value: Value) -> Value {
+        match value {
+            Value::Array(values) => {
+                Value::Array(values.into_iter().map(|v| self.apply(v)).collect())
+            }
+            value => self.format(&value).map(Value::String).unwrap_or(value),
+        }
+    }
+}
+
+/// The metadata of the types making up a schema
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Metadata {
+    pub types: Vec<TypeMetadata>,
+}
+
+impl Metadata {
+    /// Keeps the metadata of the types the schema refers to, matched by their schema hash as
+    /// types of different modules can share a name.
+    ///
+    /// The metadata is looked up by type name, so if the schema itself refers to several annotated
+    /// types of the same name their metadata is dropped.
+    pub fn for_schema(all: &[TypeMetadata], schema: &OwnedDataModelType) -> Self {
+        let mut hashes = HashSet::new();
+        collect_type_hashes(schema, &mut hashes);
+        let mut types: Vec<_> = all
+            .iter()
+            .filter(|ty| hashes.contains(&ty.schema_hash))
+            .cloned()
+            .collect();
+        let ambiguous: HashSet<_> = types
+            .iter()
+            .enumerate()
+            .filter(|(i, ty)| types[..*i].iter().any(|t| t.name == ty.name && t != *ty))
+            .map(|(_, ty)| ty.name.clone())
+            .collect();
+        for name in &ambiguous {
+            warn!(
+                "Ignoring the metadata of {}, the schema refers to several types of that name",
+                name
+            );
+        }
+        types.retain(|ty| !ambiguous.contains(&ty.name));
+        Self { types }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.types.is_empty()
+    }
+
+    /// Annotation of a field of a struct (`variant` is `None`) or of an enum variant
+    pub fn field(&self, ty: &str, variant: Option<&str>, field: &str) -> Option<&FieldMetadata> {
+        self.find(ty, variant, Some(field))
+    }
+
+    /// Annotation of an enum variant itself
+    pub fn variant(&self, ty: &str, variant: &str) -> Option<&FieldMetadata> {
+        self.find(ty, Some(variant), None)
+    }
+
+    fn find(&self, ty: &str, variant: Option<&str>, field: Option<&str>) -> Option<&FieldMetadata> {
+        self.types
+            .iter()
+            .filter(|t| t.name == ty)
+            .flat_map(|t| &t.fields)
+            .find(|f| f.variant.as_deref() == variant && f.field.as_deref() == field)
+    }
+
+    /// Multiplies the values of fields with a scale, so they are expressed in their unit
+    pub fn apply_scale(&self, ty: &OwnedDataModelType, value: Value) -> Value {
+        self.map_fields(ty, value, &|field, value| match field.scale {
+            Some(factor) => scale_number(value, factor),
+            None => value,
+        })
+    }
+
+    /// Replaces the integers of fields with a display format by their formatted string, e.g. for
+    /// json output. Other outputs format them while rendering.
+    pub fn apply_format(&self, ty: &OwnedDataModelType, value: Value) -> Value {
+        self.map_fields(ty, value, &|field, value| match field.display_format() {
+            Some(format) => format.apply(value),
+            None => value,
+        })
+    }
+
+    /// Rewrites the values of the annotated fields with `f`, innermost first
+    fn map_fields(&self, ty: &OwnedDataModelType, value: Value, f: &MapField) -> Value {
+        if self.is_empty() {
+            return value;
+        }
+        match (ty, value) {
+            (OwnedDataModelType::Option(inner), value) if !value.is_null() => {
+                self.map_fields(inner, value, f)
+            }
+            (OwnedDataModelType::Seq(inner), Value::Array(values)) => Value::Array(
+                values
+                    .into_iter()
+                    .map(|v| self.map_fields(inner, v, f))
+                    .collect(),
+            ),
+            (OwnedDataModelType::Tuple(types), Value::Array(values)) => Value::Array(
+                types
+                    .iter()
+                    .zip(values)
+                    .map(|(ty, v)| self.map_fields(ty, v, f))
+                    .collect(),
+            ),
+            (OwnedDataModelType::Map { val, .. }, Value::Object(map)) => Value::Object(
+                map.into_iter()
+                    .map(|(k, v)| (k, self.map_fields(val, v, f)))
+                    .collect(),
+            ),
+            (OwnedDataModelType::Struct { name, data }, value) => {
+                self.map_data(name, None, data, value, f)
+            }
+            (OwnedDataModelType::Enum { name, variants }, Value::Object(map)) if map.len() == 1 => {
+                let (variant_name, inner) = map.into_iter().next().unwrap();
+                let inner = match variants.iter().find(|v| *v.name == *variant_name) {
+                    Some(variant) => {
+                        self.map_data(name, Some(&variant_name), &variant.data, inner, f)
+                    }
+                    None => inner,
+                };
+                Value::Object([(variant_name, inner)].into_iter().collect())
+            }
+            (_, value) => value,
+        }
+    }
+
+    fn map_data(
+        &self,
+        ty: &str,
+        variant: Option<&str>,
+        data: &OwnedData,
+        value: Value,
+        f: &MapField,
+    ) -> Value {
+        let map_field = |field: &str, field_ty: &OwnedDataModelType, value: Value| {
+            let value = self.map_fields(field_ty, value, f);
+            match self.field(ty, variant, field) {
+                Some(annotation) => f(annotation, value),
+                None => value,
+            }
+        };
+        match (data, value) {
+            (OwnedData::Newtype(inner), value) => map_field("0", inner, value),
+            (OwnedData::Tuple(types), Value::Array(values)) => Value::Array(
+                types
+                    .iter()
+                    .zip(values)
+                    .enumerate()
+                    .map(|(i, (ty, v))| map_field(&i.to_string(), ty, v))
+                    .collect(),
+            ),
+            (OwnedData::Struct(fields), Value::Object(mut map)) => {
+                for field in fields.iter() {
+                    if let Some(v) = map.remove(&*field.name) {
+                        map.insert(field.name.to_string(), map_field(&field.name, &field.ty, v));
+                    }
+                }
+                Value::Object(map)
+            }
+            (_, value) => value,
+        }
+    }
+}
+
+type MapField = dyn Fn(&FieldMetadata, Value) -> Value;
+
+/// Collects the schema hashes of the structs and enums a schema consists of
+fn collect_type_hashes(ty: &OwnedDataModelType, hashes: &mut HashSet<[u8; 8]>) {
+    match ty {
+        OwnedDataModelType::Option(inner) | OwnedDataModelType::Seq(inner) => {
+            collect_type_hashes(inner, hashes)
+        }
+        OwnedDataModelType::Tuple(types) => {
+            types.iter().for_each(|t| collect_type_hashes(t, hashes))
+        }
+        OwnedDataModelType::Map { key, val } => {
+            collect_type_hashes(key, hashes);
+            collect_type_hashes(val, hashes);
+        }
+        OwnedDataModelType::Struct { data, .. } => {
+            hashes.insert(hash_ty_path_owned("", ty));
+            collect_data_type_hashes(data, hashes);
+        }
+        OwnedDataModelType::Enum { variants, .. } => {
+            hashes.insert(hash_ty_path_owned("", ty));
+            variants
+                .iter()
+                .for_each(|v| collect_data_type_hashes(&v.data, hashes));
+        }
+        _ => {}
+    }
+}
+
+fn collect_data_type_hashes(data: &OwnedData, hashes: &mut HashSet<[u8; 8]>) {
+    match data {
+        OwnedData::Unit => {}
+        OwnedData::Newtype(inner) => collect_type_hashes(inner, hashes),
+        OwnedData::Tuple(types) => types.iter().for_each(|t| collect_type_hashes(t, hashes)),
+        OwnedData::Struct(fields) => fields
+            .iter()
+            .for_each(|f| collect_type_hashes(&f.ty, hashes)),
+    }
+}
+
+/// Scales a number, dividing by the inverse if it is an integer (e.g. 100 for 0.01) to avoid
+/// results like 23.150000000000002
+fn scale_number(value: Value, factor: f64) -> Value {
+    let Some(number) = value.as_f64() else {
+        return value;
+    };
+    let inverse = 1.0 / factor;
+    let scaled = if inverse.abs() > 1.0 && (inverse - inverse.round()).abs() < 1e-9 {
+        number / inverse.round()
+    } else {
+        number * factor
+    };
+    serde_json::Number::from_f64(scaled)
+        .map(Value::Number)
+        .unwrap_or(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use postcard_schema::schema::owned::{OwnedNamedField, OwnedVariant};
+    use serde_json::json;
+
+    #[test]
+    fn test_exported_metadata() {
+        const METADATA: destore::TypeMetadata = destore::TypeMetadata {
+            name: "Record",
+            schema_hash: [1, 2, 3, 4, 5, 6, 7, 8],
+            fields: &[
+                destore::FieldMetadata {
+                    variant: Some("Temperature"),
+                    field: Some("value"),
+                    unit: Some("°C"),
+                    scale: Some(0.01),
+                    format: None,
+                    description: None,
+                },
+                destore::FieldMetadata {
+                    variant: Some("Temperature"),
+                    field: Some("sensor"),
+                    unit: None,
+                    scale: None,
+                    format: Some("hex"),
+                    description: None,
+                },
+                destore::FieldMetadata {
+                    variant: Some("Boot"),
+                    field: None,
+                    unit: None,
+                    scale: None,
+                    format: None,
+                    description: Some("Written once after reset"),
+                },
+            ],
+        };
+        const LEN: usize = destore::serialized_metadata_len(&METADATA);
+        let exported = destore::ExportedMetadata::<LEN>::new(&METADATA);
+        let metadata: TypeMetadata = postcard::from_bytes(&exported.bytes).unwrap();
+        assert_eq!(metadata.schema_hash, [1, 2, 3, 4, 5, 6, 7, 8]);
+        let metadata = Metadata {
+            types: vec![metadata],
+        };
+
+        assert_eq!(
+            metadata
+                .variant("Record", "Boot")
+                .and_then(|f| f.description.as_deref()),
+            Some("Written once after reset")
+        );
+        let schema = OwnedDataModelType::Enum {
+            name: "Record".into(),
+            variants: Box::new([
+                OwnedVariant {
+                    name: "Boot".into(),
+                    data: OwnedData::Unit,
+                },
+                OwnedVariant {
+                    name: "Temperature".into(),
+                    data: OwnedData::Struct(Box::new([
+                        OwnedNamedField {
+                            name: "sensor".into(),
+                            ty: OwnedDataModelType::U8,
+                        },
+                        OwnedNamedField {
+                            name: "value".into(),
+                            ty: OwnedDataModelType::I16,
+                        },
+                    ])),
+                },
+            ]),
+        };
+        assert_eq!(
+            metadata.apply_scale(
+                &schema,
+                json!({"Temperature": {"sensor": 3, "value": 2315}})
+            ),
+            json!({"Temperature": {"sensor": 3, "value": 23.15}})
+        );
+        assert_eq!(metadata.apply_scale(&schema, json!("Boot")), json!("Boot"));
+        assert_eq!(
+            metadata.apply_format(
+                &schema,
+                json!({"Temperature": {"sensor": 31, "value": 23.15}})
+            ),
+            json!({"Temperature": {"sensor": "0x1f", "value": 23.15}})
+        );
+    }
+
+    #[test]
+    fn test_display_format() {
+        assert_eq!(DisplayFormat::Hex.format(&json!(255)), Some("0xff".into()));
+        assert_eq!(DisplayFormat::Hex.format(&json!(-16)), Some("-0x10".into()));
+        assert_eq!(DisplayFormat::Bin.format(&json!(5)), Some("0b101".into()));
+        assert_eq!(DisplayFormat::Hex.format(&json!(1.5)), None);
+        assert_eq!(
+            DisplayFormat::Hex.apply(json!([1, 2, 171])),
+            json!(["0x1", "0x2", "0xab"])
+        );
+    }
+
+    #[test]
+    fn test_for_schema_same_name() {
+        // `Reading` of two modules, annotated with different units
+        let reading = |ty: OwnedDataModelType| OwnedDataModelType::Struct {
+            name: "Reading".into(),
+            data: OwnedData::Struct(Box::new([OwnedNamedField {
+                name: "value".into(),
+                ty,
+            }])),
+        };
+        let temperature = reading(OwnedDataModelType::I16);
+        let voltage = reading(OwnedDataModelType::U32);
+        let metadata = |schema: &OwnedDataModelType, unit: &str| TypeMetadata {
+            name: "Reading".to_string(),
+            schema_hash: hash_ty_path_owned("", schema),
+            fields: vec![FieldMetadata {
+                variant: None,
+                field: Some("value".to_string()),
+                unit: Some(unit.to_string()),
+                scale: None,
+                format: None,
+                description: None,
+            }],
+        };
+        let all = [metadata(&temperature, "°C"), metadata(&voltage, "mV")];
+
+        let unit = |metadata: &Metadata| {
+            metadata
+                .field("Reading", None, "value")
+                .and_then(|f| f.unit.clone())
+        };
+        assert_eq!(
+            unit(&Metadata::for_schema(&all, &temperature)),
+            Some("°C".to_string())
+        );
+        let record = OwnedDataModelType::Seq(Box::new(voltage.clone()));
+        assert_eq!(
+            unit(&Metadata::for_schema(&all, &record)),
+            Some("mV".to_string())
+        );
+
+        // Looked up by name, the metadata of a schema with both would be ambiguous
+        let both = OwnedDataModelType::Tuple(Box::new([temperature, voltage]));
+        assert!(Metadata::for_schema(&all, &both).is_empty());
+    }
+}
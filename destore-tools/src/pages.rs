@@ -0,0 +1,120 @@
+use crate::flash_utils::FlashVec;
+use embedded_storage_async::nor_flash::{NorFlash, ReadNorFlash};
+use std::fmt;
+
+/// Size of a flash page (erase unit) as used by sequential-storage
+pub const PAGE_SIZE: usize = <FlashVec as NorFlash>::ERASE_SIZE;
+
+/// Size of the page markers at the start and the end of every page
+pub const WORD_SIZE: usize =
+    if <FlashVec as ReadNorFlash>::READ_SIZE > <FlashVec as NorFlash>::WRITE_SIZE {
+        <FlashVec as ReadNorFlash>::READ_SIZE
+    } else {
+        <FlashVec as NorFlash>::WRITE_SIZE
+    };
+
+/// A marker counts as written if at least half of its bits are cleared (same rule as sequential-storage)
+const HALF_MARKER_BITS: u32 = (WORD_SIZE * 8 / 2) as u32;
+
+/// State of a page, derived from the markers at its start and end (see sequential-storage)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageState {
+    /// Erased, no data
+    Open,
+    /// Currently being written to
+    PartialOpen,
+    /// Full
+    Closed,
+    /// End marker written without start marker
+    Corrupted,
+}
+
+impl fmt::Display for PageState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            PageState::Open => "open",
+            PageState::PartialOpen => "partial-open",
+            PageState::Closed => "closed",
+            PageState::Corrupted => "corrupted",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Reads the state of every page of a partition image
+pub fn page_states(partition: &[u8]) -> Vec<PageState> {
+    partition
+        .chunks_exact(PAGE_SIZE)
+        .map(|page| {
+            let marked = |marker: &[u8]| {
+                marker.iter().map(|b| b.count_zeros()).sum::<u32>() >= HALF_MARKER_BITS
+            };
+            let start_marked = marked(&page[..WORD_SIZE]);
+            let end_marked = marked(&page[PAGE_SIZE - WORD_SIZE..]);
+            match (start_marked, end_marked) {
+                (false, false) => PageState::Open,
+                (true, false) => PageState::PartialOpen,
+                (true, true) => PageState::Closed,
+                (false, true) => PageState::Corrupted,
+            }
+        })
+        .collect()
+}
+
+/// Finds the page holding the oldest data of the queue, like sequential-storage does.
+///
+/// The queue is a ring of pages: the oldest data is in the first closed page after the page written
+/// last, which is the partially open one (or else the first open one). If all pages are closed, the
+/// queue has not wrapped and starts at the first page. Returns `None` if there is no used page.
+pub fn oldest_page(states: &[PageState]) -> Option<usize> {
+    if !states
+        .iter()
+        .any(|s| matches!(s, PageState::PartialOpen | PageState::Closed))
+    {
+        return None;
+    }
+    let youngest = states
+        .iter()
+        .position(|s| *s == PageState::PartialOpen)
+        .or_else(|| states.iter().position(|s| *s == PageState::Open))
+        .unwrap_or(states.len() - 1);
+    let oldest = (1..=states.len())
+        .map(|offset| (youngest + offset) % states.len())
+        .find(|&i| states[i] == PageState::Closed);
+    Some(oldest.unwrap_or(youngest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use PageState::*;
+
+    #[test]
+    fn test_page_states() {
+        let mut partition = vec![0xFF; PAGE_SIZE * 5];
+        partition[PAGE_SIZE..PAGE_SIZE + WORD_SIZE].fill(0);
+        partition[2 * PAGE_SIZE..2 * PAGE_SIZE + WORD_SIZE].fill(0);
+        partition[3 * PAGE_SIZE - WORD_SIZE..3 * PAGE_SIZE].fill(0);
+        // Half of the bits of a marker count as written, fewer do not
+        partition[3 * PAGE_SIZE..3 * PAGE_SIZE + WORD_SIZE / 2].fill(0);
+        partition[4 * PAGE_SIZE] = 0xF0;
+        assert_eq!(
+            page_states(&partition),
+            [Open, PartialOpen, Closed, PartialOpen, Open]
+        );
+    }
+
+    #[test]
+    fn test_oldest_page() {
+        assert_eq!(oldest_page(&[Closed, PartialOpen, Open, Open]), Some(0));
+        assert_eq!(oldest_page(&[Closed, PartialOpen, Open, Closed]), Some(3));
+        assert_eq!(oldest_page(&[PartialOpen, Open, Closed, Closed]), Some(2));
+        assert_eq!(oldest_page(&[PartialOpen, Open, Open]), Some(0));
+        assert_eq!(oldest_page(&[Closed, Open, Closed]), Some(2));
+        // Full rings
+        assert_eq!(oldest_page(&[Closed, PartialOpen, Closed]), Some(2));
+        assert_eq!(oldest_page(&[Closed, Closed, PartialOpen]), Some(0));
+        assert_eq!(oldest_page(&[Closed, Closed]), Some(0));
+        assert_eq!(oldest_page(&[Open, Open]), None);
+    }
+}
@@ -0,0 +1,576 @@
+use crate::{
+    find_timestamp, DecodedRecord, DefmtRecord, DisplayFormat, FieldMetadata, Metadata,
+    TIMESTAMP_FIELD,
+};
+use postcard_dyn::Value;
+use postcard_schema::schema::owned::{OwnedData, OwnedDataModelType, OwnedNamedField};
+use std::fmt::Write;
+
+const RESET: &str = "\x1b[0m";
+const DIM: &str = "\x1b[2m";
+const TYPE_NAME: &str = "\x1b[1;34m";
+const VARIANT_NAME: &str = "\x1b[1;35m";
+const STRING: &str = "\x1b[32m";
+const NUMBER: &str = "\x1b[33m";
+const ERROR: &str = "\x1b[1;31m";
+const WARN: &str = "\x1b[1;33m";
+const INFO: &str = "\x1b[1;32m";
+
+/// Renders decoded records the way they would be written in Rust source,
+/// e.g. `Sub(Sub { first_name: "Alice", age: 20 })`.
+///
+/// Lines start with the index of the entry and the offset of its item in the partition, followed by
+/// the `timestamp` field of the record (or the struct of its variant) if it has one.
+///
+/// Units from the metadata follow the value (`temp: 23.15 °C`), integers of fields with a display
+/// format are rendered in it (`status: 0x1f`) and descriptions of unit variants are rendered as
+/// comments (`Boot /* Written once after reset */`).
+pub struct PrettyPrinter {
+    color: bool,
+}
+
+impl PrettyPrinter {
+    pub fn new(color: bool) -> Self {
+        Self { color }
+    }
+
+    /// Enables colors if stdout is a terminal and `NO_COLOR` is not set
+    pub fn for_stdout() -> Self {
+        let no_color = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+        Self::new(!no_color && atty::is(atty::Stream::Stdout))
+    }
+
+    /// Formats a record as a single line, prefixed with its position and timestamp
+    pub fn format_record(
+        &self,
+        schema: &OwnedDataModelType,
+        metadata: &Metadata,
+        record: &DecodedRecord,
+    ) -> String {
+        let mut out = String::new();
+        self.format_position(&mut out, record.index, record.offset);
+        if let Some(((ty, variant), timestamp)) = find_timestamp(schema, &record.value) {
+            let mut time = timestamp.to_string();
+            if let Some(unit) = metadata
+                .field(ty, variant, TIMESTAMP_FIELD)
+                .and_then(|f| f.unit.as_deref())
+            {
+                time.push(' ');
+                time.push_str(unit);
+            }
+            self.paint(&mut out, DIM, &time);
+            out.push(' ');
+        }
+        self.format_value(&mut out, metadata, schema, &record.value);
+        out
+    }
+
+    /// Formats a defmt log frame like `defmt-print`, prefixed with its position
+    pub fn format_defmt(&self, frame: &DefmtRecord) -> String {
+        let mut out = String::new();
+        self.format_position(&mut out, frame.index, frame.offset);
+        if let Some(timestamp) = &frame.timestamp {
+            self.paint(&mut out, DIM, timestamp);
+            out.push(' ');
+        }
+        if let Some(level) = &frame.level {
+            let color = match level.as_str() {
+                "error" => ERROR,
+                "warn" => WARN,
+                "info" => INFO,
+                _ => DIM,
+            };
+            self.paint(&mut out, color, &format!("{:<5}", level.to_uppercase()));
+            out.push(' ');
+        }
+        out.push_str(&frame.message);
+        out
+    }
+
+    /// The index of the entry and the offset of its item, blank if unknown
+    fn format_position(&self, out: &mut String, index: usize, offset: Option<usize>) {
+        let offset = offset.map_or_else(|| " ".repeat(8), |offset| format!("{:#08x}", offset));
+        self.paint(out, DIM, &format!("{:>6}  {}", index, offset));
+        out.push_str("  ");
+    }
+
+    /// Formats a value decoded by postcard-dyn according to its schema
+    pub fn format_value(
+        &self,
+        out: &mut String,
+        metadata: &Metadata,
+        ty: &OwnedDataModelType,
+        value: &Value,
+    ) {
+        match (ty, value) {
+            (OwnedDataModelType::Option(_), Value::Null) => out.push_str("None"),
+            (OwnedDataModelType::Option(inner), value) => {
+                out.push_str("Some(");
+                self.format_value(out, metadata, inner, value);
+                out.push(')');
+            }
+            (OwnedDataModelType::Unit, _) => out.push_str("()"),
+            (OwnedDataModelType::Struct { name, data }, value) => {
+                self.paint(out, TYPE_NAME, name);
+                self.format_data(out, metadata, (name, None), data, value);
+            }
+            (OwnedDataModelType::Enum { name: ty, variants }, value) => {
+                let (name, inner) = match value {
+                    Value::String(name) => (name.as_str(), &Value::Null),
+                    Value::Object(map) if map.len() == 1 => {
+                        let (name, inner) = map.iter().next().unwrap();
+                        (name.as_str(), inner)
+                    }
+                    value => return self.format_fallback(out, value),
+                };
+                self.paint(out, VARIANT_NAME, name);
+                match variants.iter().find(|v| *v.name == *name) {
+                    Some(variant) if matches!(variant.data, OwnedData::Unit) => {
+                        if let Some(description) = metadata
+                            .variant(ty, name)
+                            .and_then(|v| v.description.as_deref())
+                        {
+                            self.paint(out, DIM, &format!(" /* {} */", description));
+                        }
+                    }
+                    Some(variant) => {
+                        self.format_data(out, metadata, (ty, Some(name)), &variant.data, inner)
+                    }
+                    None => {}
+                }
+            }
+            (OwnedDataModelType::Char | OwnedDataModelType::String, Value::String(s)) => {
+                self.paint(out, STRING, &format!("{:?}", s))
+            }
+            (OwnedDataModelType::ByteArray, Value::String(hex)) => {
+                self.paint(out, NUMBER, &format!("0x{}", hex))
+            }
+            (_, Value::Number(n)) => self.paint(out, NUMBER, &n.to_string()),
+            (OwnedDataModelType::Seq(inner), Value::Array(values)) => {
+                out.push('[');
+                self.format_list(out, metadata, values.iter().map(|v| (&**inner, v)));
+                out.push(']');
+            }
+            (OwnedDataModelType::Tuple(types), Value::Array(values)) => {
+                out.push('(');
+                self.format_list(out, metadata, types.iter().zip(values));
+                out.push(')');
+            }
+            (OwnedDataModelType::Map { key, val }, Value::Object(map)) => {
+                out.push('{');
+                for (i, (k, v)) in map.iter().enumerate() {
+                    if i > 0 {
+                        out.push_str(", ");
+                    }
+                    // Keys are stringified by postcard-dyn, only quote them if they are strings
+                    match **key {
+                        OwnedDataModelType::String | OwnedDataModelType::Char => {
+                            self.paint(out, STRING, &format!("{:?}", k))
+                        }
+                        _ => self.paint(out, NUMBER, k),
+                    }
+                    out.push_str(": ");
+                    self.format_value(out, metadata, val, v);
+                }
+                out.push('}');
+            }
+            (_, value) => self.format_fallback(out, value),
+        }
+    }
+
+    /// `owner` is the type and variant the data belongs to, which the metadata refers to
+    fn format_data(
+        &self,
+        out: &mut String,
+        metadata: &Metadata,
+        owner: (&str, Option<&str>),
+        data: &OwnedData,
+        value: &Value,
+    ) {
+        match (data, value) {
+            (OwnedData::Unit, _) => {}
+            (OwnedData::Newtype(inner), value) => {
+                out.push('(');
+                self.format_field(out, metadata, owner, "0", inner, value);
+                out.push(')');
+            }
+            (OwnedData::Tuple(types), Value::Array(values)) => {
+                out.push('(');
+                for (i, (ty, value)) in types.iter().zip(values).enumerate() {
+                    if i > 0 {
+                        out.push_str(", ");
+                    }
+                    self.format_field(out, metadata, owner, &i.to_string(), ty, value);
+                }
+                out.push(')');
+            }
+            (OwnedData::Struct(fields), Value::Object(map)) => {
+                self.format_fields(out, metadata, owner, fields, map)
+            }
+            (_, value) => {
+                out.push(' ');
+                self.format_fallback(out, value);
+            }
+        }
+    }
+
+    fn format_fields(
+        &self,
+        out: &mut String,
+        metadata: &Metadata,
+        owner: (&str, Option<&str>),
+        fields: &[OwnedNamedField],
+        map: &serde_json::Map<String, Value>,
+    ) {
+        if fields.is_empty() {
+            out.push_str(" {}");
+            return;
+        }
+        out.push_str(" { ");
+        for (i, field) in fields.iter().enumerate() {
+            if i > 0 {
+                out.push_str(", ");
+            }
+            let _ = write!(out, "{}: ", field.name);
+            match map.get(&*field.name) {
+                Some(v) => self.format_field(out, metadata, owner, &field.name, &field.ty, v),
+                None => out.push('?'),
+            }
+        }
+        out.push_str(" }");
+    }
+
+    /// Formats the value of a field in its display format, followed by its unit
+    fn format_field(
+        &self,
+        out: &mut String,
+        metadata: &Metadata,
+        (ty, variant): (&str, Option<&str>),
+        field: &str,
+        field_ty: &OwnedDataModelType,
+        value: &Value,
+    ) {
+        let annotation = metadata.field(ty, variant, field);
+        match annotation.and_then(FieldMetadata::display_format) {
+            Some(format) => self.format_integers(out, metadata, format, field_ty, value),
+            None => self.format_value(out, metadata, field_ty, value),
+        }
+        if let Some(unit) = annotation.and_then(|f| f.unit.as_deref()) {
+            out.push(' ');
+            out.push_str(unit);
+        }
+    }
+
+    /// Formats a value whose integers, including those in options, sequences and tuples, are
+    /// rendered in `format`
+    fn format_integers(
+        &self,
+        out: &mut String,
+        metadata: &Metadata,
+        format: DisplayFormat,
+        ty: &OwnedDataModelType,
+        value: &Value,
+    ) {
+        match (ty, value) {
+            (OwnedDataModelType::Option(inner), value) if !value.is_null() => {
+                out.push_str("Some(");
+                self.format_integers(out, metadata, format, inner, value);
+                out.push(')');
+            }
+            (OwnedDataModelType::Seq(inner), Value::Array(values)) => {
+                out.push('[');
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        out.push_str(", ");
+                    }
+                    self.format_integers(out, metadata, format, inner, value);
+                }
+                out.push(']');
+            }
+            (OwnedDataModelType::Tuple(types), Value::Array(values)) => {
+                out.push('(');
+                for (i, (ty, value)) in types.iter().zip(values).enumerate() {
+                    if i > 0 {
+                        out.push_str(", ");
+                    }
+                    self.format_integers(out, metadata, format, ty, value);
+                }
+                out.push(')');
+            }
+            (ty, value) => match format.format(value) {
+                Some(text) => self.paint(out, NUMBER, &text),
+                None => self.format_value(out, metadata, ty, value),
+            },
+        }
+    }
+
+    fn format_list<'a>(
+        &self,
+        out: &mut String,
+        metadata: &Metadata,
+        items: impl Iterator<Item = (&'a OwnedDataModelType, &'a Value)>,
+    ) {
+        for (i, (ty, value)) in items.enumerate() {
+            if i > 0 {
+                out.push_str(", ");
+            }
+            self.format_value(out, metadata, ty, value);
+        }
+    }
+
+    /// Used if the value does not match the schema (or the schema has no special rendering)
+    fn format_fallback(&self, out: &mut String, value: &Value) {
+        match value {
+            Value::String(s) => self.paint(out, STRING, &format!("{:?}", s)),
+            value => out.push_str(&value.to_string()),
+        }
+    }
+
+    fn paint(&self, out: &mut String, color: &str, text: &str) {
+        if self.color {
+            out.push_str(color);
+            out.push_str(text);
+            out.push_str(RESET);
+        } else {
+            out.push_str(text);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TypeMetadata;
+    use postcard_schema::schema::owned::OwnedVariant;
+    use serde_json::json;
+
+    #[test]
+    fn test_format_record() {
+        let sub = OwnedDataModelType::Struct {
+            name: "Sub".into(),
+            data: OwnedData::Struct(Box::new([
+                OwnedNamedField {
+                    name: "first_name".into(),
+                    ty: OwnedDataModelType::String,
+                },
+                OwnedNamedField {
+                    name: "age".into(),
+                    ty: OwnedDataModelType::U8,
+                },
+                OwnedNamedField {
+                    name: "nick".into(),
+                    ty: OwnedDataModelType::Option(Box::new(OwnedDataModelType::String)),
+                },
+            ])),
+        };
+        let schema = OwnedDataModelType::Enum {
+            name: "Record".into(),
+            variants: Box::new([
+                OwnedVariant {
+                    name: "Boot".into(),
+                    data: OwnedData::Unit,
+                },
+                OwnedVariant {
+                    name: "Sub".into(),
+                    data: OwnedData::Newtype(Box::new(sub)),
+                },
+            ]),
+        };
+        let record = DecodedRecord {
+            index: 3,
+            offset: Some(0x48),
+            schema_hash: [0; 8],
+            value: json!({"Sub": {"first_name": "Alice", "age": 20, "nick": null}}),
+        };
+
+        let printer = PrettyPrinter::new(false);
+        assert_eq!(
+            printer.format_record(&schema, &Metadata::default(), &record),
+            r#"     3  0x000048  Sub(Sub { first_name: "Alice", age: 20, nick: None })"#
+        );
+
+        let record = DecodedRecord {
+            value: json!("Boot"),
+            ..record
+        };
+        assert_eq!(
+            printer.format_record(&schema, &Metadata::default(), &record),
+            "     3  0x000048  Boot"
+        );
+    }
+
+    #[test]
+    fn test_format_defmt() {
+        let frame = DefmtRecord {
+            index: 12,
+            offset: None,
+            schema_hash: [0; 8],
+            level: Some("warn".to_string()),
+            timestamp: Some("1.250000".to_string()),
+            message: "battery low: 3300 mV".to_string(),
+        };
+        let printer = PrettyPrinter::new(false);
+        assert_eq!(
+            printer.format_defmt(&frame),
+            "    12            1.250000 WARN  battery low: 3300 mV"
+        );
+        let frame = DefmtRecord {
+            level: None,
+            timestamp: None,
+            ..frame
+        };
+        assert_eq!(
+            printer.format_defmt(&frame),
+            "    12            battery low: 3300 mV"
+        );
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        let reading = OwnedDataModelType::Struct {
+            name: "Reading".into(),
+            data: OwnedData::Struct(Box::new([
+                OwnedNamedField {
+                    name: "timestamp".into(),
+                    ty: OwnedDataModelType::U32,
+                },
+                OwnedNamedField {
+                    name: "value".into(),
+                    ty: OwnedDataModelType::I16,
+                },
+            ])),
+        };
+        let schema = OwnedDataModelType::Enum {
+            name: "Record".into(),
+            variants: Box::new([
+                OwnedVariant {
+                    name: "Boot".into(),
+                    data: OwnedData::Unit,
+                },
+                OwnedVariant {
+                    name: "Reading".into(),
+                    data: OwnedData::Newtype(Box::new(reading)),
+                },
+            ]),
+        };
+        let metadata = Metadata {
+            types: vec![TypeMetadata {
+                name: "Reading".to_string(),
+                schema_hash: [0; 8],
+                fields: vec![FieldMetadata {
+                    variant: None,
+                    field: Some("timestamp".to_string()),
+                    unit: Some("ms".to_string()),
+                    scale: None,
+                    format: None,
+                    description: None,
+                }],
+            }],
+        };
+        let record = DecodedRecord {
+            index: 7,
+            offset: Some(0x1f0),
+            schema_hash: [0; 8],
+            value: json!({"Reading": {"timestamp": 1500, "value": -4}}),
+        };
+
+        let printer = PrettyPrinter::new(false);
+        assert_eq!(
+            printer.format_record(&schema, &metadata, &record),
+            "     7  0x0001f0  1500 ms Reading(Reading { timestamp: 1500 ms, value: -4 })"
+        );
+        let record = DecodedRecord {
+            value: json!("Boot"),
+            ..record
+        };
+        assert_eq!(
+            printer.format_record(&schema, &metadata, &record),
+            "     7  0x0001f0  Boot"
+        );
+    }
+
+    #[test]
+    fn test_format_metadata() {
+        let schema = OwnedDataModelType::Enum {
+            name: "Record".into(),
+            variants: Box::new([
+                OwnedVariant {
+                    name: "Boot".into(),
+                    data: OwnedData::Unit,
+                },
+                OwnedVariant {
+                    name: "Temperature".into(),
+                    data: OwnedData::Newtype(Box::new(OwnedDataModelType::I16)),
+                },
+                OwnedVariant {
+                    name: "Status".into(),
+                    data: OwnedData::Tuple(Box::new([
+                        OwnedDataModelType::U16,
+                        OwnedDataModelType::Seq(Box::new(OwnedDataModelType::U8)),
+                    ])),
+                },
+            ]),
+        };
+        let annotation = |variant: &str, field: Option<&str>| FieldMetadata {
+            variant: Some(variant.to_string()),
+            field: field.map(str::to_string),
+            unit: None,
+            scale: None,
+            format: None,
+            description: None,
+        };
+        let metadata = Metadata {
+            types: vec![TypeMetadata {
+                name: "Record".to_string(),
+                schema_hash: [0; 8],
+                fields: vec![
+                    FieldMetadata {
+                        description: Some("Written once after reset".to_string()),
+                        ..annotation("Boot", None)
+                    },
+                    FieldMetadata {
+                        unit: Some("°C".to_string()),
+                        scale: Some(0.01),
+                        ..annotation("Temperature", Some("0"))
+                    },
+                    FieldMetadata {
+                        format: Some("bin".to_string()),
+                        ..annotation("Status", Some("0"))
+                    },
+                    FieldMetadata {
+                        format: Some("hex".to_string()),
+                        ..annotation("Status", Some("1"))
+                    },
+                ],
+            }],
+        };
+        let record = DecodedRecord {
+            index: 1,
+            offset: None,
+            schema_hash: [0; 8],
+            value: metadata.apply_scale(&schema, json!({"Temperature": 2315})),
+        };
+
+        let printer = PrettyPrinter::new(false);
+        assert_eq!(
+            printer.format_record(&schema, &metadata, &record),
+            "     1            Temperature(23.15 °C)"
+        );
+        let record = DecodedRecord {
+            value: json!("Boot"),
+            ..record
+        };
+        assert_eq!(
+            printer.format_record(&schema, &metadata, &record),
+            "     1            Boot /* Written once after reset */"
+        );
+        let record = DecodedRecord {
+            value: json!({"Status": [5, [170, 11]]}),
+            ..record
+        };
+        assert_eq!(
+            printer.format_record(&schema, &metadata, &record),
+            "     1            Status(0b101, [0xaa, 0xb])"
+        );
+    }
+}
@@ -1,18 +1,33 @@
 use crate::flash_utils::FlashVec;
-use crate::Cache;
+use crate::{builtin_schema, item_offsets, DefmtRecord, Metadata, SchemaSource};
 use anyhow::{anyhow, bail};
 use futures::executor::block_on;
 use log::info;
-use postcard_dyn::from_slice_dyn;
+use postcard_dyn::{from_slice_dyn, Value};
+use postcard_schema::schema::owned::{OwnedData, OwnedDataModelType};
 use sequential_storage::cache::NoCache;
-use std::ops::Deref;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
 
+const ID_DATA: u8 = 0x00;
 const ID_SCHEMA: u8 = 0xFF;
+const ID_DEFMT: u8 = 0xFE;
+/// Appended to the schema entry by firmware that prefixes the data entries with their kind
+const FORMAT_TAGGED: u8 = 0x01;
 
-pub fn unpack_partition(partition: &mut [u8]) -> anyhow::Result<()> {
-    let mut schema_cache = Cache::new();
-    info!("partition size: {}", partition.len());
+/// A raw entry of the partition, as pushed by `destore::Storer`
+pub enum PartitionEntry {
+    /// Written by `Storer::new`: all following data entries use the schema with this hash.
+    /// Firmware before defmt support wrote it without format, the data entries after it have no kind byte.
+    Schema([u8; 8]),
+    /// A postcard encoded record
+    Data(Vec<u8>),
+    /// A raw defmt log frame, written by `Storer::write_defmt_frame`
+    Defmt(Vec<u8>),
+}
 
+/// Reads all entries of the partition in the order they were written
+pub fn read_entries(partition: &mut [u8]) -> anyhow::Result<Vec<PartitionEntry>> {
     let range = 0..partition.len() as u32;
     let mut flash = FlashVec(partition);
     let mut cache = NoCache::new();
@@ -20,40 +35,384 @@ pub fn unpack_partition(partition: &mut [u8]) -> anyhow::Result<()> {
         &mut flash, range, &mut cache,
     ))?;
     let mut buf = [0; 1024];
-    let mut schema = None;
-    loop {
-        if let Some(entry) = block_on(it.next(&mut buf))
-            .map_err(|_| anyhow!("Failed to fetch next batch of logs"))?
-        {
-            if entry.len() == 0 {
-                bail!("Empty entry");
-            }
-            if entry[0] == ID_SCHEMA {
-                let hash: &[u8; 8] = &entry[1..].try_into()?;
-                info!(
-                    "Schema entry: {}",
-                    hash.iter()
-                        .map(|b| format!("{:02x}", b))
-                        .collect::<String>()
-                );
-                if let Some(s) = schema_cache.lookup(hash)? {
-                    schema = Some(s);
-                } else {
-                    bail!("Schema not found: {:?}", hash);
+    let mut entries = Vec::new();
+    let mut tagged = true;
+    while let Some(entry) =
+        block_on(it.next(&mut buf)).map_err(|_| anyhow!("Failed to fetch next batch of logs"))?
+    {
+        let parsed = PartitionEntry::parse(&entry, tagged)?;
+        if let PartitionEntry::Schema(_) = parsed {
+            // Only schema entries of the tagged format carry the format byte after the hash
+            tagged = entry.len() == 1 + 8 + 1;
+        }
+        entries.push(parsed);
+    }
+    Ok(entries)
+}
+
+impl PartitionEntry {
+    /// Splits an entry into its kind, the first byte, and its contents.
+    ///
+    /// `tagged` is false after a schema entry of old firmware, whose data entries are the bare record.
+    pub fn parse(entry: &[u8], tagged: bool) -> anyhow::Result<Self> {
+        let Some((&kind, contents)) = entry.split_first() else {
+            bail!("Empty entry");
+        };
+        Ok(match kind {
+            ID_SCHEMA => match contents {
+                [hash @ .., FORMAT_TAGGED] if hash.len() == 8 => {
+                    PartitionEntry::Schema(hash.try_into()?)
+                }
+                hash => PartitionEntry::Schema(hash.try_into()?),
+            },
+            _ if !tagged => PartitionEntry::Data(entry.to_vec()),
+            ID_DATA => PartitionEntry::Data(contents.to_vec()),
+            ID_DEFMT => PartitionEntry::Defmt(contents.to_vec()),
+            kind => bail!("Unknown entry kind {:#04x}", kind),
+        })
+    }
+}
+
+/// A data entry of the partition, decoded with the schema of the preceding schema entry
+pub struct DecodedRecord {
+    /// Position of the entry in the partition (schema entries included)
+    pub index: usize,
+    /// Offset of the item in the partition, if the page layout could be followed
+    pub offset: Option<usize>,
+    /// Hash of the schema the record was decoded with
+    pub schema_hash: [u8; 8],
+    pub value: Value,
+}
+
+/// All records of a partition together with the schemas needed to interpret them.
+/// Values of fields with a scale in the metadata are already scaled.
+pub struct DecodedPartition {
+    pub schemas: HashMap<[u8; 8], OwnedDataModelType>,
+    pub metadata: HashMap<[u8; 8], Metadata>,
+    pub records: Vec<DecodedRecord>,
+    /// Defmt log frames, ordered by index like the records
+    pub defmt: Vec<DefmtRecord>,
+}
+
+impl DecodedPartition {
+    /// Keeps the last `last` records and defmt frames, counted together
+    pub fn retain_last(&mut self, last: usize) {
+        let mut indices: Vec<_> = self
+            .records
+            .iter()
+            .map(|r| r.index)
+            .chain(self.defmt.iter().map(|f| f.index))
+            .collect();
+        indices.sort_unstable();
+        let first = match indices.len().checked_sub(last) {
+            Some(skip) => indices.get(skip).copied().unwrap_or(usize::MAX),
+            None => 0,
+        };
+        self.records.retain(|r| r.index >= first);
+        self.defmt.retain(|f| f.index >= first);
+    }
+}
+
+/// Decodes all records of a partition, looking up the schemas in `schemas`
+pub fn unpack_partition(
+    partition: &mut [u8],
+    schemas: &dyn SchemaSource,
+) -> anyhow::Result<DecodedPartition> {
+    info!("partition size: {}", partition.len());
+
+    let mut decoded = DecodedPartition {
+        schemas: HashMap::new(),
+        metadata: HashMap::new(),
+        records: Vec::new(),
+        defmt: Vec::new(),
+    };
+    let mut defmt_tables = HashMap::new();
+    let mut schema_hash = None;
+    let offsets = item_offsets(partition);
+    let entries = read_entries(partition)?;
+    // Only trusted if the page walk found the same items as sequential-storage
+    let offsets = (offsets.len() == entries.len()).then_some(offsets);
+    for (index, entry) in entries.into_iter().enumerate() {
+        let offset = offsets.as_ref().map(|offsets| offsets[index]);
+        match entry {
+            PartitionEntry::Schema(hash) => {
+                info!("Schema entry: {}", format_hash(&hash));
+                if let Entry::Vacant(e) = decoded.schemas.entry(hash) {
+                    if let Some(s) = schemas.lookup(&hash)?.or_else(|| builtin_schema(&hash)) {
+                        e.insert(s);
+                    } else {
+                        bail!("Schema not found: {}", format_hash(&hash));
+                    }
+                    decoded
+                        .metadata
+                        .insert(hash, schemas.lookup_metadata(&hash)?);
                 }
-            } else {
-                if let Some(schema) = schema.as_ref() {
-                    let value = from_slice_dyn(&schema, entry.deref())
-                        .map_err(|e| anyhow!("Failed to decode entry: {:?}", e))?;
-                    info!("Data entry: {:?}", value);
-                } else {
+                schema_hash = Some(hash);
+            }
+            PartitionEntry::Data(data) => {
+                let Some(hash) = schema_hash else {
                     bail!("Cannot decode data entry without schema");
+                };
+                let schema = &decoded.schemas[&hash];
+                let value =
+                    decoded.metadata[&hash].apply_scale(schema, decode_value(schema, &data)?);
+                decoded.records.push(DecodedRecord {
+                    index,
+                    offset,
+                    schema_hash: hash,
+                    value,
+                });
+            }
+            PartitionEntry::Defmt(frame) => {
+                let Some(hash) = schema_hash else {
+                    bail!("Cannot decode defmt frame without schema");
+                };
+                // Loaded with the first frame, partitions without frames need no table
+                let table = match defmt_tables.entry(hash) {
+                    Entry::Occupied(e) => e.into_mut(),
+                    Entry::Vacant(e) => e.insert(schemas.defmt_table(&hash)?),
+                };
+                let Some(table) = table else {
+                    bail!(
+                        "No defmt table for the firmware of schema {}, flash it with `destore proxy` or pass --elf",
+                        format_hash(&hash)
+                    );
+                };
+                decoded
+                    .defmt
+                    .push(DefmtRecord::decode(table, index, offset, hash, &frame)?);
+            }
+        }
+    }
+
+    Ok(decoded)
+}
+
+/// Decodes a data entry with its schema.
+///
+/// postcard-dyn yields byte arrays as arrays of numbers, they are replaced by hex strings.
+pub fn decode_value(schema: &OwnedDataModelType, data: &[u8]) -> anyhow::Result<Value> {
+    let mut value =
+        from_slice_dyn(schema, data).map_err(|e| anyhow!("Failed to decode entry: {:?}", e))?;
+    hex_byte_arrays(schema, &mut value);
+    Ok(value)
+}
+
+fn hex_byte_arrays(ty: &OwnedDataModelType, value: &mut Value) {
+    match ty {
+        OwnedDataModelType::ByteArray => {
+            let bytes = value.as_array().and_then(|values| {
+                values
+                    .iter()
+                    .map(|v| v.as_u64().and_then(|b| u8::try_from(b).ok()))
+                    .collect::<Option<Vec<_>>>()
+            });
+            if let Some(bytes) = bytes {
+                *value = Value::String(format_hex(&bytes));
+            }
+        }
+        OwnedDataModelType::Option(inner) => hex_byte_arrays(inner, value),
+        OwnedDataModelType::Seq(inner) => {
+            if let Value::Array(values) = value {
+                values.iter_mut().for_each(|v| hex_byte_arrays(inner, v));
+            }
+        }
+        OwnedDataModelType::Tuple(types) => hex_tuple(types, value),
+        OwnedDataModelType::Map { val, .. } => {
+            if let Value::Object(map) = value {
+                map.values_mut().for_each(|v| hex_byte_arrays(val, v));
+            }
+        }
+        OwnedDataModelType::Struct { data, .. } => hex_data(data, value),
+        OwnedDataModelType::Enum { variants, .. } => {
+            if let Value::Object(map) = value {
+                for (name, inner) in map.iter_mut() {
+                    if let Some(variant) = variants.iter().find(|v| *v.name == **name) {
+                        hex_data(&variant.data, inner);
+                    }
                 }
             }
-        } else {
-            break;
         }
+        _ => {}
+    }
+}
+
+fn hex_data(data: &OwnedData, value: &mut Value) {
+    match data {
+        OwnedData::Unit => {}
+        OwnedData::Newtype(inner) => hex_byte_arrays(inner, value),
+        OwnedData::Tuple(types) => hex_tuple(types, value),
+        OwnedData::Struct(fields) => {
+            if let Value::Object(map) = value {
+                for field in fields.iter() {
+                    if let Some(v) = map.get_mut(&*field.name) {
+                        hex_byte_arrays(&field.ty, v);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn hex_tuple(types: &[OwnedDataModelType], value: &mut Value) {
+    if let Value::Array(values) = value {
+        types
+            .iter()
+            .zip(values.iter_mut())
+            .for_each(|(ty, v)| hex_byte_arrays(ty, v));
+    }
+}
+
+/// Formats bytes as lowercase hex without separators
+pub fn format_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Formats a schema hash the same way as the file names in the cache
+pub fn format_hash(hash: &[u8; 8]) -> String {
+    format_hex(hash)
+}
+
+/// Parses a schema hash formatted with [`format_hash`]
+pub fn parse_hash(hex: &str) -> Option<[u8; 8]> {
+    parse_hex(hex)?.try_into().ok()
+}
+
+/// Parses hex without separators, as written by [`format_hex`]
+pub fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use postcard_schema::schema::owned::OwnedNamedField;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_entry() {
+        // The length prefix of a string of 254 bytes is the kind of a defmt frame
+        let record = postcard::to_allocvec(&"x".repeat(254)).unwrap();
+        assert_eq!(record[0], ID_DEFMT);
+        let entry = [&[ID_DATA], &record[..]].concat();
+        assert!(
+            matches!(PartitionEntry::parse(&entry, true).unwrap(), PartitionEntry::Data(data) if data == record)
+        );
+
+        let entry = [&[ID_DEFMT], &record[..]].concat();
+        assert!(
+            matches!(PartitionEntry::parse(&entry, true).unwrap(), PartitionEntry::Defmt(frame) if frame == record)
+        );
+        let untagged = [ID_SCHEMA, 1, 2, 3, 4, 5, 6, 7, 8];
+        let tagged = [ID_SCHEMA, 1, 2, 3, 4, 5, 6, 7, 8, FORMAT_TAGGED];
+        for entry in [&untagged[..], &tagged[..]] {
+            for tagged in [true, false] {
+                assert!(matches!(
+                    PartitionEntry::parse(entry, tagged).unwrap(),
+                    PartitionEntry::Schema([1, 2, 3, 4, 5, 6, 7, 8])
+                ));
+            }
+        }
+        assert!(PartitionEntry::parse(&[ID_SCHEMA, 1], true).is_err());
+        assert!(PartitionEntry::parse(&[ID_SCHEMA, 1, 2, 3, 4, 5, 6, 7, 8, 2], true).is_err());
+        assert!(PartitionEntry::parse(&[0x01, 1], true).is_err());
+        assert!(PartitionEntry::parse(&[], true).is_err());
     }
 
-    Ok(())
+    #[test]
+    fn test_parse_untagged_entry() {
+        // Old firmware stored the bare record, whatever its first byte
+        for record in [
+            vec![0x01, 1],
+            vec![ID_DATA, 2],
+            vec![ID_DEFMT, 3],
+            // Only 0xFF starts a schema entry of an untagged partition
+            vec![0xFD, 1, 2, 3, 4, 5, 6, 7, 8],
+        ] {
+            assert!(
+                matches!(PartitionEntry::parse(&record, false).unwrap(), PartitionEntry::Data(data) if data == record)
+            );
+        }
+    }
+
+    #[test]
+    fn test_retain_last() {
+        let partition = || {
+            let record = |index| DecodedRecord {
+                index,
+                offset: None,
+                schema_hash: [0; 8],
+                value: json!(index),
+            };
+            let frame = |index| DefmtRecord {
+                index,
+                offset: None,
+                schema_hash: [0; 8],
+                level: None,
+                timestamp: None,
+                message: String::new(),
+            };
+            DecodedPartition {
+                schemas: HashMap::new(),
+                metadata: HashMap::new(),
+                records: vec![record(1), record(2), record(4)],
+                defmt: vec![frame(3), frame(5)],
+            }
+        };
+        let indices = |decoded: &DecodedPartition| {
+            let records: Vec<_> = decoded.records.iter().map(|r| r.index).collect();
+            let frames: Vec<_> = decoded.defmt.iter().map(|f| f.index).collect();
+            (records, frames)
+        };
+
+        let mut decoded = partition();
+        decoded.retain_last(0);
+        assert_eq!(indices(&decoded), (vec![], vec![]));
+        let mut decoded = partition();
+        decoded.retain_last(3);
+        assert_eq!(indices(&decoded), (vec![4], vec![3, 5]));
+        let mut decoded = partition();
+        decoded.retain_last(10);
+        assert_eq!(indices(&decoded), (vec![1, 2, 4], vec![3, 5]));
+    }
+
+    #[test]
+    fn test_hex_byte_arrays() {
+        let schema = OwnedDataModelType::Struct {
+            name: "Blob".into(),
+            data: OwnedData::Struct(
+                vec![
+                    OwnedNamedField {
+                        name: "len".into(),
+                        ty: OwnedDataModelType::Usize,
+                    },
+                    OwnedNamedField {
+                        name: "data".into(),
+                        ty: OwnedDataModelType::ByteArray,
+                    },
+                    OwnedNamedField {
+                        name: "seq".into(),
+                        ty: OwnedDataModelType::Seq(Box::new(OwnedDataModelType::U8)),
+                    },
+                    OwnedNamedField {
+                        name: "extra".into(),
+                        ty: OwnedDataModelType::Option(Box::new(OwnedDataModelType::ByteArray)),
+                    },
+                ]
+                .into(),
+            ),
+        };
+        let mut value = json!({"len": 3, "data": [0xde, 0xad, 0x0f], "seq": [1, 2], "extra": null});
+        hex_byte_arrays(&schema, &mut value);
+        assert_eq!(
+            value,
+            json!({"len": 3, "data": "dead0f", "seq": [1, 2], "extra": null})
+        );
+    }
 }
@@ -1,30 +1,77 @@
-use anyhow::{Context, Result};
+use crate::type_layout::SchemaLayout;
+use crate::{format_hex, LogDescriptor, TypeMetadata, LOG_SYMBOL_PREFIX, METADATA_SYMBOL_PREFIX};
+use anyhow::{bail, Context, Result};
+use defmt_decoder::Table;
+use goblin::elf::note::NT_GNU_BUILD_ID;
+use goblin::elf::program_header::PT_LOAD;
+use goblin::elf::section_header::{SHF_ALLOC, SHT_NOBITS};
+use goblin::elf::sym::STT_OBJECT;
 use goblin::elf::{Elf, SectionHeader, Sym};
 use log::debug;
-use memmap2::Mmap;
 use postcard_schema::schema::owned::{
     OwnedData, OwnedDataModelType, OwnedNamedField, OwnedVariant,
 };
-use std::{fs::File, path::Path};
+use std::cell::{Cell, OnceCell};
+use std::path::Path;
+
+/// Maximum nesting of the types of a schema. Protects against stack overflows caused by
+/// cyclic pointers or maliciously deep schemas.
+const MAX_DEPTH: usize = 64;
+
+/// Maximum number of types decoded for a schema. Bounds the work on types shared by many
+/// pointers, which the depth limit alone does not.
+const MAX_NODES: usize = 100_000;
+
+/// `offset + delta` for offsets read from the ELF, an error instead of an overflow
+fn add_offset(offset: usize, delta: usize) -> Result<usize> {
+    offset
+        .checked_add(delta)
+        .with_context(|| format!("Offset {:#x} + {:#x} overflows", offset, delta))
+}
 
-// `RUSTFLAGS=-Zprint-type-sizes  cargo +nightly build > out.txt` helped a lot to understand the layout of the types
+/// Tries to recover a postcard schema from an ELF file.
+///
+/// Every read is bounds checked, so a truncated or malformed ELF results in an error, not a panic.
+pub struct SchemaRestorer<'a> {
+    elf: Elf<'a>,
+    bytes: &'a [u8],
+    layout: OnceCell<SchemaLayout>,
+    /// Types decoded for the schema being restored, see [`MAX_NODES`]
+    nodes: Cell<usize>,
+}
 
-/// Tries to recover a postcard schema from an ELF file
-pub struct SchemaRestorer {
-    // Use a reference to the mmap data for the Elf
-    elf: Elf<'static>,
-    mmap: Mmap,
+/// An ELF file read into memory, owning the bytes a [`SchemaRestorer`] borrows
+pub struct ElfFile {
+    bytes: Vec<u8>,
 }
 
-impl SchemaRestorer {
-    pub fn from_path(path: impl AsRef<Path>) -> Result<Self> {
-        //TODO: remove unsafe trickery
-        let file = File::open(path)?;
-        let mmap = unsafe { Mmap::map(&file)? };
-        let mmap_slice: &'static [u8] = unsafe { std::mem::transmute(mmap.as_ref()) };
-        let elf = Elf::parse(mmap_slice).context("Failed to parse ELF file")?;
+impl ElfFile {
+    pub fn read(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+        Ok(Self { bytes })
+    }
 
-        Ok(Self { elf, mmap })
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Parses the ELF, the restorer borrows the bytes of the file
+    pub fn restorer(&self) -> Result<SchemaRestorer<'_>> {
+        SchemaRestorer::from_bytes(&self.bytes)
+    }
+}
+
+impl<'a> SchemaRestorer<'a> {
+    /// Parses an ELF file held in memory, e.g. read with `std::fs::read`
+    pub fn from_bytes(bytes: &'a [u8]) -> Result<Self> {
+        let elf = Elf::parse(bytes).context("Failed to parse ELF file")?;
+        Ok(Self {
+            elf,
+            bytes,
+            layout: OnceCell::new(),
+            nodes: Cell::new(0),
+        })
     }
 
     fn find_symbol(&self, symbol: &str) -> Result<Sym> {
@@ -51,300 +98,812 @@ impl SchemaRestorer {
     // Convert a section-relative address to file offset
     fn section_addr_to_offset(&self, section_idx: usize, addr: u64) -> Result<usize> {
         let section = self.find_section_by_index(section_idx)?;
-        let offset = section.sh_offset + addr - section.sh_addr;
-        Ok(offset as usize)
+        if section.sh_type == SHT_NOBITS {
+            bail!("Address {:#x} lies in a section without data", addr);
+        }
+        addr.checked_sub(section.sh_addr)
+            .filter(|relative| *relative <= section.sh_size)
+            .and_then(|relative| section.sh_offset.checked_add(relative))
+            .and_then(|offset| usize::try_from(offset).ok())
+            .with_context(|| format!("Address {:#x} outside of section {}", addr, section_idx))
     }
 
-    fn read_u32_at(&self, offset: usize) -> Result<usize> {
-        let bytes = self
-            .mmap
-            .get(offset..offset + 4)
-            .context("Failed to read u32: out of bounds")?;
-        Ok(u32::from_le_bytes(bytes.try_into()?) as usize)
+    /// File offset of the data of a symbol. The exported symbols live in sections which are not
+    /// loaded with `destore.x`, so the section is tried before the program headers.
+    fn symbol_offset(&self, sym: &Sym) -> Result<usize> {
+        self.section_addr_to_offset(sym.st_shndx, sym.st_value)
+            .or_else(|e| self.addr_to_offset(sym.st_value).map_err(|_| e))
     }
 
-    // This function resolves a pointer in the ELF file, considering section boundaries
-    fn read_pointer_at(&self, offset: usize) -> Result<usize> {
-        let addr = self.read_u32_at(offset)? as u64;
-        debug!(
-            "Resolving pointer at offset {:#x} to addr {:#x}",
-            offset, addr
-        );
+    /// Translates a virtual address, e.g. the value of a pointer, to a file offset.
+    ///
+    /// The `PT_LOAD` program headers describe the memory image even of stripped ELFs without
+    /// section table. Sections are only used as a fallback for addresses outside of any segment.
+    fn addr_to_offset(&self, addr: u64) -> Result<usize> {
+        let segment = self.elf.program_headers.iter().find(|ph| {
+            ph.p_type == PT_LOAD
+                && addr
+                    .checked_sub(ph.p_vaddr)
+                    .is_some_and(|relative| relative < ph.p_filesz)
+        });
+        if let Some(segment) = segment {
+            debug!(
+                "Resolving address {:#x} in segment {:#x}..{:#x}",
+                addr,
+                segment.p_vaddr,
+                segment.p_vaddr.saturating_add(segment.p_filesz)
+            );
+            return segment
+                .p_offset
+                .checked_add(addr - segment.p_vaddr)
+                .and_then(|offset| usize::try_from(offset).ok())
+                .with_context(|| format!("Address {:#x} outside of the file", addr));
+        }
 
-        // Find the section containing this address
-        for (idx, section) in self.elf.section_headers.iter().enumerate() {
-            let section_start = section.sh_addr;
-            let section_end = section_start + section.sh_size;
-            let section_name = self.elf.shdr_strtab.get_at(section.sh_name).unwrap_or("");
-            if addr >= section_start && addr < section_end {
+        // Only allocated sections have an address, the others start at 0
+        let section = self.elf.section_headers.iter().position(|section| {
+            section.sh_flags & u64::from(SHF_ALLOC) != 0
+                && addr
+                    .checked_sub(section.sh_addr)
+                    .is_some_and(|relative| relative < section.sh_size)
+        });
+        match section {
+            Some(idx) => {
                 debug!(
-                    "Resolving offset {:#x} to pointer at address {:#x} section {} ({})",
-                    offset, addr, section_name, idx
+                    "Resolving address {:#x} in section {}",
+                    addr,
+                    self.elf
+                        .shdr_strtab
+                        .get_at(self.elf.section_headers[idx].sh_name)
+                        .unwrap_or("")
                 );
-                // Convert virtual address to file offset
-                return self.section_addr_to_offset(idx, addr);
+                self.section_addr_to_offset(idx, addr)
             }
+            None => bail!("Could not resolve pointer address {:#x}", addr),
         }
-
-        Err(anyhow::anyhow!(
-            "Could not resolve pointer address {:#x}",
-            addr
-        ))
     }
 
-    fn decode_data_model_type(&self, offset: usize) -> Result<OwnedDataModelType> {
-        let type_tag = self.mmap[offset];
-        debug!("Decoding type tag {} at offset {:#x}", type_tag, offset);
-
-        // The discriminant of the enum DataModelType is a bit special:
-        // It starts at 4, then follows the variant order as defined in the enum
-        // The struct variant contains another enum with 4 variants, so the struct variant is left away, and mapped to discriminator 0..4
-
-        // Here we follow the order of the enum DataModelType as defined in the source code
-        match type_tag {
-            // 1. Bool
-            4 => Ok(OwnedDataModelType::Bool),
-
-            // 2. I8
-            5 => Ok(OwnedDataModelType::I8),
-
-            // 3. U8
-            6 => Ok(OwnedDataModelType::U8),
-
-            // 4. I16
-            7 => Ok(OwnedDataModelType::I16),
-
-            // 5. I32
-            8 => Ok(OwnedDataModelType::I32),
-
-            // 6. I64
-            9 => Ok(OwnedDataModelType::I64),
-
-            // 7. I128
-            10 => Ok(OwnedDataModelType::I128),
-
-            // 8. U16
-            11 => Ok(OwnedDataModelType::U16),
-
-            // 9. U32
-            12 => Ok(OwnedDataModelType::U32),
-
-            // 10. U64
-            13 => Ok(OwnedDataModelType::U64),
-
-            // 11. U128
-            14 => Ok(OwnedDataModelType::U128),
-
-            // 12. Usize
-            15 => Ok(OwnedDataModelType::Usize),
+    /// The bytes of the file in `offset..offset + len`
+    fn read_bytes(&self, offset: usize, len: usize) -> Result<&'a [u8]> {
+        offset
+            .checked_add(len)
+            .and_then(|end| self.bytes.get(offset..end))
+            .with_context(|| {
+                format!(
+                    "Failed to read {} bytes at {:#x}: out of bounds",
+                    len, offset
+                )
+            })
+    }
 
-            // 13. Isize
-            16 => Ok(OwnedDataModelType::Isize),
+    fn read_u32_at(&self, offset: usize) -> Result<usize> {
+        Ok(self.read_uint_at(offset, 4)? as usize)
+    }
 
-            // 14. F32
-            17 => Ok(OwnedDataModelType::F32),
+    /// Reads an unsigned integer of `size` bytes in the byte order of the target
+    fn read_uint_at(&self, offset: usize, size: usize) -> Result<u64> {
+        let bytes = self.read_bytes(offset, size)?;
+        let mut buf = [0; 8];
+        if self.elf.little_endian {
+            buf[..size].copy_from_slice(bytes);
+            Ok(u64::from_le_bytes(buf))
+        } else {
+            buf[8 - size..].copy_from_slice(bytes);
+            Ok(u64::from_be_bytes(buf))
+        }
+    }
 
-            // 15. F64
-            18 => Ok(OwnedDataModelType::F64),
+    /// Size of pointers and `usize` on the target
+    fn pointer_size(&self) -> usize {
+        if self.elf.is_64 {
+            8
+        } else {
+            4
+        }
+    }
 
-            // 16. Char
-            19 => Ok(OwnedDataModelType::Char),
+    fn read_usize_at(&self, offset: usize) -> Result<usize> {
+        Ok(self.read_uint_at(offset, self.pointer_size())? as usize)
+    }
 
-            // 17. String
-            20 => Ok(OwnedDataModelType::String),
+    /// Reads the pointer at `offset` and returns the file offset it points to
+    fn read_pointer_at(&self, offset: usize) -> Result<usize> {
+        let addr = self.read_uint_at(offset, self.pointer_size())?;
+        debug!(
+            "Resolving pointer at offset {:#x} to addr {:#x}",
+            offset, addr
+        );
+        self.addr_to_offset(addr)
+    }
 
-            // 18. ByteArray
-            21 => Ok(OwnedDataModelType::ByteArray),
+    /// The layout of the schema types, read from the debug info on first use
+    fn layout(&self) -> Result<&SchemaLayout> {
+        if let Some(layout) = self.layout.get() {
+            return Ok(layout);
+        }
+        let layout = SchemaLayout::from_elf(&self.elf, self.bytes)
+            .context("Failed to read the layout of the schema types from the debug info")?;
+        debug!("Schema type layout: {:?}", layout);
+        Ok(self.layout.get_or_init(|| layout))
+    }
 
-            // 19. Option
-            22 => {
-                let inner = self.decode_data_model_type(self.read_pointer_at(offset + 4)?)?;
+    fn decode_data_model_type(
+        &self,
+        layout: &SchemaLayout,
+        offset: usize,
+        depth: usize,
+    ) -> Result<OwnedDataModelType> {
+        if depth > MAX_DEPTH {
+            bail!("Schema nested deeper than {} levels", MAX_DEPTH);
+        }
+        self.nodes.set(self.nodes.get() + 1);
+        if self.nodes.get() > MAX_NODES {
+            bail!("Schema has more than {} types", MAX_NODES);
+        }
+        let variant = layout.data_model_type.variant(self.read_uint_at(
+            add_offset(offset, layout.data_model_type.discr_offset)?,
+            layout.data_model_type.discr_size,
+        )?)?;
+        debug!("Decoding {} at offset {:#x}", variant.name, offset);
+        let field = |name| add_offset(offset, variant.fields.field(name)?);
+
+        match variant.name.as_str() {
+            "Bool" => Ok(OwnedDataModelType::Bool),
+            "I8" => Ok(OwnedDataModelType::I8),
+            "U8" => Ok(OwnedDataModelType::U8),
+            "I16" => Ok(OwnedDataModelType::I16),
+            "I32" => Ok(OwnedDataModelType::I32),
+            "I64" => Ok(OwnedDataModelType::I64),
+            "I128" => Ok(OwnedDataModelType::I128),
+            "U16" => Ok(OwnedDataModelType::U16),
+            "U32" => Ok(OwnedDataModelType::U32),
+            "U64" => Ok(OwnedDataModelType::U64),
+            "U128" => Ok(OwnedDataModelType::U128),
+            "Usize" => Ok(OwnedDataModelType::Usize),
+            "Isize" => Ok(OwnedDataModelType::Isize),
+            "F32" => Ok(OwnedDataModelType::F32),
+            "F64" => Ok(OwnedDataModelType::F64),
+            "Char" => Ok(OwnedDataModelType::Char),
+            "String" => Ok(OwnedDataModelType::String),
+            "ByteArray" => Ok(OwnedDataModelType::ByteArray),
+            "Option" => {
+                let inner = self.decode_pointee(layout, field("__0")?, depth + 1)?;
                 Ok(OwnedDataModelType::Option(Box::new(inner)))
             }
-
-            // 20. Unit
-            23 => Ok(OwnedDataModelType::Unit),
-
-            // 21. Seq
-            24 => {
-                let inner = self.decode_data_model_type(self.read_pointer_at(offset + 4)?)?;
+            "Unit" => Ok(OwnedDataModelType::Unit),
+            "Seq" => {
+                let inner = self.decode_pointee(layout, field("__0")?, depth + 1)?;
                 Ok(OwnedDataModelType::Seq(Box::new(inner)))
             }
-
-            // 22. Tuple
-            25 => {
-                let types = self.decode_slice(offset + 4, Self::decode_data_model_type)?;
+            "Tuple" => {
+                let types = self.decode_slice(
+                    layout,
+                    field("__0")?,
+                    depth + 1,
+                    Self::decode_data_model_type,
+                )?;
                 Ok(OwnedDataModelType::Tuple(types))
             }
-
-            // 23. Map
-            26 => {
-                /*
-                print-type-size     variant `Map`: 12 bytes
-                print-type-size         padding: 4 bytes
-                print-type-size         field `.key`: 4 bytes, alignment: 4 bytes
-                print-type-size         field `.val`: 4 bytes
-                */
-
-                let key = self.decode_data_model_type(self.read_pointer_at(offset + 4)?)?;
-                let value = self.decode_data_model_type(self.read_pointer_at(offset + 8)?)?;
-
+            "Map" => {
+                let key = self.decode_pointee(layout, field("key")?, depth + 1)?;
+                let val = self.decode_pointee(layout, field("val")?, depth + 1)?;
                 Ok(OwnedDataModelType::Map {
                     key: Box::new(key),
-                    val: Box::new(value),
+                    val: Box::new(val),
                 })
             }
-
-            // 24. Struct
-            0..4 => {
-                /*
-                print-type-size type: `destore::DataModelType`: 20 bytes, alignment: 4 bytes
-                print-type-size     variant `Struct`: 20 bytes
-                print-type-size         field `.data`: 12 bytes
-                print-type-size         field `.name`: 8 bytes
-                */
-                let data = self.decode_data(offset)?;
-                let name = self.decode_static_str(offset + 12)?;
+            "Struct" => {
+                let name = self.decode_static_str(field("name")?)?;
+                let data = self.decode_data(layout, field("data")?, depth + 1)?;
                 Ok(OwnedDataModelType::Struct { name, data })
             }
-
-            // 27 =>  Struct is left away, because it is in 0..4
-
-            // 25. Enum
-            28 => {
-                /*
-                   print-type-size     variant `Enum`: 20 bytes
-                   print-type-size         padding: 4 bytes
-                   print-type-size         field `.name`: 8 bytes, alignment: 4 bytes
-                   print-type-size         field `.variants`: 8 bytes
-                */
-
-                let name = self.decode_static_str(offset + 4)?; // takes two words
-                let variants = self.decode_slice(offset + 12, Self::decode_variant)?;
+            "Enum" => {
+                let name = self.decode_static_str(field("name")?)?;
+                let variants =
+                    self.decode_slice(layout, field("variants")?, depth + 1, Self::decode_variant)?;
                 Ok(OwnedDataModelType::Enum { name, variants })
             }
-
-            // 29. Schema
-            29 => Ok(OwnedDataModelType::Schema),
-
-            _ => anyhow::bail!("Unknown type tag: {}", type_tag),
+            "Schema" => Ok(OwnedDataModelType::Schema),
+            name => bail!("Unknown DataModelType variant: {}", name),
         }
     }
 
+    fn decode_pointee(
+        &self,
+        layout: &SchemaLayout,
+        offset: usize,
+        depth: usize,
+    ) -> Result<OwnedDataModelType> {
+        self.decode_data_model_type(layout, self.read_pointer_at(offset)?, depth)
+    }
+
+    // `&str` and `&[T]` are a pointer followed by a length
     fn decode_static_str(&self, offset: usize) -> Result<Box<str>> {
         let name_str_ptr = self.read_pointer_at(offset)?;
-        let name_str_len = self.read_u32_at(offset + 4)?;
+        let name_str_len = self.read_usize_at(add_offset(offset, self.pointer_size())?)?;
 
-        let name_bytes = &self.mmap[name_str_ptr..(name_str_ptr + name_str_len)];
+        let name_bytes = self
+            .read_bytes(name_str_ptr, name_str_len)
+            .context("Failed to read str")?;
         Ok(std::str::from_utf8(name_bytes)?
             .to_string()
             .into_boxed_str())
     }
 
-    fn decode_variant(&self, offset: usize) -> Result<OwnedVariant> {
-        /*
-        print-type-size type: `postcard_schema::schema::Variant`: 20 bytes, alignment: 4 bytes
-        print-type-size     field `.data`: 12 bytes
-        print-type-size     field `.name`: 8 bytes
-         */
-
-        let name = self.decode_static_str(offset + 12)?;
-        let data = self.decode_data(offset)?;
+    fn decode_variant(
+        &self,
+        layout: &SchemaLayout,
+        offset: usize,
+        depth: usize,
+    ) -> Result<OwnedVariant> {
+        let name = self.decode_static_str(add_offset(offset, layout.variant.field("name")?)?)?;
+        let data = self.decode_data(
+            layout,
+            add_offset(offset, layout.variant.field("data")?)?,
+            depth,
+        )?;
 
         Ok(OwnedVariant { name, data })
     }
 
-    fn decode_data(&self, offset: usize) -> Result<OwnedData> {
-        /*
-        print-type-size type: `postcard_schema::schema::Data`: 12 bytes, alignment: 4 bytes
-        print-type-size     discriminant: 4 bytes
-        print-type-size     variant `Tuple`: 8 bytes
-        print-type-size         field `.0`: 8 bytes
-        print-type-size     variant `Struct`: 8 bytes
-        print-type-size         field `.0`: 8 bytes
-        print-type-size     variant `Newtype`: 4 bytes
-        print-type-size         field `.0`: 4 bytes
-        print-type-size     variant `Unit`: 0 bytes
-         */
-
-        let discriminant = self.read_u32_at(offset)?;
-        match discriminant {
-            0 => Ok(OwnedData::Unit),
-            1 => {
-                // Newtype
-                let inner = self.decode_data_model_type(self.read_pointer_at(offset + 4)?)?;
+    fn decode_data(&self, layout: &SchemaLayout, offset: usize, depth: usize) -> Result<OwnedData> {
+        let variant = layout.data.variant(self.read_uint_at(
+            add_offset(offset, layout.data.discr_offset)?,
+            layout.data.discr_size,
+        )?)?;
+        let field = |name| add_offset(offset, variant.fields.field(name)?);
+
+        match variant.name.as_str() {
+            "Unit" => Ok(OwnedData::Unit),
+            "Newtype" => {
+                let inner = self.decode_pointee(layout, field("__0")?, depth + 1)?;
                 Ok(OwnedData::Newtype(Box::new(inner)))
             }
-            2 => {
-                // Tuple
-                let inner = self.decode_slice(offset + 4, Self::decode_data_model_type)?;
+            "Tuple" => {
+                let inner = self.decode_slice(
+                    layout,
+                    field("__0")?,
+                    depth + 1,
+                    Self::decode_data_model_type,
+                )?;
                 Ok(OwnedData::Tuple(inner))
             }
-            3 => {
-                // Struct
-                let inner = self.decode_slice(offset + 4, Self::decode_named_field)?;
+            "Struct" => {
+                let inner =
+                    self.decode_slice(layout, field("__0")?, depth + 1, Self::decode_named_field)?;
                 Ok(OwnedData::Struct(inner))
             }
-            _ => anyhow::bail!("Unknown data discriminant: {}", discriminant),
+            name => bail!("Unknown Data variant: {}", name),
         }
     }
 
-    fn decode_named_field(&self, offset: usize) -> Result<OwnedNamedField> {
-        /*
-        print-type-size type: `postcard_schema::schema::NamedField`: 12 bytes, alignment: 4 bytes
-        print-type-size     field `.name`: 8 bytes
-        print-type-size     field `.ty`: 4 bytes
-         */
-        let name = self.decode_static_str(offset)?;
-        let ty = self.decode_data_model_type(self.read_pointer_at(offset + 8)?)?;
+    fn decode_named_field(
+        &self,
+        layout: &SchemaLayout,
+        offset: usize,
+        depth: usize,
+    ) -> Result<OwnedNamedField> {
+        let name =
+            self.decode_static_str(add_offset(offset, layout.named_field.field("name")?)?)?;
+        let ty = self.decode_pointee(
+            layout,
+            add_offset(offset, layout.named_field.field("ty")?)?,
+            depth,
+        )?;
         Ok(OwnedNamedField { name, ty })
     }
 
-    fn decode_slice<F, R>(&self, offset: usize, inner_decoder: F) -> Result<Box<[R]>>
+    fn decode_slice<F, R>(
+        &self,
+        layout: &SchemaLayout,
+        offset: usize,
+        depth: usize,
+        inner_decoder: F,
+    ) -> Result<Box<[R]>>
     where
-        F: Fn(&Self, usize) -> Result<R>,
+        F: Fn(&Self, &SchemaLayout, usize, usize) -> Result<R>,
     {
+        let count = self.read_usize_at(add_offset(offset, self.pointer_size())?)?;
+        if count == 0 {
+            // The pointer of an empty slice is dangling (e.g. `0x8`) and lies in no segment
+            return Ok(Box::new([]));
+        }
         let slice_start = self.read_pointer_at(offset)?;
-        let count = self.read_u32_at(offset + 4)?;
         debug!("slice start {:#x} count {}", slice_start, count);
+        // The pointers to the elements must lie in the file, which also bounds the allocation below
+        let pointers = count
+            .checked_mul(self.pointer_size())
+            .context("Slice length overflows")?;
+        self.read_bytes(slice_start, pointers)
+            .context("Failed to read slice")?;
+
         let mut types_vec = Vec::with_capacity(count);
         for i in 0..count {
-            let type_offset = self.read_pointer_at(slice_start + i * 4)?;
-            let inner_type = inner_decoder(self, type_offset)?;
+            let type_offset = self.read_pointer_at(slice_start + i * self.pointer_size())?;
+            let inner_type = inner_decoder(self, layout, type_offset, depth)?;
             types_vec.push(inner_type);
         }
         Ok(types_vec.into_boxed_slice())
     }
 
+    /// The GNU build id of the ELF, hex encoded, if the linker emitted one (`-Wl,--build-id`)
+    pub fn build_id(&self) -> Option<String> {
+        let notes = self
+            .elf
+            .iter_note_headers(self.bytes)
+            .or_else(|| self.elf.iter_note_sections(self.bytes, None))?;
+        let note = notes
+            .map_while(Result::ok)
+            .find(|note| note.n_type == NT_GNU_BUILD_ID && note.name == "GNU")?;
+        Some(format_hex(note.desc))
+    }
+
+    /// Identifies the defmt table of the ELF, see [`defmt_table_id`](crate::defmt_table_id)
+    pub fn defmt_table_id(&self) -> Option<String> {
+        crate::defmt_table_id(&self.elf)
+    }
+
+    /// The defmt table needed to decode the frames stored with `Storer::write_defmt_frame`
+    pub fn defmt_table(&self) -> Result<Option<Table>> {
+        if self.defmt_table_id().is_none() {
+            return Ok(None);
+        }
+        Table::parse(self.bytes).context("Failed to read the defmt table")
+    }
+
+    /// Loads every schema exported with `export_schema!`, i.e. every symbol in the schema section.
+    /// A symbol that fails to load does not keep the others from loading.
+    pub fn load_all_schemas(&self) -> Vec<(String, Result<OwnedDataModelType>)> {
+        self.exported_symbols(".destore.schema")
+            .into_iter()
+            .filter(|(name, _)| {
+                !name.starts_with(LOG_SYMBOL_PREFIX) && !name.starts_with(METADATA_SYMBOL_PREFIX)
+            })
+            .map(|(name, _)| {
+                let schema = self
+                    .load_schema_from_symbol(name)
+                    .with_context(|| format!("Failed to load schema {}", name));
+                (name.to_string(), schema)
+            })
+            .collect()
+    }
+
+    /// Loads every log declared with `export_log!`
+    pub fn load_logs(&self) -> Result<Vec<LogDescriptor>> {
+        self.exported_symbols(".destore.log")
+            .into_iter()
+            .filter(|(name, _)| name.starts_with(LOG_SYMBOL_PREFIX))
+            .map(|(name, sym)| {
+                let offset = self.symbol_offset(&sym)?;
+                let bytes = self
+                    .read_length_prefixed(&sym, offset)
+                    .with_context(|| format!("Failed to load log {}", name))?;
+                SerializedReader::new(bytes)
+                    .check_log()
+                    .with_context(|| format!("Failed to load log {}", name))?;
+                Ok(postcard::from_bytes(bytes)?)
+            })
+            .collect()
+    }
+
+    /// Loads the metadata `#[derive(DestoreRecord)]` exports for annotated types
+    pub fn load_metadata(&self) -> Result<Vec<TypeMetadata>> {
+        self.exported_symbols(".destore.meta")
+            .into_iter()
+            .filter(|(name, _)| name.starts_with(METADATA_SYMBOL_PREFIX))
+            .map(|(name, sym)| {
+                let offset = self.symbol_offset(&sym)?;
+                let bytes = self
+                    .read_length_prefixed(&sym, offset)
+                    .with_context(|| format!("Failed to load metadata {}", name))?;
+                Ok(postcard::from_bytes(bytes)?)
+            })
+            .collect()
+    }
+
+    /// Names of the objects in the given section.
+    /// With `destore.x` all destore sections are merged into `.destore`, so its objects are included too.
+    fn exported_symbols(&self, section: &str) -> Vec<(&str, Sym)> {
+        let sections: Vec<usize> = self
+            .elf
+            .section_headers
+            .iter()
+            .enumerate()
+            .filter(|(_, sh)| {
+                self.elf
+                    .shdr_strtab
+                    .get_at(sh.sh_name)
+                    .is_some_and(|name| name == section || name == ".destore")
+            })
+            .map(|(idx, _)| idx)
+            .collect();
+
+        self.elf
+            .syms
+            .iter()
+            .filter(|sym| {
+                sections.contains(&sym.st_shndx) && sym.st_type() == STT_OBJECT && sym.st_size > 0
+            })
+            .filter_map(|sym| Some((self.elf.strtab.get_at(sym.st_name)?, sym)))
+            .collect()
+    }
+
     /// Load a schema from a symbol (e.g. _DESTORE_SCHEMA) in the ELF file
     pub fn load_schema_from_symbol(&self, symbol: &str) -> Result<OwnedDataModelType> {
         let schema_sym = self.find_symbol(symbol)?;
-        let section_idx = schema_sym.st_shndx;
 
         // Convert the symbol's value to a file offset
-        let schema_offset = self.section_addr_to_offset(section_idx, schema_sym.st_value)?;
+        let schema_offset = self.symbol_offset(&schema_sym)?;
         debug!(
             "sym {} value: {:#x}, offset: {:#x}",
             symbol, schema_sym.st_value, schema_offset
         );
-        let type_def = self.decode_data_model_type(self.read_pointer_at(schema_offset)?)?;
+        match self.load_serialized_schema(&schema_sym, schema_offset) {
+            Ok(type_def) => return Ok(type_def),
+            Err(e) => debug!(
+                "No serialized schema in {}: {}. Restoring it from the memory layout",
+                symbol, e
+            ),
+        }
+
+        self.restore_schema(schema_offset)
+    }
 
-        Ok(type_def)
+    /// Restores the schema from the memory layout of the `&'static DataModelType` at `offset`
+    fn restore_schema(&self, offset: usize) -> Result<OwnedDataModelType> {
+        let layout = self.layout()?;
+        self.nodes.set(0);
+        self.decode_pointee(layout, offset, 0)
+    }
+
+    /// Reads the postcard serialization that `export_schema!` places after the schema pointer
+    /// (see `destore::ExportedSchema`)
+    fn load_serialized_schema(&self, sym: &Sym, offset: usize) -> Result<OwnedDataModelType> {
+        let pointer_size = self.pointer_size();
+        if (sym.st_size as usize) < pointer_size + 4 {
+            bail!("symbol only contains a pointer (exported by an older destore version?)");
+        }
+        let bytes = self.read_length_prefixed(sym, add_offset(offset, pointer_size)?)?;
+        SerializedReader::new(bytes).check_ty(0)?;
+        Ok(postcard::from_bytes(bytes)?)
+    }
+
+    /// Reads a `u32` length followed by that many bytes, which must lie within the symbol
+    fn read_length_prefixed(&self, sym: &Sym, offset: usize) -> Result<&'a [u8]> {
+        let sym_end = self
+            .symbol_offset(sym)?
+            .saturating_add(sym.st_size as usize);
+        let len = self.read_u32_at(offset)?;
+        if offset.saturating_add(4).saturating_add(len) > sym_end {
+            bail!("serialized length {} exceeds the symbol", len);
+        }
+        self.read_bytes(add_offset(offset, 4)?, len)
+            .context("Failed to read serialized data")
+    }
+}
+
+/// Walks the postcard serialization of schemas (see `destore::ExportedSchema`) without building them.
+///
+/// postcard has no recursion limit, so the nesting is checked before deserializing a schema
+/// from the ELF.
+struct SerializedReader<'b> {
+    bytes: &'b [u8],
+    pos: usize,
+}
+
+impl<'b> SerializedReader<'b> {
+    fn new(bytes: &'b [u8]) -> Self {
+        Self { bytes, pos: 0 }
+    }
+
+    /// Checks the nesting of the serialized `ExportedLog` fields, the schema being the last one
+    fn check_log(mut self) -> Result<()> {
+        self.skip_str()?;
+        self.varint()?;
+        self.varint()?;
+        self.skip_str()?;
+        self.check_ty(0)
+    }
+
+    /// Checks that a serialized `OwnedDataModelType` is nested at most [`MAX_DEPTH`] levels
+    fn check_ty(&mut self, depth: usize) -> Result<()> {
+        if depth > MAX_DEPTH {
+            bail!("Schema nested deeper than {} levels", MAX_DEPTH);
+        }
+        // Variant indices of `OwnedDataModelType`
+        match self.varint()? {
+            0..=17 | 19 | 25 => Ok(()),
+            18 | 20 => self.check_ty(depth + 1),
+            21 => self.check_types(depth + 1),
+            22 => {
+                self.check_ty(depth + 1)?;
+                self.check_ty(depth + 1)
+            }
+            23 => {
+                self.skip_str()?;
+                self.check_data(depth + 1)
+            }
+            24 => {
+                self.skip_str()?;
+                for _ in 0..self.varint()? {
+                    self.skip_str()?;
+                    self.check_data(depth + 1)?;
+                }
+                Ok(())
+            }
+            index => bail!("Unknown DataModelType variant index {}", index),
+        }
+    }
+
+    fn check_data(&mut self, depth: usize) -> Result<()> {
+        match self.varint()? {
+            0 => Ok(()),
+            1 => self.check_ty(depth),
+            2 => self.check_types(depth),
+            3 => {
+                for _ in 0..self.varint()? {
+                    self.skip_str()?;
+                    self.check_ty(depth)?;
+                }
+                Ok(())
+            }
+            index => bail!("Unknown Data variant index {}", index),
+        }
+    }
+
+    fn check_types(&mut self, depth: usize) -> Result<()> {
+        for _ in 0..self.varint()? {
+            self.check_ty(depth)?;
+        }
+        Ok(())
+    }
+
+    fn skip_str(&mut self) -> Result<()> {
+        let len = self.varint()?;
+        self.pos = usize::try_from(len)
+            .ok()
+            .and_then(|len| self.pos.checked_add(len))
+            .filter(|end| *end <= self.bytes.len())
+            .context("Serialized string out of bounds")?;
+        Ok(())
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = *self
+                .bytes
+                .get(self.pos)
+                .context("Serialized data truncated")?;
+            self.pos += 1;
+            value |= u64::from(byte & 0x7F) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        bail!("Invalid varint")
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::defmt_frames::write_elf;
+    use crate::{decode_value, FieldMetadata};
+    use goblin::container::{Container, Ctx, Endian};
+    use goblin::elf::section_header::SHT_PROGBITS;
+    use goblin::elf::sym::STB_GLOBAL;
+    use goblin::elf::{Header, ProgramHeader};
     use log::info;
-    use std::path::Path;
+    use postcard_schema::key::hash::fnv1a64_owned::hash_ty_path_owned;
+    use postcard_schema::schema::{Data, DataModelType, NamedField};
+    use scroll::ctx::{IntoCtx, TryIntoCtx};
+    use std::path::{Path, PathBuf};
     use std::process::Command;
 
+    // The targets have to be installed, see tests/elf-schema-restore/rust-toolchain.toml
+    #[test]
+    fn test_restore_riscv32imac() {
+        check_restore_profiles("riscv32imac-unknown-none-elf");
+    }
+
     #[test]
-    fn test_restore() {
+    fn test_restore_riscv32imc() {
+        check_restore_profiles("riscv32imc-unknown-none-elf");
+    }
+
+    #[test]
+    fn test_restore_thumbv6m() {
+        check_restore_profiles("thumbv6m-none-eabi");
+    }
+
+    #[test]
+    fn test_restore_thumbv7em() {
+        check_restore_profiles("thumbv7em-none-eabihf");
+    }
+
+    #[test]
+    fn test_restore_x86_64() {
+        check_restore_profiles("x86_64-unknown-linux-gnu");
+    }
+
+    #[test]
+    fn test_restore_malformed() {
+        check_malformed(&build_elf("x86_64-unknown-linux-gnu", Profile::Debug));
+    }
+
+    /// Loading truncated or randomly modified ELFs must fail (or succeed) without panicking
+    fn check_malformed(elf_path: &Path) {
+        let mut bytes = std::fs::read(elf_path).unwrap();
+        let destore_sections: Vec<_> = {
+            let elf = Elf::parse(&bytes).unwrap();
+            elf.section_headers
+                .iter()
+                .filter(|sh| {
+                    sh.sh_size > 0
+                        && elf
+                            .shdr_strtab
+                            .get_at(sh.sh_name)
+                            .is_some_and(|name| name.starts_with(".destore"))
+                })
+                .map(|sh| {
+                    let end = sh.sh_offset.checked_add(sh.sh_size).unwrap();
+                    sh.sh_offset as usize..end as usize
+                })
+                .collect()
+        };
+        assert!(!destore_sections.is_empty());
+
+        let load = |bytes: &[u8]| {
+            if let Ok(restorer) = SchemaRestorer::from_bytes(bytes) {
+                let _ = restorer.load_all_schemas();
+                let _ = restorer.load_logs();
+                let _ = restorer.load_metadata();
+                let _ = restorer.load_schema_from_symbol("_DESTORE_SCHEMA_ENUM");
+            }
+        };
+
+        let step = bytes.len() / 64;
+        let around_sections = destore_sections
+            .iter()
+            .flat_map(|range| range.start.saturating_sub(8)..(range.end + 8).min(bytes.len()));
+        for len in (0..bytes.len()).step_by(step).chain(around_sections) {
+            load(&bytes[..len]);
+        }
+
+        // xorshift, so that failures are reproducible
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as usize
+        };
+        for i in 0..400 {
+            let pos = match i % 4 {
+                // Mostly the exported data, the rest of the file is mostly irrelevant to the restorer
+                0 => random() % bytes.len(),
+                _ => {
+                    let range = &destore_sections[random() % destore_sections.len()];
+                    range.start + random() % range.len()
+                }
+            };
+            let original = bytes[pos];
+            bytes[pos] = random() as u8;
+            load(&bytes);
+            bytes[pos] = original;
+        }
+
+        // Sections and segments at the end of the address space, so that the offsets of the
+        // exported data and of the pointers into it are close to usize::MAX
+        let (ctx, shoff, sections, phoff, segments) = {
+            let elf = Elf::parse(&bytes).unwrap();
+            let container = if elf.is_64 {
+                Container::Big
+            } else {
+                Container::Little
+            };
+            let endian = if elf.little_endian {
+                Endian::Little
+            } else {
+                Endian::Big
+            };
+            (
+                Ctx::new(container, endian),
+                elf.header.e_shoff as usize,
+                elf.section_headers.clone(),
+                elf.header.e_phoff as usize,
+                elf.program_headers.clone(),
+            )
+        };
+        let max = if ctx.container.is_big() {
+            u64::MAX
+        } else {
+            u64::from(u32::MAX)
+        };
+        let original = bytes.clone();
+        for k in [0, 1, 4, 7, 8, 16, 4096] {
+            for (i, section) in sections.iter().enumerate() {
+                SectionHeader {
+                    sh_offset: max - k,
+                    ..section.clone()
+                }
+                .into_ctx(&mut bytes[shoff + i * SectionHeader::size(ctx)..], ctx);
+                load(&bytes);
+                bytes.copy_from_slice(&original);
+            }
+            for (i, segment) in segments.iter().enumerate() {
+                ProgramHeader {
+                    p_offset: max - k,
+                    ..segment.clone()
+                }
+                .try_into_ctx(&mut bytes[phoff + i * ProgramHeader::size(ctx)..], ctx)
+                .unwrap();
+                load(&bytes);
+                bytes.copy_from_slice(&original);
+            }
+        }
+
+        // The elements of a tuple all point back to the tuple. Without a bound on the number of
+        // types, restoring it would take 3^MAX_DEPTH steps.
+        let restorer = SchemaRestorer::from_bytes(&bytes).unwrap();
+        let sym = restorer.find_symbol("_DESTORE_SCHEMA_TUPLE").unwrap();
+        let offset = restorer.symbol_offset(&sym).unwrap();
+        let pointer_size = restorer.pointer_size();
+        let tuple_addr = restorer.read_uint_at(offset, pointer_size).unwrap();
+        let tuple = restorer.read_pointer_at(offset).unwrap();
+        let layout = restorer.layout().unwrap();
+        let discr = restorer
+            .read_uint_at(
+                tuple + layout.data_model_type.discr_offset,
+                layout.data_model_type.discr_size,
+            )
+            .unwrap();
+        let elements_field = layout
+            .data_model_type
+            .variant(discr)
+            .unwrap()
+            .fields
+            .field("__0")
+            .unwrap();
+        let elements = restorer.read_pointer_at(tuple + elements_field).unwrap();
+        let addr_bytes = if restorer.elf.little_endian {
+            tuple_addr.to_le_bytes()[..pointer_size].to_vec()
+        } else {
+            tuple_addr.to_be_bytes()[8 - pointer_size..].to_vec()
+        };
+        for i in 0..3 {
+            let start = elements + i * pointer_size;
+            bytes[start..start + pointer_size].copy_from_slice(&addr_bytes);
+        }
+        let restorer = SchemaRestorer::from_bytes(&bytes).unwrap();
+        assert!(restorer.restore_schema(offset).is_err());
+        load(&bytes);
+    }
+
+    fn check_restore_profiles(target: &str) {
+        for profile in [Profile::Debug, Profile::ReleaseLto] {
+            info!("testing {} {:?}", target, profile);
+            check_restore(&build_elf(target, profile));
+        }
+    }
+
+    fn check_restore(elf_path: &Path) {
         env_logger::try_init_from_env(env_logger::Env::default().default_filter_or("debug")).ok();
-        let elf_path = build_riscv32_elf();
-        let restorer = SchemaRestorer::from_path(&elf_path).unwrap();
+        let bytes = std::fs::read(elf_path).unwrap();
+        let restorer = SchemaRestorer::from_bytes(&bytes).unwrap();
+        let all: Vec<_> = restorer
+            .load_all_schemas()
+            .into_iter()
+            .map(|(symbol, schema)| (symbol, schema.unwrap()))
+            .collect();
+
+        // Like a stripped ELF, pointers can only be resolved through the program headers.
+        // The layout comes from the debug info, so it is read before dropping the sections.
+        let mut segments_only = SchemaRestorer::from_bytes(&bytes).unwrap();
+        segments_only.layout().unwrap();
+        segments_only.elf.section_headers.clear();
+        let restore = |symbol: &str| restore(&restorer, &segments_only, &all, symbol);
 
         let tests = &[
             ("_DESTORE_SCHEMA_BOOL", "bool"),
@@ -358,13 +917,10 @@ mod tests {
             ("_DESTORE_SCHEMA_U32", "u32"),
             ("_DESTORE_SCHEMA_U64", "u64"),
             ("_DESTORE_SCHEMA_U128", "u128"),
-            // ("_DESTORE_SCHEMA_USIZE", "usize"),
-            //("_DESTORE_SCHEMA_ISIZE", "isize"),
             ("_DESTORE_SCHEMA_F32", "f32"),
             ("_DESTORE_SCHEMA_F64", "f64"),
             ("_DESTORE_SCHEMA_CHAR", "char"),
             ("_DESTORE_SCHEMA_STRING", "String"),
-            //("_DESTORE_SCHEMA_BYTEARRAY", "&[u8]"),
             ("_DESTORE_SCHEMA_OPTION", "Option<u8>"),
             ("_DESTORE_SCHEMA_UNIT", "()"),
             ("_DESTORE_SCHEMA_SEQUENCE", "[u16]"),
@@ -388,10 +944,13 @@ mod tests {
                 "enum Enums { Unit, Nt(u64), Tup(u32, bool), Str { a: u32, b: u16, c: bool } }",
             ),
             ("_DESTORE_SCHEMA_SCHEMA", "Schema"),
+            (
+                "_DESTORE_SCHEMA_elf_schema_restore_test::Derived",
+                "enum Derived { Boot, Reading { sensor: u8, value: i16 }, Raw(u32, u32) }",
+            ),
         ];
         for (symbol, expected) in tests {
-            info!("testing symbol {}", symbol);
-            let schema = restorer.load_schema_from_symbol(symbol).unwrap();
+            let schema = restore(symbol);
             let actual = schema.to_pseudocode();
             if actual != *expected {
                 panic!(
@@ -402,28 +961,337 @@ mod tests {
 
             //assert_eq!(actual, expected);
         }
+
+        // Compared structurally, the pseudocode does not tell these apart from other types
+        const BLOB: &DataModelType = &DataModelType::Struct {
+            name: "Blob",
+            data: Data::Struct(&[
+                &NamedField {
+                    name: "data",
+                    ty: &DataModelType::ByteArray,
+                },
+                &NamedField {
+                    name: "len",
+                    ty: &DataModelType::Usize,
+                },
+                &NamedField {
+                    name: "offset",
+                    ty: &DataModelType::Isize,
+                },
+            ]),
+        };
+        const BUFFER: &DataModelType = &DataModelType::Struct {
+            name: "Buffer",
+            data: Data::Struct(&[&NamedField {
+                name: "data",
+                ty: &DataModelType::Seq(&DataModelType::U8),
+            }]),
+        };
+        const FRAME: &DataModelType = &DataModelType::Struct {
+            name: "Frame",
+            data: Data::Struct(&[
+                &NamedField {
+                    name: "payload",
+                    ty: &DataModelType::ByteArray,
+                },
+                &NamedField {
+                    name: "checksum",
+                    ty: &DataModelType::U16,
+                },
+            ]),
+        };
+        let tests: &[(&str, &DataModelType)] = &[
+            ("_DESTORE_SCHEMA_USIZE", &DataModelType::Usize),
+            ("_DESTORE_SCHEMA_ISIZE", &DataModelType::Isize),
+            ("_DESTORE_SCHEMA_BYTEARRAY", &DataModelType::ByteArray),
+            ("_DESTORE_SCHEMA_SERDE_BYTES", BLOB),
+            ("_DESTORE_SCHEMA_HEAPLESS", BUFFER),
+            ("_DESTORE_SCHEMA_elf_schema_restore_test::Frame", FRAME),
+        ];
+        for (symbol, expected) in tests {
+            assert_eq!(
+                restore(symbol),
+                OwnedDataModelType::from(*expected),
+                "Testing symbol {} failed",
+                symbol
+            );
+        }
+
+        // Records written by the firmware decode with the restored schema
+        #[derive(serde::Serialize)]
+        struct Blob {
+            #[serde(with = "serde_bytes")]
+            data: Vec<u8>,
+            len: usize,
+            offset: isize,
+        }
+        let blob = Blob {
+            data: vec![0xde, 0xad, 0x0f],
+            // The extremes of the 32 bit firmware
+            len: u32::MAX as usize,
+            offset: i32::MIN as isize,
+        };
+        let bytes = postcard::to_allocvec(&blob).unwrap();
+        assert_eq!(
+            decode_value(&restore("_DESTORE_SCHEMA_SERDE_BYTES"), &bytes).unwrap(),
+            serde_json::json!({"data": "dead0f", "len": u32::MAX, "offset": i32::MIN})
+        );
+
+        let logs = restorer.load_logs().unwrap();
+        assert_eq!(
+            logs,
+            [LogDescriptor {
+                name: "EVENTS".to_string(),
+                flash_range: 0x1000..0x3000,
+                description: "Test log".to_string(),
+                schema: restore("_DESTORE_SCHEMA_ENUM"),
+            }]
+        );
+
+        let annotation = |variant: &str, field: Option<&str>| FieldMetadata {
+            variant: Some(variant.to_string()),
+            field: field.map(str::to_string),
+            unit: None,
+            scale: None,
+            format: None,
+            description: None,
+        };
+        assert_eq!(
+            restorer.load_metadata().unwrap(),
+            [TypeMetadata {
+                name: "Derived".to_string(),
+                schema_hash: hash_ty_path_owned(
+                    "",
+                    &restore("_DESTORE_SCHEMA_elf_schema_restore_test::Derived"),
+                ),
+                fields: vec![
+                    FieldMetadata {
+                        description: Some("Written once after reset".to_string()),
+                        ..annotation("Boot", None)
+                    },
+                    FieldMetadata {
+                        format: Some("hex".to_string()),
+                        ..annotation("Reading", Some("sensor"))
+                    },
+                    FieldMetadata {
+                        unit: Some("°C".to_string()),
+                        scale: Some(0.01),
+                        ..annotation("Reading", Some("value"))
+                    },
+                ],
+            }]
+        );
+    }
+
+    fn restore(
+        restorer: &SchemaRestorer,
+        segments_only: &SchemaRestorer,
+        all: &[(String, OwnedDataModelType)],
+        symbol: &str,
+    ) -> OwnedDataModelType {
+        info!("testing symbol {}", symbol);
+        let schema = restorer.load_schema_from_symbol(symbol).unwrap();
+
+        // The fallback for ELFs without serialized schema must agree
+        let sym = restorer.find_symbol(symbol).unwrap();
+        let offset = restorer.symbol_offset(&sym).unwrap();
+        assert_eq!(restorer.restore_schema(offset).unwrap(), schema);
+        assert_eq!(segments_only.restore_schema(offset).unwrap(), schema);
+
+        assert!(all.contains(&(symbol.to_string(), schema.clone())));
+        schema
+    }
+
+    /// Offsets close to usize::MAX fail to load instead of overflowing
+    #[test]
+    fn test_offsets_near_usize_max() {
+        let ctx = Ctx::new(Container::Big, Endian::Little);
+        let data = [0xFF; 16];
+        let names = [".destore.schema", ".destore.log", ".destore.meta"];
+        let sections = names.map(|name| {
+            let section = SectionHeader {
+                sh_type: SHT_PROGBITS,
+                sh_size: data.len() as u64,
+                ..SectionHeader::default()
+            };
+            (name, section, &data[..])
+        });
+        let sym = |st_shndx| Sym {
+            st_info: (STB_GLOBAL << 4) | STT_OBJECT,
+            st_shndx,
+            st_size: data.len() as u64,
+            ..Sym::default()
+        };
+        let symbols = [
+            ("_DESTORE_SCHEMA", sym(1)),
+            ("_DESTORE_LOG_EVENTS", sym(2)),
+            ("_DESTORE_META_app::Record", sym(3)),
+        ];
+        let original = write_elf(Header::new(ctx), ctx, &sections, &symbols);
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), &original).unwrap();
+        let file = ElfFile::read(file.path()).unwrap();
+        let restorer = file.restorer().unwrap();
+        assert_eq!(restorer.exported_symbols(".destore.schema").len(), 1);
+        let (shoff, headers) = {
+            let elf = Elf::parse(&original).unwrap();
+            (elf.header.e_shoff as usize, elf.section_headers.clone())
+        };
+
+        for k in 0..16 {
+            let mut bytes = original.clone();
+            for (i, section) in headers.iter().enumerate().skip(1).take(sections.len()) {
+                SectionHeader {
+                    sh_offset: u64::MAX - k,
+                    ..section.clone()
+                }
+                .into_ctx(&mut bytes[shoff + i * SectionHeader::size(ctx)..], ctx);
+            }
+            let restorer = SchemaRestorer::from_bytes(&bytes).unwrap();
+            assert!(restorer.load_schema_from_symbol("_DESTORE_SCHEMA").is_err());
+            let schemas = restorer.load_all_schemas();
+            assert_eq!(schemas.len(), 1);
+            assert!(schemas[0].1.is_err());
+            assert!(restorer.load_logs().is_err());
+            assert!(restorer.load_metadata().is_err());
+        }
+    }
+
+    #[test]
+    fn test_serialized_nesting() {
+        // Option<Option<...<bool>>>
+        let nested = |depth: usize| [vec![18; depth], vec![0]].concat();
+        let schema: OwnedDataModelType = postcard::from_bytes(&nested(MAX_DEPTH)).unwrap();
+        assert_eq!(postcard::to_allocvec(&schema).unwrap(), nested(MAX_DEPTH));
+        assert!(SerializedReader::new(&nested(MAX_DEPTH))
+            .check_ty(0)
+            .is_ok());
+        assert!(SerializedReader::new(&nested(100_000)).check_ty(0).is_err());
+        assert!(SerializedReader::new(&nested(10)[..10])
+            .check_ty(0)
+            .is_err());
+    }
+
+    #[test]
+    fn test_serialized_schema() {
+        use postcard_schema::schema::Variant;
+
+        const CLASSIC: &DataModelType = &DataModelType::Struct {
+            name: "Classic",
+            data: Data::Struct(&[
+                &NamedField {
+                    name: "a",
+                    ty: &DataModelType::U32,
+                },
+                &NamedField {
+                    name: "b",
+                    ty: &DataModelType::Option(&DataModelType::String),
+                },
+                &NamedField {
+                    name: "c",
+                    ty: &DataModelType::ByteArray,
+                },
+                &NamedField {
+                    name: "d",
+                    ty: &DataModelType::Isize,
+                },
+            ]),
+        };
+        const ENUM: &DataModelType = &DataModelType::Enum {
+            name: "Enums",
+            variants: &[
+                &Variant {
+                    name: "Unit",
+                    data: Data::Unit,
+                },
+                &Variant {
+                    name: "Nt",
+                    data: Data::Newtype(CLASSIC),
+                },
+                &Variant {
+                    name: "Tup",
+                    data: Data::Tuple(&[&DataModelType::U8, &DataModelType::Bool]),
+                },
+            ],
+        };
+        const MAP: &DataModelType = &DataModelType::Map {
+            key: &DataModelType::I64,
+            val: &DataModelType::Seq(ENUM),
+        };
+
+        // Same as export_schema! does
+        macro_rules! check {
+            ($ty:expr) => {{
+                const LEN: usize = destore::serialized_schema_len($ty);
+                let exported = destore::ExportedSchema::<LEN>::new($ty);
+                let expected = OwnedDataModelType::from($ty);
+                assert_eq!(exported.len as usize, LEN);
+                assert_eq!(postcard::to_allocvec(&expected).unwrap(), exported.bytes);
+                assert_eq!(
+                    postcard::from_bytes::<OwnedDataModelType>(&exported.bytes).unwrap(),
+                    expected
+                );
+            }};
+        }
+        check!(CLASSIC);
+        check!(ENUM);
+        check!(MAP);
+        check!(&DataModelType::Schema);
+    }
+
+    #[derive(Clone, Copy, Debug)]
+    enum Profile {
+        Debug,
+        /// Release with LTO, keeping the debug info for the memory layout fallback
+        ReleaseLto,
     }
 
-    fn build_riscv32_elf() -> &'static Path {
+    fn build_elf(target: &str, profile: Profile) -> PathBuf {
+        let profile_args: &[&str] = match profile {
+            Profile::Debug => &[],
+            Profile::ReleaseLto => &[
+                "--release",
+                "--config",
+                "profile.release.lto=true",
+                "--config",
+                "profile.release.debug=true",
+            ],
+        };
         let output = Command::new("cargo")
             .args([
                 "build",
                 "--target",
-                "riscv32imac-unknown-none-elf",
+                target,
                 "--package",
                 "elf-schema-restore-test",
             ])
+            .args(profile_args)
+            // Pointers in a position independent executable are only valid after relocation
+            .env(
+                format!(
+                    "CARGO_TARGET_{}_RUSTFLAGS",
+                    target.to_uppercase().replace('-', "_")
+                ),
+                "-C relocation-model=static",
+            )
             .output()
-            .expect("Failed to build riscv32 binary");
+            .expect("Failed to run cargo");
 
         assert!(
             output.status.success(),
-            "Failed to build riscv32 binary: {:?}",
+            "Failed to build {} binary: {:?}",
+            target,
             String::from_utf8_lossy(&output.stderr)
         );
 
-        let elf_path =
-            Path::new("../target/riscv32imac-unknown-none-elf/debug/elf-schema-restore-test");
+        let profile_dir = match profile {
+            Profile::Debug => "debug",
+            Profile::ReleaseLto => "release",
+        };
+        let elf_path = PathBuf::from(format!(
+            "../target/{}/{}/elf-schema-restore-test",
+            target, profile_dir
+        ));
         assert!(
             elf_path.exists(),
             "ELF file was not generated at expected path: {:?}",
@@ -0,0 +1,287 @@
+use crate::pages::{oldest_page, page_states, PageState, PAGE_SIZE};
+use crate::{
+    builtin_schema, decode_value, format_hash, read_entries, Cache, PartitionEntry, Provenance,
+};
+use postcard_dyn::Value;
+use postcard_schema::schema::owned::OwnedDataModelType;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+/// Number of entries and payload bytes of one record kind
+#[derive(Default, Debug, PartialEq)]
+pub struct EntryStats {
+    pub count: usize,
+    pub bytes: usize,
+}
+
+/// Summary of the content of a partition
+#[derive(Debug)]
+pub struct PartitionStats {
+    pub size: usize,
+    pub pages: Vec<PageState>,
+    /// Page holding the oldest data (see [`oldest_page`]), `None` if the partition is empty
+    pub oldest_page: Option<usize>,
+    /// Schema entries found, one per `Storer::new`, i.e. per boot of the firmware
+    pub sessions: usize,
+    /// Distinct schema hashes in the order they first appear
+    pub schemas: Vec<[u8; 8]>,
+    /// The firmware each schema was last extracted from, according to the cache index
+    pub firmwares: HashMap<[u8; 8], Provenance>,
+    /// Data entries per record kind (`Enum::Variant`, the type name for other records or `defmt`
+    /// for defmt frames)
+    pub records: BTreeMap<String, EntryStats>,
+    /// Data entries whose schema is not in the cache (or that precede any schema entry)
+    pub unknown_schema: EntryStats,
+    /// Data entries that could not be decoded with their schema
+    pub decode_errors: EntryStats,
+}
+
+impl PartitionStats {
+    pub fn used_pages(&self) -> usize {
+        self.pages
+            .iter()
+            .filter(|s| matches!(s, PageState::PartialOpen | PageState::Closed))
+            .count()
+    }
+
+    pub fn free_pages(&self) -> usize {
+        self.pages.iter().filter(|s| **s == PageState::Open).count()
+    }
+
+    /// The queue has wrapped around if its oldest data is not in the first page
+    pub fn wrapped(&self) -> bool {
+        self.oldest_page.is_some_and(|page| page != 0)
+    }
+}
+
+/// Collects statistics about a partition image, using the schemas from the cache
+pub fn partition_stats(
+    partition: &mut [u8],
+    schema_cache: &Cache,
+) -> anyhow::Result<PartitionStats> {
+    let pages = page_states(partition);
+    let mut stats = PartitionStats {
+        size: partition.len(),
+        oldest_page: oldest_page(&pages),
+        pages,
+        sessions: 0,
+        schemas: Vec::new(),
+        firmwares: HashMap::new(),
+        records: BTreeMap::new(),
+        unknown_schema: EntryStats::default(),
+        decode_errors: EntryStats::default(),
+    };
+
+    let index = schema_cache.index()?;
+    let mut schemas: HashMap<[u8; 8], Option<OwnedDataModelType>> = HashMap::new();
+    let mut schema_hash = None;
+    for entry in read_entries(partition)? {
+        match entry {
+            PartitionEntry::Schema(hash) => {
+                stats.sessions += 1;
+                if let Entry::Vacant(e) = schemas.entry(hash) {
+                    stats.schemas.push(hash);
+                    e.insert(
+                        schema_cache
+                            .lookup(&hash)?
+                            .or_else(|| builtin_schema(&hash)),
+                    );
+                    if let Some(entry) = index.for_schema(&hash).last() {
+                        stats.firmwares.insert(hash, entry.provenance.clone());
+                    }
+                }
+                schema_hash = Some(hash);
+            }
+            PartitionEntry::Data(data) => {
+                let schema = schema_hash.and_then(|hash| schemas[&hash].as_ref());
+                let kind = match schema {
+                    None => &mut stats.unknown_schema,
+                    Some(schema) => match decode_value(schema, &data) {
+                        Ok(value) => stats
+                            .records
+                            .entry(record_kind(schema, &value))
+                            .or_default(),
+                        Err(_) => &mut stats.decode_errors,
+                    },
+                };
+                kind.count += 1;
+                kind.bytes += data.len();
+            }
+            PartitionEntry::Defmt(frame) => {
+                let kind = stats.records.entry("defmt".to_string()).or_default();
+                kind.count += 1;
+                kind.bytes += frame.len();
+            }
+        }
+    }
+
+    Ok(stats)
+}
+
+fn record_kind(schema: &OwnedDataModelType, value: &Value) -> String {
+    match schema {
+        OwnedDataModelType::Enum { name, .. } => {
+            let variant = match value {
+                Value::String(variant) => variant.as_str(),
+                Value::Object(map) => map.keys().next().map(String::as_str).unwrap_or("?"),
+                _ => "?",
+            };
+            format!("{}::{}", name, variant)
+        }
+        OwnedDataModelType::Struct { name, .. } => name.to_string(),
+        schema => schema.to_pseudocode(),
+    }
+}
+
+impl fmt::Display for PartitionStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Partition size: {} bytes ({} pages of {} bytes)",
+            self.size,
+            self.pages.len(),
+            PAGE_SIZE
+        )?;
+        writeln!(
+            f,
+            "Pages: {} used, {} free, {} corrupted",
+            self.used_pages(),
+            self.free_pages(),
+            self.pages
+                .iter()
+                .filter(|s| **s == PageState::Corrupted)
+                .count()
+        )?;
+        match self.oldest_page {
+            Some(page) if self.wrapped() => {
+                writeln!(f, "Wrapped: yes (oldest data in page {})", page)?
+            }
+            _ => writeln!(f, "Wrapped: no")?,
+        }
+        writeln!(f, "Sessions: {}", self.sessions)?;
+        writeln!(f, "Schemas:")?;
+        for hash in &self.schemas {
+            match self.firmwares.get(hash) {
+                Some(firmware) => writeln!(f, "  {}  {}", format_hash(hash), firmware)?,
+                None => writeln!(f, "  {}", format_hash(hash))?,
+            }
+        }
+        writeln!(f, "Records:")?;
+        let width = self.records.keys().map(String::len).max().unwrap_or(0);
+        for (kind, stats) in &self.records {
+            writeln!(
+                f,
+                "  {:width$}  {:>8} entries  {:>10} bytes",
+                kind,
+                stats.count,
+                stats.bytes,
+                width = width
+            )?;
+        }
+        writeln!(
+            f,
+            "Unknown schema: {} entries, {} bytes",
+            self.unknown_schema.count, self.unknown_schema.bytes
+        )?;
+        write!(
+            f,
+            "Decode errors: {} entries, {} bytes",
+            self.decode_errors.count, self.decode_errors.bytes
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash_utils::{mock_flash, MOCK_FLASH_RANGE};
+    use futures::executor::block_on;
+    use postcard_schema::key::hash::fnv1a64_owned::hash_ty_path_owned;
+    use postcard_schema::schema::owned::{OwnedData, OwnedVariant};
+    use sequential_storage::cache::NoCache;
+    use sequential_storage::queue;
+
+    #[test]
+    fn test_partition_stats() {
+        let schema = OwnedDataModelType::Enum {
+            name: "Record".into(),
+            variants: Box::new([
+                OwnedVariant {
+                    name: "Boot".into(),
+                    data: OwnedData::Unit,
+                },
+                OwnedVariant {
+                    name: "Temperature".into(),
+                    data: OwnedData::Newtype(Box::new(OwnedDataModelType::I16)),
+                },
+            ]),
+        };
+        let hash = hash_ty_path_owned("", &schema);
+        let unknown = [0xAA; 8];
+        let tmp = tempfile::tempdir().unwrap();
+        let mut cache = Cache::new(vec![tmp.path().to_path_buf()]);
+        cache.store(&schema).unwrap();
+        let provenance = Provenance {
+            elf_path: "firmware.elf".into(),
+            ..Provenance::default()
+        };
+        cache.index_schema(&schema, &provenance).unwrap();
+
+        // Entries as written by `destore::Storer`, over three boots
+        let schema_entry = |hash: [u8; 8]| [&[0xFF][..], &hash, &[0x01]].concat();
+        let boot = vec![0x00, 0x00];
+        // Temperature(-5), the i16 zigzag encoded
+        let temperature = vec![0x00, 0x01, 0x09];
+        let entries = [
+            schema_entry(hash),
+            boot.clone(),
+            temperature.clone(),
+            vec![0xFE, 1, 2, 3],
+            // Temperature without its value
+            vec![0x00, 0x01],
+            schema_entry(hash),
+            boot,
+            temperature,
+            schema_entry(unknown),
+            vec![0x00, 0x01, 0x02],
+        ];
+        let mut flash = mock_flash();
+        let mut queue_cache = NoCache::new();
+        for entry in &entries {
+            block_on(queue::push(
+                &mut flash,
+                MOCK_FLASH_RANGE,
+                &mut queue_cache,
+                entry,
+                false,
+            ))
+            .unwrap();
+        }
+        let mut partition = flash.as_bytes().to_vec();
+
+        let stats = partition_stats(&mut partition, &cache).unwrap();
+        assert_eq!(stats.size, 4 * PAGE_SIZE);
+        assert_eq!((stats.used_pages(), stats.free_pages()), (1, 3));
+        assert!(!stats.wrapped());
+        assert_eq!(stats.sessions, 3);
+        assert_eq!(stats.schemas, [hash, unknown]);
+        assert_eq!(stats.firmwares[&hash].elf_path, provenance.elf_path);
+        assert!(!stats.firmwares.contains_key(&unknown));
+        let records: Vec<_> = stats
+            .records
+            .iter()
+            .map(|(kind, stats)| (kind.as_str(), stats.count, stats.bytes))
+            .collect();
+        assert_eq!(
+            records,
+            [
+                ("Record::Boot", 2, 2),
+                ("Record::Temperature", 2, 4),
+                ("defmt", 1, 3)
+            ]
+        );
+        assert_eq!(stats.decode_errors, EntryStats { count: 1, bytes: 1 });
+        assert_eq!(stats.unknown_schema, EntryStats { count: 1, bytes: 2 });
+    }
+}
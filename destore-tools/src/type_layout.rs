@@ -0,0 +1,287 @@
+//! Memory layout of the `postcard_schema::schema` types, as described by the DWARF debug info of an ELF file.
+//!
+//! rustc gives no guarantees about the layout of `DataModelType` and friends: field order, niche
+//! discriminants and enum sizes depend on the compiler version and the target. The debug info
+//! describes the actual layout, so the schema restorer does not need to hardcode it.
+
+use anyhow::{anyhow, bail, Context, Result};
+use gimli::{
+    constants, AttributeValue, DebuggingInformationEntry, EndianSlice, RunTimeEndian, Section,
+    SectionId, UnitOffset, UnitRef,
+};
+use goblin::elf::section_header::{SHF_COMPRESSED, SHT_NOBITS};
+use goblin::elf::Elf;
+use std::collections::HashMap;
+
+type Reader<'a> = EndianSlice<'a, RunTimeEndian>;
+
+const SCHEMA_NAMESPACE: [&str; 2] = ["postcard_schema", "schema"];
+const DATA_MODEL_TYPE: &str = "DataModelType";
+const DATA: &str = "Data";
+const VARIANT: &str = "Variant";
+const NAMED_FIELD: &str = "NamedField";
+
+/// Namespaces nested deeper are not searched, which bounds the recursion on malformed debug info
+const MAX_NAMESPACE_DEPTH: usize = 32;
+
+/// Offsets of the fields of a struct or enum variant
+#[derive(Debug, Default)]
+pub struct StructLayout {
+    fields: HashMap<String, usize>,
+}
+
+impl StructLayout {
+    pub fn field(&self, name: &str) -> Result<usize> {
+        self.fields
+            .get(name)
+            .copied()
+            .ok_or_else(|| anyhow!("field {} not described by the debug info", name))
+    }
+}
+
+#[derive(Debug)]
+pub struct VariantLayout {
+    pub name: String,
+    /// `None` for the variant that is active if no other discriminant matches
+    /// (the dataful variant of a niche optimized enum)
+    discr: Option<u64>,
+    pub fields: StructLayout,
+}
+
+/// Location of the discriminant and the layout of every variant of an enum
+#[derive(Debug)]
+pub struct EnumLayout {
+    pub discr_offset: usize,
+    pub discr_size: usize,
+    variants: Vec<VariantLayout>,
+}
+
+impl EnumLayout {
+    /// Finds the variant with the discriminant read from `discr_offset`
+    pub fn variant(&self, discr: u64) -> Result<&VariantLayout> {
+        self.variants
+            .iter()
+            .find(|v| v.discr == Some(discr))
+            .or_else(|| self.variants.iter().find(|v| v.discr.is_none()))
+            .ok_or_else(|| anyhow!("Unknown discriminant: {}", discr))
+    }
+}
+
+/// Layout of the types making up a schema
+#[derive(Debug)]
+pub struct SchemaLayout {
+    pub data_model_type: EnumLayout,
+    pub data: EnumLayout,
+    pub variant: StructLayout,
+    pub named_field: StructLayout,
+}
+
+impl SchemaLayout {
+    /// Reads the layout from the DWARF debug info. Fails if the ELF has no debug info.
+    pub fn from_elf(elf: &Elf, bytes: &[u8]) -> Result<Self> {
+        let endian = if elf.little_endian {
+            RunTimeEndian::Little
+        } else {
+            RunTimeEndian::Big
+        };
+        let dwarf = gimli::Dwarf::load(|id| -> Result<Reader> {
+            Ok(EndianSlice::new(section_data(elf, bytes, id)?, endian))
+        })?;
+        if dwarf.debug_info.reader().is_empty() {
+            bail!("ELF file has no debug info (.debug_info), build it with `debug = true` to restore schemas");
+        }
+
+        let mut units = dwarf.units();
+        while let Some(header) = units.next()? {
+            let unit = dwarf.unit(header)?;
+            let unit = unit.unit_ref(&dwarf);
+
+            let mut offsets = HashMap::new();
+            let mut tree = unit.entries_tree(None)?;
+            find_schema_types(unit, tree.root()?, &mut Vec::new(), &mut offsets)?;
+            let [Some(data_model_type), Some(data), Some(variant), Some(named_field)] =
+                [DATA_MODEL_TYPE, DATA, VARIANT, NAMED_FIELD].map(|name| offsets.get(name))
+            else {
+                continue;
+            };
+
+            return Ok(Self {
+                data_model_type: parse_enum(unit, *data_model_type)
+                    .context("Failed to read layout of DataModelType")?,
+                data: parse_enum(unit, *data).context("Failed to read layout of Data")?,
+                variant: parse_struct(unit, *variant)
+                    .context("Failed to read layout of Variant")?,
+                named_field: parse_struct(unit, *named_field)
+                    .context("Failed to read layout of NamedField")?,
+            });
+        }
+        bail!("Debug info does not describe the postcard_schema::schema types")
+    }
+}
+
+fn section_data<'a>(elf: &Elf, bytes: &'a [u8], id: SectionId) -> Result<&'a [u8]> {
+    let Some(section) = elf
+        .section_headers
+        .iter()
+        .find(|sh| elf.shdr_strtab.get_at(sh.sh_name) == Some(id.name()))
+    else {
+        return Ok(&[]);
+    };
+    if section.sh_type == SHT_NOBITS {
+        return Ok(&[]);
+    }
+    if section.sh_flags & SHF_COMPRESSED as u64 != 0 {
+        bail!("Compressed debug info is not supported ({})", id.name());
+    }
+    usize::try_from(section.sh_offset)
+        .ok()
+        .zip(usize::try_from(section.sh_size).ok())
+        .and_then(|(start, size)| bytes.get(start..start.checked_add(size)?))
+        .with_context(|| format!("Section {} out of bounds", id.name()))
+}
+
+/// Collects the offsets of the definitions of the schema types, which live in the `postcard_schema::schema` namespace
+fn find_schema_types<'a>(
+    unit: UnitRef<Reader<'a>>,
+    node: gimli::EntriesTreeNode<Reader<'a>>,
+    path: &mut Vec<String>,
+    offsets: &mut HashMap<String, UnitOffset>,
+) -> Result<()> {
+    let mut children = node.children();
+    while let Some(child) = children.next()? {
+        let entry = child.entry();
+        match entry.tag() {
+            constants::DW_TAG_namespace if path.len() < MAX_NAMESPACE_DEPTH => {
+                path.push(entry_name(unit, entry)?.unwrap_or_default());
+                find_schema_types(unit, child, path, offsets)?;
+                path.pop();
+            }
+            constants::DW_TAG_structure_type
+                if path.iter().eq(SCHEMA_NAMESPACE.iter())
+                    && entry.attr(constants::DW_AT_declaration)?.is_none() =>
+            {
+                if let Some(name) = entry_name(unit, entry)? {
+                    offsets.entry(name).or_insert(entry.offset());
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn parse_struct<'a>(unit: UnitRef<Reader<'a>>, offset: UnitOffset) -> Result<StructLayout> {
+    let mut tree = unit.entries_tree(Some(offset))?;
+    let mut children = tree.root()?.children();
+    let mut layout = StructLayout::default();
+    while let Some(child) = children.next()? {
+        let entry = child.entry();
+        if entry.tag() == constants::DW_TAG_member {
+            let name = entry_name(unit, entry)?.context("Unnamed member")?;
+            layout.fields.insert(name, member_offset(entry)?);
+        }
+    }
+    Ok(layout)
+}
+
+/// Rust enums with data are described as a struct containing a variant part, where every variant
+/// has a single member of a struct type holding the fields of the variant
+fn parse_enum<'a>(unit: UnitRef<Reader<'a>>, offset: UnitOffset) -> Result<EnumLayout> {
+    let mut tree = unit.entries_tree(Some(offset))?;
+    let mut children = tree.root()?.children();
+    while let Some(child) = children.next()? {
+        if child.entry().tag() != constants::DW_TAG_variant_part {
+            continue;
+        }
+
+        let Some(AttributeValue::UnitRef(discr)) =
+            child.entry().attr_value(constants::DW_AT_discr)?
+        else {
+            bail!("Variant part without discriminant");
+        };
+        let discr = unit.entry(discr)?;
+        let discr_offset = member_offset(&discr)?;
+        let discr_size = type_size(unit, &discr)?;
+        if discr_size == 0 || discr_size > 8 {
+            bail!("Unsupported discriminant size: {}", discr_size);
+        }
+
+        let mut variants = Vec::new();
+        let mut parts = child.children();
+        while let Some(part) = parts.next()? {
+            if part.entry().tag() != constants::DW_TAG_variant {
+                continue;
+            }
+            let discr = part
+                .entry()
+                .attr_value(constants::DW_AT_discr_value)?
+                .map(|value| match value {
+                    AttributeValue::Sdata(value) => Some(value as u64),
+                    value => value.udata_value(),
+                })
+                .map(|value| value.context("Unsupported discriminant value"))
+                .transpose()?
+                .map(|value| value & (u64::MAX >> (64 - 8 * discr_size)));
+
+            let mut members = part.children();
+            let member = members.next()?.context("Variant without member")?;
+            let member = member.entry();
+            let name = entry_name(unit, member)?.context("Unnamed variant")?;
+            let Some(AttributeValue::UnitRef(ty)) = member.attr_value(constants::DW_AT_type)?
+            else {
+                bail!("Variant {} without type", name);
+            };
+            let base = member_offset(member)?;
+            let mut fields = parse_struct(unit, ty)?;
+            for offset in fields.fields.values_mut() {
+                *offset = offset
+                    .checked_add(base)
+                    .with_context(|| format!("Offset of a field of variant {} overflows", name))?;
+            }
+            variants.push(VariantLayout {
+                name,
+                discr,
+                fields,
+            });
+        }
+
+        return Ok(EnumLayout {
+            discr_offset,
+            discr_size,
+            variants,
+        });
+    }
+    bail!("Enum without variant part")
+}
+
+fn entry_name<'a>(
+    unit: UnitRef<Reader<'a>>,
+    entry: &DebuggingInformationEntry<Reader<'a>>,
+) -> Result<Option<String>> {
+    entry
+        .attr_value(constants::DW_AT_name)?
+        .map(|name| Ok(unit.attr_string(name)?.to_string()?.to_owned()))
+        .transpose()
+}
+
+fn member_offset(entry: &DebuggingInformationEntry<Reader>) -> Result<usize> {
+    entry
+        .attr_value(constants::DW_AT_data_member_location)?
+        .and_then(|value| value.udata_value())
+        .map(|offset| offset as usize)
+        .context("Member without constant offset")
+}
+
+fn type_size<'a>(
+    unit: UnitRef<Reader<'a>>,
+    entry: &DebuggingInformationEntry<Reader<'a>>,
+) -> Result<usize> {
+    let Some(AttributeValue::UnitRef(ty)) = entry.attr_value(constants::DW_AT_type)? else {
+        bail!("Member without type");
+    };
+    unit.entry(ty)?
+        .attr_value(constants::DW_AT_byte_size)?
+        .and_then(|value| value.udata_value())
+        .map(|size| size as usize)
+        .context("Type without size")
+}
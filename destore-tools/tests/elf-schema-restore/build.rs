@@ -1,4 +1,6 @@
 fn main() {
-    println!("cargo:rustc-link-arg=-Tdestore.x");
-    println!("cargo:rustc-link-arg=-Tlinkall.x");
+    // Keeps the schemas, there is no other linker script on bare metal targets
+    if std::env::var("CARGO_CFG_TARGET_OS").unwrap() == "none" {
+        println!("cargo:rustc-link-arg=-Tdestore.x");
+    }
 }
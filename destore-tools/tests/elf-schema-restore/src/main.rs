@@ -1,36 +1,44 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(target_os = "none", no_std)]
+#![cfg_attr(target_os = "none", no_main)]
 
 extern crate alloc;
 use alloc::string::String;
-use esp_hal::init as _;
+use postcard_schema::schema::{Data, DataModelType, NamedField};
 use postcard_schema::Schema;
 
-#[panic_handler]
-fn panic(_info: &core::panic::PanicInfo) -> ! {
-    loop {}
-}
+// No runtime or HAL crate, so that the fixture builds for every target.
+// It is never run, only the schemas in the ELF are of interest.
+#[cfg(target_os = "none")]
+mod bare_metal {
+    #[panic_handler]
+    fn panic(_info: &core::panic::PanicInfo) -> ! {
+        loop {}
+    }
 
-use core::alloc::{GlobalAlloc, Layout};
-use core::ptr::null_mut;
+    use core::alloc::{GlobalAlloc, Layout};
+    use core::ptr::null_mut;
 
-struct DummyAllocator;
+    struct DummyAllocator;
 
-unsafe impl GlobalAlloc for DummyAllocator {
-    unsafe fn alloc(&self, _layout: Layout) -> *mut u8 {
-        null_mut()
+    unsafe impl GlobalAlloc for DummyAllocator {
+        unsafe fn alloc(&self, _layout: Layout) -> *mut u8 {
+            null_mut()
+        }
+        unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
     }
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
-}
 
-#[global_allocator]
-static ALLOC: DummyAllocator = DummyAllocator;
+    #[global_allocator]
+    static ALLOC: DummyAllocator = DummyAllocator;
 
-#[esp_hal::main]
-fn main() -> ! {
-    loop {}
+    #[no_mangle]
+    extern "C" fn _start() -> ! {
+        loop {}
+    }
 }
 
+#[cfg(not(target_os = "none"))]
+fn main() {}
+
 #[allow(unused)]
 #[derive(Schema)]
 struct UnitStruct;
@@ -67,6 +75,90 @@ struct ClassicGen<T: Schema> {
     b: T,
 }
 
+// postcard-schema implements Schema neither for usize/isize nor for fields serialized with
+// serde_bytes, so these are written by hand
+
+#[allow(unused)]
+struct Usize(usize);
+
+impl Schema for Usize {
+    const SCHEMA: &'static DataModelType = &DataModelType::Usize;
+}
+
+#[allow(unused)]
+struct Isize(isize);
+
+impl Schema for Isize {
+    const SCHEMA: &'static DataModelType = &DataModelType::Isize;
+}
+
+#[allow(unused)]
+#[derive(serde::Serialize)]
+struct ByteArray(#[serde(with = "serde_bytes")] alloc::vec::Vec<u8>);
+
+impl Schema for ByteArray {
+    const SCHEMA: &'static DataModelType = &DataModelType::ByteArray;
+}
+
+#[allow(unused)]
+#[derive(serde::Serialize)]
+struct Blob {
+    #[serde(with = "serde_bytes")]
+    data: alloc::vec::Vec<u8>,
+    len: usize,
+    offset: isize,
+}
+
+impl Schema for Blob {
+    const SCHEMA: &'static DataModelType = &DataModelType::Struct {
+        name: "Blob",
+        data: Data::Struct(&[
+            &NamedField {
+                name: "data",
+                ty: &DataModelType::ByteArray,
+            },
+            &NamedField {
+                name: "len",
+                ty: &DataModelType::Usize,
+            },
+            &NamedField {
+                name: "offset",
+                ty: &DataModelType::Isize,
+            },
+        ]),
+    };
+}
+
+#[allow(unused)]
+#[derive(Schema)]
+struct Buffer {
+    data: heapless::Vec<u8, 8>,
+}
+
+// Exported by the derive as `_DESTORE_SCHEMA_elf_schema_restore_test::Derived`
+#[allow(unused)]
+#[derive(serde::Serialize, destore::DestoreRecord)]
+enum Derived {
+    /// Written once after reset
+    Boot,
+    Reading {
+        #[destore(format = "hex")]
+        sensor: u8,
+        #[destore(unit = "°C", scale = 0.01)]
+        value: i16,
+    },
+    Raw(u32, u32),
+}
+
+// Exported by the derive as `_DESTORE_SCHEMA_elf_schema_restore_test::Frame`
+#[allow(unused)]
+#[derive(serde::Serialize, destore::DestoreRecord)]
+struct Frame {
+    #[serde(with = "serde_bytes")]
+    payload: alloc::vec::Vec<u8>,
+    checksum: u16,
+}
+
 destore::export_schema!(_DESTORE_SCHEMA_BOOL, bool);
 destore::export_schema!(_DESTORE_SCHEMA_I8, i8);
 destore::export_schema!(_DESTORE_SCHEMA_U8, u8);
@@ -78,13 +170,13 @@ destore::export_schema!(_DESTORE_SCHEMA_U16, u16);
 destore::export_schema!(_DESTORE_SCHEMA_U32, u32);
 destore::export_schema!(_DESTORE_SCHEMA_U64, u64);
 destore::export_schema!(_DESTORE_SCHEMA_U128, u128);
-//destore::export_schema!(_DESTORE_SCHEMA_USIZE, usize); // Schema not implemented for usize ?!
-//destore::export_schema!(_DESTORE_SCHEMA_ISIZE, isize); // Schema not implemented for isize ?!
+destore::export_schema!(_DESTORE_SCHEMA_USIZE, Usize);
+destore::export_schema!(_DESTORE_SCHEMA_ISIZE, Isize);
 destore::export_schema!(_DESTORE_SCHEMA_F32, f32);
 destore::export_schema!(_DESTORE_SCHEMA_F64, f64);
 destore::export_schema!(_DESTORE_SCHEMA_CHAR, char);
 destore::export_schema!(_DESTORE_SCHEMA_STRING, String);
-//destore::export_schema!(_DESTORE_SCHEMA_BYTEARRAY, &[u8]); // only ever used for uuids. &[u8] is type seq instead
+destore::export_schema!(_DESTORE_SCHEMA_BYTEARRAY, ByteArray);
 destore::export_schema!(_DESTORE_SCHEMA_OPTION, Option<u8>);
 destore::export_schema!(_DESTORE_SCHEMA_UNIT, ());
 destore::export_schema!(_DESTORE_SCHEMA_SEQUENCE, &[u16]);
@@ -95,7 +187,10 @@ destore::export_schema!(_DESTORE_SCHEMA_NEWTYPESTRUCT, NewTypeStruct);
 destore::export_schema!(_DESTORE_SCHEMA_TUPLESTRUCT, TupStruct);
 destore::export_schema!(_DESTORE_SCHEMA_STRUCTSTRUCT, Classic);
 destore::export_schema!(_DESTORE_SCHEMA_ENUM, Enums);
+destore::export_schema!(_DESTORE_SCHEMA_SERDE_BYTES, Blob);
+destore::export_schema!(_DESTORE_SCHEMA_HEAPLESS, Buffer);
 destore::export_schema!(
     _DESTORE_SCHEMA_SCHEMA,
     postcard_schema::schema::DataModelType
 );
+destore::export_log!(EVENTS: Enums, 0x1000..0x3000, "Test log");
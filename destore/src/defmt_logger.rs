@@ -0,0 +1,192 @@
+//! A `#[defmt::global_logger]` storing the log frames in a destore partition, so that they survive
+//! resets. Enabled by the `defmt` feature.
+//!
+//! Logging only copies the frame into a RAM buffer, a task writes the buffered frames to the flash
+//! with [`run`] (or [`flush`]). The host decodes them with the `.defmt` table of the ELF:
+//!
+//! ```ignore
+//! #[embassy_executor::task]
+//! async fn log_task(mut storer: Storer<FlashStorage, Record>) {
+//!     destore::defmt_logger::run(&mut storer).await.unwrap();
+//! }
+//! ```
+
+use crate::pending::{Buffer, Pending};
+use crate::Storer;
+use alloc::vec::Vec;
+use core::cell::{Cell, RefCell};
+use core::convert::Infallible;
+use critical_section::{Mutex, RestoreState};
+use embedded_storage_async::nor_flash::NorFlash;
+use postcard_schema::Schema;
+use serde::Serialize;
+
+/// Size of the RAM buffer holding the frames until they are written to the flash
+pub const BUFFER_SIZE: usize = 1024;
+
+static PENDING: Pending<FrameBuffer> = Pending::new(FrameBuffer::new());
+
+/// Number of frames being logged, more than one if formatting a frame logs or panics
+static DEPTH: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+static RESTORE: Mutex<RefCell<Option<RestoreState>>> = Mutex::new(RefCell::new(None));
+
+#[defmt::global_logger]
+struct FlashLogger;
+
+unsafe impl defmt::Logger for FlashLogger {
+    fn acquire() {
+        // Released in `release`, so a frame is not interleaved with frames of interrupts
+        let restore = unsafe { critical_section::acquire() };
+        let nested = critical_section::with(|cs| {
+            let depth = DEPTH.borrow(cs);
+            depth.set(depth.get() + 1);
+            if depth.get() > 1 {
+                return true;
+            }
+            *RESTORE.borrow_ref_mut(cs) = Some(restore);
+            PENDING.buffer(cs).start_frame();
+            false
+        });
+        if nested {
+            // The frame is dropped, the outer frame keeps the critical section until its release
+            unsafe { critical_section::release(restore) };
+        }
+    }
+
+    unsafe fn flush() {}
+
+    unsafe fn release() {
+        let restore = critical_section::with(|cs| {
+            let depth = DEPTH.borrow(cs);
+            depth.set(depth.get().saturating_sub(1));
+            if depth.get() > 0 {
+                PENDING.commit(cs, false);
+                return None;
+            }
+            let added = PENDING.buffer(cs).end_frame();
+            PENDING.commit(cs, added);
+            RESTORE.borrow_ref_mut(cs).take()
+        });
+        if let Some(restore) = restore {
+            unsafe { critical_section::release(restore) };
+        }
+    }
+
+    unsafe fn write(bytes: &[u8]) {
+        critical_section::with(|cs| {
+            // Bytes of a nested frame would corrupt the outer one
+            if DEPTH.borrow(cs).get() == 1 {
+                PENDING.buffer(cs).write(bytes);
+            }
+        });
+    }
+}
+
+/// Writes the frames as they are logged. Only returns if writing to the flash fails.
+pub async fn run<F: NorFlash, T: Schema + Serialize>(
+    storer: &mut Storer<F, T>,
+) -> Result<Infallible, sequential_storage::Error<F::Error>> {
+    loop {
+        PENDING.wait().await;
+        flush(storer).await?;
+    }
+}
+
+/// Writes the frames logged so far, e.g. before a reset
+pub async fn flush<F: NorFlash, T: Schema + Serialize>(
+    storer: &mut Storer<F, T>,
+) -> Result<(), sequential_storage::Error<F::Error>> {
+    while let Some(frame) = PENDING.pop() {
+        storer.write_defmt_frame(&frame).await?;
+    }
+    Ok(())
+}
+
+/// Number of frames that were dropped because the buffer was full or they were logged while
+/// logging another frame
+pub fn dropped_frames() -> u32 {
+    PENDING.dropped()
+}
+
+/// Ring buffer of frames, each prefixed with its length as `u16`
+struct FrameBuffer {
+    bytes: [u8; BUFFER_SIZE],
+    /// Start of the oldest frame
+    head: usize,
+    /// Length of the complete frames, including their prefixes
+    len: usize,
+    /// Length of the frame being logged, `None` if it does not fit
+    pending: Option<usize>,
+}
+
+impl FrameBuffer {
+    const fn new() -> Self {
+        Self {
+            bytes: [0; BUFFER_SIZE],
+            head: 0,
+            len: 0,
+            pending: None,
+        }
+    }
+
+    fn start_frame(&mut self) {
+        self.pending = Some(0);
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        let Some(pending) = self.pending else {
+            return;
+        };
+        if self.len + 2 + pending + bytes.len() > BUFFER_SIZE {
+            self.pending = None;
+            return;
+        }
+        self.put(self.len + 2 + pending, bytes);
+        self.pending = Some(pending + bytes.len());
+    }
+
+    /// Commits the frame, returns false if it was dropped
+    fn end_frame(&mut self) -> bool {
+        let Some(pending) = self.pending.take() else {
+            return false;
+        };
+        self.put(self.len, &(pending as u16).to_le_bytes());
+        self.len += 2 + pending;
+        true
+    }
+
+    /// Copies `bytes` to `offset` bytes after the head
+    fn put(&mut self, offset: usize, bytes: &[u8]) {
+        for (i, byte) in bytes.iter().enumerate() {
+            self.bytes[(self.head + offset + i) % BUFFER_SIZE] = *byte;
+        }
+    }
+
+    fn get(&self, offset: usize, bytes: &mut [u8]) {
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = self.bytes[(self.head + offset + i) % BUFFER_SIZE];
+        }
+    }
+}
+
+impl Buffer for FrameBuffer {
+    type Entry = Vec<u8>;
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn pop(&mut self) -> Option<Vec<u8>> {
+        if self.is_empty() {
+            return None;
+        }
+        let mut len = [0; 2];
+        self.get(0, &mut len);
+        let len = u16::from_le_bytes(len) as usize;
+        let mut frame = alloc::vec![0; len];
+        self.get(2, &mut frame);
+        self.head = (self.head + 2 + len) % BUFFER_SIZE;
+        self.len -= 2 + len;
+        Some(frame)
+    }
+}
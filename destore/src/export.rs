@@ -0,0 +1,326 @@
+use core::marker::PhantomData;
+use core::ops::Range;
+use postcard_schema::schema::{Data, DataModelType, NamedField, Variant};
+use postcard_schema::Schema;
+
+/// What `export_schema!` places in the `.destore.schema` section.
+///
+/// `ty` points to the schema in memory and is only used as a fallback by hosts that restore the schema from the
+/// in-memory layout of `DataModelType`. `bytes` holds the postcard serialization of the schema
+/// (as `OwnedDataModelType`), computed at compile time, which the host can deserialize independently of the
+/// layout rustc chose for the target.
+#[repr(C)]
+pub struct ExportedSchema<const N: usize> {
+    pub ty: &'static DataModelType,
+    pub len: u32,
+    pub bytes: [u8; N],
+}
+
+impl<const N: usize> ExportedSchema<N> {
+    /// `N` must be [`serialized_schema_len`] of `ty`
+    pub const fn new(ty: &'static DataModelType) -> Self {
+        let mut bytes = [0; N];
+        let len = write_ty(&mut bytes, 0, ty);
+        assert!(len == N, "serialized schema length mismatch");
+        Self {
+            ty,
+            len: N as u32,
+            bytes,
+        }
+    }
+}
+
+/// Length of the postcard serialization of a schema
+pub const fn serialized_schema_len(ty: &DataModelType) -> usize {
+    // Writing to an empty buffer only counts the bytes
+    write_ty(&mut [], 0, ty)
+}
+
+/// A named log: a flash range holding records of type `T`. Declared with [`export_log!`](crate::export_log).
+pub struct Log<T> {
+    pub name: &'static str,
+    pub flash_range: Range<u32>,
+    /// Free text shown by the host tools
+    pub description: &'static str,
+    record: PhantomData<fn() -> T>,
+}
+
+impl<T: Schema> Log<T> {
+    /// Use [`export_log!`](crate::export_log) instead, which also exports the log
+    #[doc(hidden)]
+    pub const fn new(
+        name: &'static str,
+        flash_range: Range<u32>,
+        description: &'static str,
+    ) -> Self {
+        Self {
+            name,
+            flash_range,
+            description,
+            record: PhantomData,
+        }
+    }
+}
+
+/// What `export_log!` places in the `.destore.log` section: the postcard serialization of the log
+/// (name, flash range, description and record schema), computed at compile time.
+#[repr(C)]
+pub struct ExportedLog<const N: usize> {
+    pub len: u32,
+    pub bytes: [u8; N],
+}
+
+impl<const N: usize> ExportedLog<N> {
+    /// `N` must be [`serialized_log_len`] of `log`
+    pub const fn new<T: Schema>(log: &Log<T>) -> Self {
+        let mut bytes = [0; N];
+        let len = write_log(&mut bytes, 0, log);
+        assert!(len == N, "serialized log length mismatch");
+        Self {
+            len: N as u32,
+            bytes,
+        }
+    }
+}
+
+/// Length of the postcard serialization of a log
+pub const fn serialized_log_len<T: Schema>(log: &Log<T>) -> usize {
+    write_log(&mut [], 0, log)
+}
+
+const fn write_log<T: Schema>(buf: &mut [u8], pos: usize, log: &Log<T>) -> usize {
+    let pos = write_str(buf, pos, log.name);
+    let pos = write_varint(buf, pos, log.flash_range.start as usize);
+    let pos = write_varint(buf, pos, log.flash_range.end as usize);
+    let pos = write_str(buf, pos, log.description);
+    write_ty(buf, pos, T::SCHEMA)
+}
+
+/// Annotations of the fields and variants of a type, which postcard-schema cannot express.
+/// Exported by `#[derive(DestoreRecord)]` from `#[destore(...)]` attributes and doc comments.
+pub struct TypeMetadata {
+    pub name: &'static str,
+    /// Hash of the schema of the type, telling apart types of the same name in different modules
+    pub schema_hash: [u8; 8],
+    pub fields: &'static [FieldMetadata],
+}
+
+/// Annotation of a field, or of a variant if `field` is `None`. Unnamed fields are named by their index.
+pub struct FieldMetadata {
+    pub variant: Option<&'static str>,
+    pub field: Option<&'static str>,
+    pub unit: Option<&'static str>,
+    /// Factor converting the stored value into `unit`, e.g. `0.01` for a temperature stored in centidegrees
+    pub scale: Option<f64>,
+    /// How the host displays integers, `"hex"` or `"bin"`
+    pub format: Option<&'static str>,
+    pub description: Option<&'static str>,
+}
+
+/// What `#[derive(DestoreRecord)]` places in the `.destore.meta` section: the postcard serialization of the
+/// [`TypeMetadata`], computed at compile time.
+#[repr(C)]
+pub struct ExportedMetadata<const N: usize> {
+    pub len: u32,
+    pub bytes: [u8; N],
+}
+
+impl<const N: usize> ExportedMetadata<N> {
+    /// `N` must be [`serialized_metadata_len`] of `metadata`
+    pub const fn new(metadata: &TypeMetadata) -> Self {
+        let mut bytes = [0; N];
+        let len = write_metadata(&mut bytes, 0, metadata);
+        assert!(len == N, "serialized metadata length mismatch");
+        Self {
+            len: N as u32,
+            bytes,
+        }
+    }
+}
+
+/// Length of the postcard serialization of type metadata
+pub const fn serialized_metadata_len(metadata: &TypeMetadata) -> usize {
+    write_metadata(&mut [], 0, metadata)
+}
+
+const fn write_metadata(buf: &mut [u8], pos: usize, metadata: &TypeMetadata) -> usize {
+    let pos = write_str(buf, pos, metadata.name);
+    let pos = write_array(buf, pos, &metadata.schema_hash);
+    let mut pos = write_varint(buf, pos, metadata.fields.len());
+    let mut i = 0;
+    while i < metadata.fields.len() {
+        let field = &metadata.fields[i];
+        pos = write_option_str(buf, pos, field.variant);
+        pos = write_option_str(buf, pos, field.field);
+        pos = write_option_str(buf, pos, field.unit);
+        pos = match field.scale {
+            Some(scale) => {
+                let pos = write_byte(buf, pos, 1);
+                write_f64(buf, pos, scale)
+            }
+            None => write_byte(buf, pos, 0),
+        };
+        pos = write_option_str(buf, pos, field.format);
+        pos = write_option_str(buf, pos, field.description);
+        i += 1;
+    }
+    pos
+}
+
+const fn write_option_str(buf: &mut [u8], pos: usize, s: Option<&str>) -> usize {
+    match s {
+        Some(s) => {
+            let pos = write_byte(buf, pos, 1);
+            write_str(buf, pos, s)
+        }
+        None => write_byte(buf, pos, 0),
+    }
+}
+
+const fn write_f64(buf: &mut [u8], pos: usize, value: f64) -> usize {
+    write_array(buf, pos, &value.to_bits().to_le_bytes())
+}
+
+/// Writes bytes without length, like postcard serializes arrays
+const fn write_array(buf: &mut [u8], pos: usize, bytes: &[u8]) -> usize {
+    let mut pos = pos;
+    let mut i = 0;
+    while i < bytes.len() {
+        pos = write_byte(buf, pos, bytes[i]);
+        i += 1;
+    }
+    pos
+}
+
+// The functions below serialize a `DataModelType` exactly like serde + postcard serialize the
+// corresponding `OwnedDataModelType`: variant indices and lengths as varints, fields in declaration order.
+// They return the position after the written value and skip writing past the end of `buf`.
+
+const fn write_ty(buf: &mut [u8], pos: usize, ty: &DataModelType) -> usize {
+    match ty {
+        DataModelType::Bool => write_varint(buf, pos, 0),
+        DataModelType::I8 => write_varint(buf, pos, 1),
+        DataModelType::U8 => write_varint(buf, pos, 2),
+        DataModelType::I16 => write_varint(buf, pos, 3),
+        DataModelType::I32 => write_varint(buf, pos, 4),
+        DataModelType::I64 => write_varint(buf, pos, 5),
+        DataModelType::I128 => write_varint(buf, pos, 6),
+        DataModelType::U16 => write_varint(buf, pos, 7),
+        DataModelType::U32 => write_varint(buf, pos, 8),
+        DataModelType::U64 => write_varint(buf, pos, 9),
+        DataModelType::U128 => write_varint(buf, pos, 10),
+        DataModelType::Usize => write_varint(buf, pos, 11),
+        DataModelType::Isize => write_varint(buf, pos, 12),
+        DataModelType::F32 => write_varint(buf, pos, 13),
+        DataModelType::F64 => write_varint(buf, pos, 14),
+        DataModelType::Char => write_varint(buf, pos, 15),
+        DataModelType::String => write_varint(buf, pos, 16),
+        DataModelType::ByteArray => write_varint(buf, pos, 17),
+        DataModelType::Option(inner) => {
+            let pos = write_varint(buf, pos, 18);
+            write_ty(buf, pos, inner)
+        }
+        DataModelType::Unit => write_varint(buf, pos, 19),
+        DataModelType::Seq(inner) => {
+            let pos = write_varint(buf, pos, 20);
+            write_ty(buf, pos, inner)
+        }
+        DataModelType::Tuple(types) => {
+            let pos = write_varint(buf, pos, 21);
+            write_types(buf, pos, types)
+        }
+        DataModelType::Map { key, val } => {
+            let pos = write_varint(buf, pos, 22);
+            let pos = write_ty(buf, pos, key);
+            write_ty(buf, pos, val)
+        }
+        DataModelType::Struct { name, data } => {
+            let pos = write_varint(buf, pos, 23);
+            let pos = write_str(buf, pos, name);
+            write_data(buf, pos, data)
+        }
+        DataModelType::Enum { name, variants } => {
+            let pos = write_varint(buf, pos, 24);
+            let pos = write_str(buf, pos, name);
+            write_variants(buf, pos, variants)
+        }
+        DataModelType::Schema => write_varint(buf, pos, 25),
+    }
+}
+
+const fn write_data(buf: &mut [u8], pos: usize, data: &Data) -> usize {
+    match data {
+        Data::Unit => write_varint(buf, pos, 0),
+        Data::Newtype(inner) => {
+            let pos = write_varint(buf, pos, 1);
+            write_ty(buf, pos, inner)
+        }
+        Data::Tuple(types) => {
+            let pos = write_varint(buf, pos, 2);
+            write_types(buf, pos, types)
+        }
+        Data::Struct(fields) => {
+            let pos = write_varint(buf, pos, 3);
+            write_fields(buf, pos, fields)
+        }
+    }
+}
+
+const fn write_types(buf: &mut [u8], pos: usize, types: &[&DataModelType]) -> usize {
+    let mut pos = write_varint(buf, pos, types.len());
+    let mut i = 0;
+    while i < types.len() {
+        pos = write_ty(buf, pos, types[i]);
+        i += 1;
+    }
+    pos
+}
+
+const fn write_fields(buf: &mut [u8], pos: usize, fields: &[&NamedField]) -> usize {
+    let mut pos = write_varint(buf, pos, fields.len());
+    let mut i = 0;
+    while i < fields.len() {
+        pos = write_str(buf, pos, fields[i].name);
+        pos = write_ty(buf, pos, fields[i].ty);
+        i += 1;
+    }
+    pos
+}
+
+const fn write_variants(buf: &mut [u8], pos: usize, variants: &[&Variant]) -> usize {
+    let mut pos = write_varint(buf, pos, variants.len());
+    let mut i = 0;
+    while i < variants.len() {
+        pos = write_str(buf, pos, variants[i].name);
+        pos = write_data(buf, pos, &variants[i].data);
+        i += 1;
+    }
+    pos
+}
+
+const fn write_str(buf: &mut [u8], pos: usize, s: &str) -> usize {
+    let bytes = s.as_bytes();
+    let mut pos = write_varint(buf, pos, bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        pos = write_byte(buf, pos, bytes[i]);
+        i += 1;
+    }
+    pos
+}
+
+const fn write_varint(buf: &mut [u8], pos: usize, mut value: usize) -> usize {
+    let mut pos = pos;
+    while value >= 0x80 {
+        pos = write_byte(buf, pos, (value as u8) | 0x80);
+        value >>= 7;
+    }
+    write_byte(buf, pos, value as u8)
+}
+
+const fn write_byte(buf: &mut [u8], pos: usize, byte: u8) -> usize {
+    if pos < buf.len() {
+        buf[pos] = byte;
+    }
+    pos + 1
+}
@@ -1,5 +1,10 @@
 #![no_std]
 
+extern crate alloc;
+// Allows `#[derive(DestoreRecord)]` within this crate
+extern crate self as destore;
+
+use alloc::vec::Vec;
 use core::marker::PhantomData;
 use core::ops::Range;
 use embedded_storage_async::nor_flash::NorFlash;
@@ -7,50 +12,188 @@ use postcard_schema::key::hash::fnv1a64::hash_ty_path;
 use sequential_storage::cache::NoCache;
 use serde::Serialize;
 
+mod export;
+pub use export::*;
+
+#[cfg(any(feature = "defmt", feature = "log"))]
+mod pending;
+
+#[cfg(feature = "defmt")]
+pub mod defmt_logger;
+
+#[cfg(feature = "log")]
+pub mod log_backend;
+
+#[cfg(feature = "panic")]
+pub mod panic;
+
 // Reexports needed by macro below
 pub use postcard_schema::schema::DataModelType;
 pub use postcard_schema::Schema;
 
+pub use destore_macros::DestoreRecord;
+
+// Used by the code generated by the derive macros
+#[doc(hidden)]
+pub mod __private {
+    pub use postcard_schema;
+    pub use postcard_schema::key::hash::fnv1a64::hash_ty_path;
+    pub use serde;
+
+    /// Required by `#[derive(DestoreRecord)]`, for an error naming the missing derive
+    #[diagnostic::on_unimplemented(
+        message = "`{Self}` must implement `serde::Serialize` to derive `DestoreRecord`",
+        note = "add `#[derive(serde::Serialize)]` to `{Self}`"
+    )]
+    pub trait SerializeRecord {}
+
+    impl<T: serde::Serialize> SerializeRecord for T {}
+}
+
 /// Exports the schema of a type to a special section in the binary.
-/// Type passed must implement `postcard_schema::Schema`
+/// Type passed must implement `postcard_schema::Schema`.
+///
+/// The schema is exported as [`ExportedSchema`]: a pointer to the in-memory schema followed by its postcard
+/// serialization, computed at compile time.
 #[macro_export]
 macro_rules! export_schema {
-    /// Exports the schema of a type to a special section in the binary.
-    /// Can only be used once. Use this to export the root record type of your crate.
+    // Used by `#[derive(DestoreRecord)]`, which exports every type under its own symbol
+    (@export_name $name:expr, $val:ty) => {
+        const _: () = {
+            #[link_section = ".destore.schema"]
+            #[used]
+            #[export_name = $name]
+            static SCHEMA: $crate::ExportedSchema<
+                { $crate::serialized_schema_len(<$val as $crate::Schema>::SCHEMA) },
+            > = $crate::ExportedSchema::new(<$val as $crate::Schema>::SCHEMA);
+        };
+
+        // See `Storer::new`, exporting a type twice is a conflicting implementation
+        impl $crate::Exported for $val {}
+    };
+    // Exports the schema of a type to a special section in the binary.
+    // Can only be used once. Use this to export the root record type of your crate,
+    // or `export_log!` if the firmware has several logs.
     ($val:ty) => {
-        #[link_section = ".destore.schema"]
-        #[used]
-        #[no_mangle] // prevent invoking the macro multiple times
-        static _DESTORE_SCHEMA: &'static $crate::DataModelType = <$val as $crate::Schema>::SCHEMA;
+        $crate::export_schema!(_DESTORE_SCHEMA, $val);
+
+        // See `Storer::new`, exporting a type twice is a conflicting implementation
+        impl $crate::Exported for $val {}
     };
-    /// Exports the schema of a type to a custom identifier in a special section in the binary.
-    /// Mainly used for unit tests of the schema restore
+    // Exports the schema of a type to a custom identifier in a special section in the binary.
+    // Mainly used for unit tests of the schema restore, it does not implement `Exported` so that
+    // types of other crates can be exported as well
     ($id:ident, $val:ty) => {
         #[link_section = ".destore.schema"]
         #[used]
         #[no_mangle] // prevent invoking the macro multiple times
-        static $id: &'static $crate::DataModelType = <$val as $crate::Schema>::SCHEMA;
+        static $id: $crate::ExportedSchema<
+            { $crate::serialized_schema_len(<$val as $crate::Schema>::SCHEMA) },
+        > = $crate::ExportedSchema::new(<$val as $crate::Schema>::SCHEMA);
     };
 }
 
+/// Declares a named log holding records of a type in a flash range, and exports it to a special
+/// section in the binary. The host tools then know the schema and the location of every log, e.g.
+/// `destore dump --log events`.
+///
+/// Defines a [`Log`] constant to be passed to [`Storer::for_log`]:
+///
+/// ```ignore
+/// destore::export_log!(pub TELEMETRY: Telemetry, 0x620000..0x700000, "Sensor data, once per second");
+/// destore::export_log!(EVENTS: Event, 0x700000..0x720000);
+///
+/// let mut events = Storer::for_log(flash, &EVENTS).await?;
+/// ```
+#[macro_export]
+macro_rules! export_log {
+    ($vis:vis $name:ident: $val:ty, $range:expr) => {
+        $crate::export_log!($vis $name: $val, $range, "");
+    };
+    ($vis:vis $name:ident: $val:ty, $range:expr, $description:expr) => {
+        $vis const $name: $crate::Log<$val> =
+            $crate::Log::new(stringify!($name), $range, $description);
+
+        const _: () = {
+            #[link_section = ".destore.log"]
+            #[used]
+            #[export_name = concat!("_DESTORE_LOG_", stringify!($name))]
+            static LOG: $crate::ExportedLog<{ $crate::serialized_log_len(&$name) }> =
+                $crate::ExportedLog::new(&$name);
+        };
+    };
+}
+
+/// Implemented by `export_schema!` and `#[derive(DestoreRecord)]` for the exported type, so that a
+/// [`Storer`] can only be created for record types whose schema reaches the host.
+///
+/// The record type must be defined in the firmware crate, wrap types of other crates in a newtype.
+///
+/// Exporting a type twice is a conflicting implementation:
+///
+/// ```compile_fail,E0119
+/// #[derive(serde::Serialize, destore::DestoreRecord)]
+/// struct Record(u32);
+///
+/// destore::export_schema!(Record);
+/// ```
+///
+/// `#[derive(DestoreRecord)]` needs `Serialize` to be derived as well:
+///
+/// ```compile_fail,E0277
+/// #[derive(destore::DestoreRecord)]
+/// struct Record(u32);
+/// ```
+#[diagnostic::on_unimplemented(
+    message = "the schema of `{Self}` is not exported",
+    note = "add `destore::export_schema!({Self});` to the firmware"
+)]
+pub trait Exported {}
+
 pub struct Storer<F: NorFlash, T: Schema + Serialize> {
     flash: F,
     flash_range: Range<u32>,
     phantom_data: PhantomData<T>,
 }
 
+// First byte of every entry, telling the host how to read the rest
+pub(crate) const ID_DATA: u8 = 0x00;
 const ID_SCHEMA: u8 = 0xFF;
+const ID_DEFMT: u8 = 0xFE;
+// Appended to the schema entry. Older firmware wrote the schema entry without it and the data
+// entries without a kind byte, so the host can still decode their partitions.
+const FORMAT_TAGGED: u8 = 0x01;
 
 impl<F: NorFlash, T: Schema + Serialize> Storer<F, T> {
+    /// Opens the storer for records of type `T`, whose schema must be exported with [`export_schema!`]
     pub async fn new(
+        flash: F,
+        flash_range: Range<u32>,
+    ) -> Result<Self, sequential_storage::Error<F::Error>>
+    where
+        T: Exported,
+    {
+        Self::open(flash, flash_range).await
+    }
+
+    /// Opens the storer for a log declared with [`export_log!`], which exports the schema of `T`
+    pub async fn for_log(
+        flash: F,
+        log: &Log<T>,
+    ) -> Result<Self, sequential_storage::Error<F::Error>> {
+        Self::open(flash, log.flash_range.clone()).await
+    }
+
+    async fn open(
         mut flash: F,
         flash_range: Range<u32>,
     ) -> Result<Self, sequential_storage::Error<F::Error>> {
         // Store schema hash
         let hash = hash_ty_path::<T>("");
-        let mut bytes = [0; 9];
+        let mut bytes = [0; 10];
         bytes[0] = ID_SCHEMA;
         bytes[1..9].copy_from_slice(&hash);
+        bytes[9] = FORMAT_TAGGED;
         sequential_storage::queue::push(
             &mut flash,
             flash_range.clone(),
@@ -68,12 +211,30 @@ impl<F: NorFlash, T: Schema + Serialize> Storer<F, T> {
     }
 
     pub async fn write(&mut self, record: &T) -> Result<(), sequential_storage::Error<F::Error>> {
-        let bytes = postcard::to_allocvec(record).unwrap();
+        let bytes = postcard::to_extend(record, alloc::vec![ID_DATA]).unwrap();
+        self.push(&bytes).await
+    }
+
+    /// Stores a raw (not rzCOBS encoded) defmt log frame, as passed to `defmt::Logger::write`.
+    ///
+    /// The host decodes it with the `.defmt` table of the ELF that exported the schema of `T`,
+    /// interleaved with the records.
+    pub async fn write_defmt_frame(
+        &mut self,
+        frame: &[u8],
+    ) -> Result<(), sequential_storage::Error<F::Error>> {
+        let mut bytes = Vec::with_capacity(frame.len() + 1);
+        bytes.push(ID_DEFMT);
+        bytes.extend_from_slice(frame);
+        self.push(&bytes).await
+    }
+
+    async fn push(&mut self, bytes: &[u8]) -> Result<(), sequential_storage::Error<F::Error>> {
         sequential_storage::queue::push(
             &mut self.flash,
             self.flash_range.clone(),
             &mut NoCache::new(),
-            &bytes,
+            bytes,
             true,
         )
         .await
@@ -0,0 +1,154 @@
+//! A `log::Log` storing the log lines as [`LogRecord`]s, so that they survive resets. Enabled by the
+//! `log` feature.
+//!
+//! The host tools know the schema of [`LogRecord`], so the firmware need not be flashed with
+//! `destore proxy` to decode them. Logging only formats the line into a RAM buffer, a task writes the
+//! buffered records to their own log with [`run`] (or [`flush`]):
+//!
+//! ```ignore
+//! destore::export_log!(LOGS: destore::log_backend::LogRecord, 0x720000..0x740000, "log crate lines");
+//!
+//! #[embassy_executor::task]
+//! async fn log_task(flash: FlashStorage) {
+//!     let mut storer = Storer::for_log(flash, &LOGS).await.unwrap();
+//!     destore::log_backend::run(&mut storer).await.unwrap();
+//! }
+//!
+//! destore::log_backend::init(log::LevelFilter::Info).unwrap();
+//! ```
+
+use crate::pending::{Buffer, Pending};
+use crate::{DestoreRecord, Storer};
+use alloc::collections::VecDeque;
+use alloc::string::{String, ToString};
+use core::convert::Infallible;
+use embedded_storage_async::nor_flash::NorFlash;
+use log::{LevelFilter, SetLoggerError};
+use serde::Serialize;
+
+/// Number of records buffered in RAM until they are written to the flash
+pub const MAX_PENDING: usize = 32;
+
+/// A line logged with the `log` macros
+#[derive(Serialize, DestoreRecord)]
+pub struct LogRecord {
+    pub level: LogLevel,
+    /// Target of the log macro, the module path by default
+    pub target: String,
+    /// Module the line was logged from, omitted if it equals the target
+    pub module_path: Option<String>,
+    pub message: String,
+}
+
+#[derive(Serialize, DestoreRecord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<log::Level> for LogLevel {
+    fn from(level: log::Level) -> Self {
+        match level {
+            log::Level::Error => LogLevel::Error,
+            log::Level::Warn => LogLevel::Warn,
+            log::Level::Info => LogLevel::Info,
+            log::Level::Debug => LogLevel::Debug,
+            log::Level::Trace => LogLevel::Trace,
+        }
+    }
+}
+
+impl From<&log::Record<'_>> for LogRecord {
+    fn from(record: &log::Record<'_>) -> Self {
+        let target = record.target();
+        Self {
+            level: record.level().into(),
+            target: target.to_string(),
+            module_path: record
+                .module_path()
+                .filter(|module_path| *module_path != target)
+                .map(ToString::to_string),
+            message: record.args().to_string(),
+        }
+    }
+}
+
+static PENDING: Pending<VecDeque<LogRecord>> = Pending::new(VecDeque::new());
+
+static LOGGER: FlashLogger = FlashLogger;
+
+struct FlashLogger;
+
+impl log::Log for FlashLogger {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // Formatted outside of the critical section
+        let record = LogRecord::from(record);
+        critical_section::with(|cs| {
+            let added = {
+                let mut buffer = PENDING.buffer(cs);
+                let added = buffer.len() < MAX_PENDING;
+                if added {
+                    buffer.push_back(record);
+                }
+                added
+            };
+            PENDING.commit(cs, added);
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the logger, logging lines up to `level`
+pub fn init(level: LevelFilter) -> Result<(), SetLoggerError> {
+    log::set_logger(&LOGGER)?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+/// Writes each record to the log as soon as it is buffered, until writing to the flash fails
+pub async fn run<F: NorFlash>(
+    storer: &mut Storer<F, LogRecord>,
+) -> Result<Infallible, sequential_storage::Error<F::Error>> {
+    loop {
+        PENDING.wait().await;
+        flush(storer).await?;
+    }
+}
+
+/// Writes the buffered records without waiting for more
+pub async fn flush<F: NorFlash>(
+    storer: &mut Storer<F, LogRecord>,
+) -> Result<(), sequential_storage::Error<F::Error>> {
+    while let Some(record) = PENDING.pop() {
+        storer.write(&record).await?;
+    }
+    Ok(())
+}
+
+/// Number of records that were dropped because the buffer was full
+pub fn dropped_records() -> u32 {
+    PENDING.dropped()
+}
+
+impl Buffer for VecDeque<LogRecord> {
+    type Entry = LogRecord;
+
+    fn is_empty(&self) -> bool {
+        VecDeque::is_empty(self)
+    }
+
+    fn pop(&mut self) -> Option<LogRecord> {
+        self.pop_front()
+    }
+}
@@ -0,0 +1,251 @@
+//! Stores a [`PanicRecord`] in a partition when the firmware panics. Enabled by the `panic` feature,
+//! the `panic-handler` feature additionally provides the `#[panic_handler]`, which stores the record
+//! and halts.
+//!
+//! The host tools know the schema of [`PanicRecord`]. The record is written synchronously without
+//! allocating and with interrupts masked, so [`init`] takes a blocking flash:
+//!
+//! ```ignore
+//! destore::panic::init(FlashStorage::new(), 0x7E0000..0x800000);
+//! ```
+//!
+//! Firmware with its own panic handler calls [`store`] from it.
+
+use crate::{DestoreRecord, Storer, ID_DATA};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::{Cell, RefCell};
+use core::fmt::{self, Write};
+use core::future::Future;
+use core::ops::Range;
+use core::panic::PanicInfo;
+use core::pin::pin;
+use core::task::{Context, Poll, Waker};
+use critical_section::Mutex;
+use embedded_storage::nor_flash::{ErrorType, NorFlash, ReadNorFlash};
+use serde::Serialize;
+
+/// Longest panic message stored, longer ones are truncated
+pub const MAX_MESSAGE: usize = 192;
+/// Longest source file path stored, longer ones keep their end
+pub const MAX_FILE: usize = 96;
+/// Number of words of the stack stored
+pub const STACK_WORDS: usize = 32;
+/// Polls of the future writing the record before giving up. A blocking flash completes on the first.
+const MAX_POLLS: usize = 16;
+
+/// A panic of the firmware
+#[derive(Serialize, DestoreRecord)]
+pub struct PanicRecord {
+    pub message: String,
+    /// Source file of the panic
+    pub file: String,
+    pub line: u32,
+    pub column: u32,
+    /// Words of the stack above the panic handler, starting at its stack pointer and ending at the
+    /// top of the stack at the latest. The return addresses among them hint at the call chain.
+    pub stack: Vec<u32>,
+}
+
+/// Serializes like [`PanicRecord`] without allocating
+#[derive(Serialize)]
+struct PanicRecordRef<'a> {
+    message: &'a str,
+    file: &'a str,
+    line: u32,
+    column: u32,
+    stack: &'a [u32],
+}
+
+/// Writes the panic records, type erased so that it can be kept in a static
+trait PanicSink: Send {
+    /// Stores a data entry, i.e. the serialized record prefixed with its kind
+    fn store(&mut self, entry: &[u8]);
+}
+
+struct FlashSink<F> {
+    flash: Blocking<F>,
+    flash_range: Range<u32>,
+}
+
+impl<F: NorFlash + Send> PanicSink for FlashSink<F> {
+    fn store(&mut self, entry: &[u8]) {
+        // Errors are ignored, there is nothing left to report them to
+        let _ = block_on(async {
+            let mut storer =
+                Storer::<_, PanicRecord>::open(&mut self.flash, self.flash_range.clone()).await?;
+            storer.push(entry).await
+        });
+    }
+}
+
+static SINK: Mutex<RefCell<Option<Box<dyn PanicSink>>>> = Mutex::new(RefCell::new(None));
+// Behind a critical section like the buffers of the logging backends, see `pending::Pending`
+static STORING: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// Sets the flash and range panics are stored in, e.g. a second handle to the flash of the
+/// [`Storer`] of the firmware. It is only used once the firmware panicked. The range must not
+/// overlap the ranges of other storers, their writes would corrupt each other.
+pub fn init<F: NorFlash + Send + 'static>(flash: F, flash_range: Range<u32>) {
+    let sink: Box<dyn PanicSink> = Box::new(FlashSink {
+        flash: Blocking(flash),
+        flash_range,
+    });
+    critical_section::with(|cs| *SINK.borrow_ref_mut(cs) = Some(sink));
+}
+
+/// Stores the panic, if [`init`] was called. Does nothing if storing the panic panics itself.
+pub fn store(info: &PanicInfo<'_>) {
+    if critical_section::with(|cs| STORING.borrow(cs).replace(true)) {
+        return;
+    }
+    // Marks the stack pointer of the handler
+    let marker = 0u32;
+    let mut stack = [0; STACK_WORDS];
+    let stack_len = stack_snapshot(&marker, &mut stack);
+
+    let mut message = TruncatingWriter::<MAX_MESSAGE>::new();
+    let _ = write!(message, "{}", info.message());
+    let (file, line, column) = info
+        .location()
+        .map_or(("", 0, 0), |l| (l.file(), l.line(), l.column()));
+    let record = PanicRecordRef {
+        message: message.as_str(),
+        file: file_suffix(file),
+        line,
+        column,
+        stack: &stack[..stack_len],
+    };
+
+    let mut buf = [0; MAX_MESSAGE + MAX_FILE + STACK_WORDS * 5 + 32];
+    buf[0] = ID_DATA;
+    let Ok(len) = postcard::to_slice(&record, &mut buf[1..]).map(|bytes| bytes.len()) else {
+        critical_section::with(|cs| STORING.borrow(cs).set(false));
+        return;
+    };
+    critical_section::with(|cs| {
+        if let Some(sink) = SINK.borrow_ref_mut(cs).as_mut() {
+            sink.store(&buf[..1 + len]);
+        }
+        // Only reached if storing did not panic, later panics are stored as well
+        STORING.borrow(cs).set(false);
+    });
+}
+
+#[cfg(feature = "panic-handler")]
+#[panic_handler]
+fn panic(info: &PanicInfo<'_>) -> ! {
+    store(info);
+    loop {}
+}
+
+/// Reads the words above `marker` up to `_stack_start`, the top of the downwards growing stack
+/// provided by the linker scripts of cortex-m-rt and esp-hal, i.e. the frames of the callers.
+/// Returns the number of words read, none if not running on bare metal.
+#[inline(always)]
+fn stack_snapshot(marker: &u32, stack: &mut [u32; STACK_WORDS]) -> usize {
+    #[cfg(target_os = "none")]
+    {
+        extern "C" {
+            static _stack_start: u32;
+        }
+        let start = marker as *const u32 as usize;
+        let end = core::ptr::addr_of!(_stack_start) as usize;
+        let len = (end.saturating_sub(start) / 4).min(STACK_WORDS);
+        let start = core::ptr::with_exposed_provenance::<u32>(start);
+        for (i, word) in stack[..len].iter_mut().enumerate() {
+            *word = unsafe { core::ptr::read_volatile(start.add(i)) };
+        }
+        len
+    }
+    #[cfg(not(target_os = "none"))]
+    {
+        let _ = (marker, stack);
+        0
+    }
+}
+
+/// The end of a path of at most `MAX_FILE` bytes
+fn file_suffix(file: &str) -> &str {
+    let mut start = file.len().saturating_sub(MAX_FILE);
+    while !file.is_char_boundary(start) {
+        start += 1;
+    }
+    &file[start..]
+}
+
+/// Formats into a fixed buffer, dropping what does not fit
+struct TruncatingWriter<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> TruncatingWriter<N> {
+    fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        // Only whole characters are written
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+}
+
+impl<const N: usize> Write for TruncatingWriter<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut len = s.len().min(N - self.len);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.buf[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+/// Polls a future of the [`Blocking`] flash to completion, `None` if it does not complete within
+/// [`MAX_POLLS`]
+fn block_on<T>(future: impl Future<Output = T>) -> Option<T> {
+    let mut future = pin!(future);
+    let mut cx = Context::from_waker(Waker::noop());
+    (0..MAX_POLLS).find_map(|_| match future.as_mut().poll(&mut cx) {
+        Poll::Ready(value) => Some(value),
+        Poll::Pending => None,
+    })
+}
+
+/// Implements the async flash traits of the [`Storer`] for a blocking flash
+struct Blocking<F>(F);
+
+impl<F: ErrorType> embedded_storage_async::nor_flash::ErrorType for Blocking<F> {
+    type Error = F::Error;
+}
+
+impl<F: ReadNorFlash> embedded_storage_async::nor_flash::ReadNorFlash for Blocking<F> {
+    const READ_SIZE: usize = F::READ_SIZE;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.0.read(offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        self.0.capacity()
+    }
+}
+
+impl<F: NorFlash> embedded_storage_async::nor_flash::NorFlash for Blocking<F> {
+    const WRITE_SIZE: usize = F::WRITE_SIZE;
+    const ERASE_SIZE: usize = F::ERASE_SIZE;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.0.erase(from, to)
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.0.write(offset, bytes)
+    }
+}
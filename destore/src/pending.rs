@@ -0,0 +1,75 @@
+//! Entries logged by the `defmt` and `log` backends, buffered in RAM until a task writes them to
+//! the flash.
+
+use core::cell::{Cell, RefCell, RefMut};
+use core::future::poll_fn;
+use core::task::{Poll, Waker};
+use critical_section::{CriticalSection, Mutex};
+
+/// A buffer of entries waiting to be written
+pub(crate) trait Buffer {
+    type Entry;
+
+    fn is_empty(&self) -> bool;
+
+    fn pop(&mut self) -> Option<Self::Entry>;
+}
+
+/// The buffer, the waker of the writing task and the number of dropped entries.
+///
+/// Everything is kept behind critical sections, not in atomics, as targets like thumbv6m and
+/// riscv32imc have no atomic read-modify-write.
+pub(crate) struct Pending<B> {
+    buffer: Mutex<RefCell<B>>,
+    waker: Mutex<RefCell<Option<Waker>>>,
+    dropped: Mutex<Cell<u32>>,
+}
+
+impl<B: Buffer> Pending<B> {
+    pub(crate) const fn new(buffer: B) -> Self {
+        Self {
+            buffer: Mutex::new(RefCell::new(buffer)),
+            waker: Mutex::new(RefCell::new(None)),
+            dropped: Mutex::new(Cell::new(0)),
+        }
+    }
+
+    pub(crate) fn buffer<'cs>(&'cs self, cs: CriticalSection<'cs>) -> RefMut<'cs, B> {
+        self.buffer.borrow_ref_mut(cs)
+    }
+
+    /// Wakes the writing task if the entry was added to the buffer, counts it as dropped otherwise
+    pub(crate) fn commit(&self, cs: CriticalSection<'_>, added: bool) {
+        if added {
+            if let Some(waker) = self.waker.borrow_ref_mut(cs).take() {
+                waker.wake();
+            }
+        } else {
+            let dropped = self.dropped.borrow(cs);
+            dropped.set(dropped.get().saturating_add(1));
+        }
+    }
+
+    /// Waits until there is an entry to write
+    pub(crate) async fn wait(&self) {
+        poll_fn(|cx| {
+            critical_section::with(|cs| {
+                if self.buffer.borrow_ref(cs).is_empty() {
+                    *self.waker.borrow_ref_mut(cs) = Some(cx.waker().clone());
+                    Poll::Pending
+                } else {
+                    Poll::Ready(())
+                }
+            })
+        })
+        .await
+    }
+
+    pub(crate) fn pop(&self) -> Option<B::Entry> {
+        critical_section::with(|cs| self.buffer(cs).pop())
+    }
+
+    pub(crate) fn dropped(&self) -> u32 {
+        critical_section::with(|cs| self.dropped.borrow(cs).get())
+    }
+}
@@ -3,20 +3,14 @@
 
 use alloc::string::ToString;
 use defmt::info;
-use destore::{export_schema, Storer};
+use destore::Storer;
 use embassy_executor::Spawner;
 use embassy_time::{Duration, Timer};
 use esp_hal::clock::CpuClock;
 use esp_hal::timer::systimer::SystemTimer;
 use example::{BlockingAsync, Record, Sub};
 
-#[panic_handler]
-fn panic(_: &core::panic::PanicInfo) -> ! {
-    loop {}
-}
-
 extern crate alloc;
-export_schema!(Record);
 
 #[esp_hal_embassy::main]
 async fn main(spawner: Spawner) {
@@ -29,9 +23,15 @@ async fn main(spawner: Spawner) {
 
     info!("Embassy initialized!");
 
+    // Panics are stored by the panic handler of destore, in a partition of their own
+    destore::panic::init(
+        esp_storage::FlashStorage::new(),
+        0x7E0000..(0x7E0000 + 0x20000),
+    );
+
     let mut s: Storer<_, Record> = Storer::new(
         BlockingAsync::new(esp_storage::FlashStorage::new()),
-        0x620000..(0x620000 + 0x1E0000),
+        0x620000..(0x620000 + 0x1C0000),
     )
     .await
     .unwrap();
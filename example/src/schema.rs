@@ -1,4 +1,5 @@
 use alloc::string::String;
+use destore::DestoreRecord;
 use postcard_schema::Schema;
 use serde::{Deserialize, Serialize};
 
@@ -10,7 +11,7 @@ pub struct Sub {
     pub brothers: u16,
 }
 
-#[derive(Serialize, Deserialize, Schema)]
+#[derive(Serialize, Deserialize, DestoreRecord)]
 pub enum Record {
     Boot(u8),
     Message(String),